@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use crate::compiler::{Compiler, CompileError, JqaRule, JqaRuleKind};
+use crate::lexer::Lexer;
+use crate::vm::{OpCode, Value};
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+pub enum Aggregate {
+  Count,
+  Sum,
+  Min,
+  Max,
+  Avg,
+  Median,
+  Stddev,
+  // p95 and the like
+  Percentile(f64),
+}
+
+impl Aggregate {
+  pub fn from_name(name: &str) -> Option<Aggregate> {
+    match name {
+      "count" => Some(Aggregate::Count),
+      "sum" => Some(Aggregate::Sum),
+      "min" => Some(Aggregate::Min),
+      "max" => Some(Aggregate::Max),
+      "avg" => Some(Aggregate::Avg),
+      "median" => Some(Aggregate::Median),
+      "stddev" => Some(Aggregate::Stddev),
+      _ => name.strip_prefix('p')
+        .and_then(|p| p.parse::<f64>().ok())
+        .filter(|p| (0.0..=100.0).contains(p))
+        .map(Aggregate::Percentile),
+    }
+  }
+
+  pub fn needs_argument(self) -> bool {
+    self != Aggregate::Count
+  }
+
+  // whether every value has to be kept, rather than a running total
+  fn keeps_values(self) -> bool {
+    matches!(self, Aggregate::Median | Aggregate::Stddev | Aggregate::Percentile(_))
+  }
+}
+
+// the value p percent of the way through the sorted numbers, interpolating
+// between the two either side of it
+pub fn percentile(mut numbers: Vec<f64>, p: f64) -> Option<f64> {
+  if numbers.is_empty() {
+    return None;
+  }
+  numbers.sort_by(|a, b| a.total_cmp(b));
+  let rank = p / 100.0 * (numbers.len() - 1) as f64;
+  let (lower, upper) = (numbers[rank.floor() as usize], numbers[rank.ceil() as usize]);
+  return Some(lower + (upper - lower) * rank.fract());
+}
+
+// the sample standard deviation, 0 for a single number
+pub fn stddev(numbers: &[f64]) -> Option<f64> {
+  if numbers.len() < 2 {
+    return numbers.first().map(|_| 0.0);
+  }
+  let mean = numbers.iter().sum::<f64>() / numbers.len() as f64;
+  let squares: f64 = numbers.iter().map(|n| (n - mean).powi(2)).sum();
+  return Some((squares / (numbers.len() - 1) as f64).sqrt());
+}
+
+// the running state of one aggregate in one group
+#[derive(Clone, Default, Serialize, Deserialize)]
+struct Accumulator {
+  count: usize,
+  sum: f64,
+  min: f64,
+  max: f64,
+  // only for the aggregates that need them
+  values: Vec<f64>,
+}
+
+impl Accumulator {
+  fn add(&mut self, aggregate: Aggregate, n: f64) {
+    if aggregate.keeps_values() {
+      self.values.push(n);
+    }
+    if self.count == 0 {
+      self.min = n;
+      self.max = n;
+    } else {
+      self.min = self.min.min(n);
+      self.max = self.max.max(n);
+    }
+    self.count += 1;
+    self.sum += n;
+  }
+
+  fn result(&self, aggregate: Aggregate) -> f64 {
+    match aggregate {
+      Aggregate::Count => self.count as f64,
+      Aggregate::Sum => self.sum,
+      Aggregate::Min => self.min,
+      Aggregate::Max => self.max,
+      Aggregate::Avg if self.count == 0 => 0.0,
+      Aggregate::Avg => self.sum / self.count as f64,
+      Aggregate::Median => percentile(self.values.clone(), 50.0).unwrap_or(0.0),
+      Aggregate::Stddev => stddev(&self.values).unwrap_or(0.0),
+      Aggregate::Percentile(p) => percentile(self.values.clone(), p).unwrap_or(0.0),
+    }
+  }
+}
+
+#[derive(Default, Serialize, Deserialize)]
+pub struct Groups {
+  // in the order they were first seen
+  keys: Vec<String>,
+  groups: HashMap<String, Vec<Accumulator>>,
+}
+
+impl Groups {
+  pub fn add(&mut self, key: String, aggregates: &[Aggregate], values: &[f64]) {
+    if !self.groups.contains_key(&key) {
+      self.keys.push(key.clone());
+    }
+    let accumulators = self.groups.entry(key)
+      .or_insert_with(|| vec![Accumulator::default(); values.len()]);
+    for ((acc, aggregate), n) in accumulators.iter_mut().zip(aggregates.iter()).zip(values.iter()) {
+      acc.add(*aggregate, *n);
+    }
+  }
+
+  // one row per group, the key followed by each aggregate. without a
+  // --group-by there's always exactly one row, even for empty input
+  pub fn report(&self, aggregates: &[Aggregate], grouped: bool) -> Vec<Vec<Value>> {
+    let empty = vec![Accumulator::default(); aggregates.len()];
+    let mut rows: Vec<(&str, &Vec<Accumulator>)> = self.keys.iter()
+      .map(|key| (key.as_str(), self.groups.get(key).unwrap()))
+      .collect();
+    if !grouped && rows.is_empty() {
+      rows.push(("", &empty));
+    }
+
+    rows.into_iter().map(|(key, accumulators)| {
+      let mut row: Vec<Value> = aggregates.iter().zip(accumulators.iter())
+        .map(|(aggregate, acc)| Value::Num(acc.result(*aggregate)))
+        .collect();
+      if grouped {
+        row.insert(0, Value::Str(String::from(key)));
+      }
+      row
+    }).collect()
+  }
+}
+
+// builds the rules for --group-by and --agg: a match rule that adds every
+// record to its group, and an END rule that prints the groups
+pub fn rules(group_by: Option<&str>, aggregates: &str) -> Result<Vec<JqaRule>, Vec<CompileError>> {
+  let mut body = match group_by {
+    Some(expr) => Compiler::new(Lexer::new(expr)).compile_expression()?,
+    None => vec![OpCode::PushImmediate(Value::Str(String::new()))],
+  };
+
+  let compiled = Compiler::new(Lexer::new(aggregates)).compile_aggregates()?;
+  let mut kinds = Vec::with_capacity(compiled.len());
+  for (aggregate, code) in compiled.into_iter() {
+    if code.is_empty() {
+      // count() has nothing to add up, but takes its place on the stack
+      body.push(OpCode::PushImmediate(Value::Num(0.0)));
+    } else {
+      body.extend(code);
+    }
+    kinds.push(aggregate);
+  }
+  body.push(OpCode::Accumulate(kinds.clone()));
+
+  let mut source = String::new();
+  if let Some(expr) = group_by {
+    source = format!("--group-by '{}' ", expr);
+  }
+  source.push_str(&format!("--agg '{}'", aggregates));
+
+  return Ok(vec![
+    JqaRule {
+      pattern: Vec::new(),
+      body,
+      kind: JqaRuleKind::Match,
+      line: 1,
+      source,
+      pattern_spans: Vec::new(),
+      body_spans: Vec::new(),
+    },
+    JqaRule {
+      pattern: Vec::new(),
+      body: vec![OpCode::ReportGroups(kinds, group_by.is_some())],
+      kind: JqaRuleKind::End,
+      line: 1,
+      source: String::from("END"),
+      pattern_spans: Vec::new(),
+      body_spans: Vec::new(),
+    },
+  ]);
+}
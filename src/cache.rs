@@ -0,0 +1,43 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use crate::compiler::JqaRule;
+
+// bump whenever OpCode, Value or JqaRule change shape. the crate version
+// alone isn't enough, development builds share a version across many
+// bytecode layouts and bincode happily misreads an old entry
+const FORMAT: u32 = 1;
+
+// compiled rules are cached by a hash of the program text, the bytecode
+// format and the jqawk version, so a cache dir shared between builds never
+// hands back stale bytecode
+fn cache_path(dir: &str, program: &str) -> PathBuf {
+  let mut hasher = DefaultHasher::new();
+  FORMAT.hash(&mut hasher);
+  env!("CARGO_PKG_VERSION").hash(&mut hasher);
+  program.hash(&mut hasher);
+  Path::new(dir).join(format!("{:016x}.bin", hasher.finish()))
+}
+
+pub fn load(dir: &str, program: &str) -> Option<Vec<JqaRule>> {
+  let bytes = fs::read(cache_path(dir, program)).ok()?;
+  // a corrupt or outdated entry is treated as a miss and recompiled
+  bincode::deserialize(&bytes).ok()
+}
+
+pub fn store(dir: &str, program: &str, rules: &[JqaRule]) {
+  let bytes = bincode::serialize(rules)
+    .expect("error serializing compiled program");
+
+  // failing to write the cache shouldn't stop the program running
+  if fs::create_dir_all(dir).is_err() {
+    return;
+  }
+  // write then rename so concurrent runs never see a half-written entry
+  let path = cache_path(dir, program);
+  let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+  if fs::write(&tmp, bytes).is_ok() {
+    let _ = fs::rename(&tmp, &path);
+  }
+}
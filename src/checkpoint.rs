@@ -0,0 +1,43 @@
+use std::fs;
+use std::path::Path;
+use serde::{Serialize, Deserialize};
+use crate::aggregate::Groups;
+
+// the state of a run part way through its input, for --checkpoint and
+// --resume. it's kept as json so it can be looked at if a run goes wrong
+#[derive(Serialize, Deserialize)]
+pub struct Checkpoint {
+  // where the next document to read starts
+  pub filename: String,
+  pub offset: usize,
+  pub line: usize,
+  // every global, including NR
+  pub variables: serde_json::Map<String, serde_json::Value>,
+  // range patterns in the middle of their range
+  pub ranges: Vec<usize>,
+  // what first(), maxby() and friends have kept, by call site
+  pub tracked: Vec<(usize, serde_json::Value, serde_json::Value)>,
+  // --group-by and --agg totals
+  pub groups: Groups,
+  // how long each file written with `> "file"` was, to carry on from there
+  #[serde(default)]
+  pub outputs: Vec<(String, u64)>,
+}
+
+// a missing checkpoint is a run that hasn't started yet
+pub fn load(path: &str) -> Option<Checkpoint> {
+  if !Path::new(path).exists() {
+    return None;
+  }
+  let text = fs::read_to_string(path).unwrap_or_else(|err| panic!("error reading checkpoint {}: {}", path, err));
+  let checkpoint = serde_json::from_str(&text).unwrap_or_else(|err| panic!("error parsing checkpoint {}: {}", path, err));
+  return Some(checkpoint);
+}
+
+pub fn store(path: &str, checkpoint: &Checkpoint) {
+  let text = serde_json::to_string(checkpoint).expect("error serializing checkpoint");
+  // write then rename so an interrupted run never leaves half a checkpoint
+  let tmp = format!("{}.tmp{}", path, std::process::id());
+  fs::write(&tmp, text).unwrap_or_else(|err| panic!("error writing checkpoint {}: {}", tmp, err));
+  fs::rename(&tmp, path).unwrap_or_else(|err| panic!("error writing checkpoint {}: {}", path, err));
+}
@@ -0,0 +1,69 @@
+use std::cmp::Ordering;
+
+// how strings are ordered by < and >, maxby() and minby(), set with
+// --collate
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum Collation {
+  // by code point, so Z comes before a
+  #[default]
+  Binary,
+  // ignoring case, falling back to code points for strings that only
+  // differ in case
+  NoCase,
+  // ignoring case, with runs of digits compared by their value, so item2
+  // comes before item10
+  Natural,
+}
+
+impl Collation {
+  pub fn from_name(name: &str) -> Option<Collation> {
+    match name {
+      "binary" => Some(Collation::Binary),
+      "nocase" => Some(Collation::NoCase),
+      "natural" => Some(Collation::Natural),
+      _ => None,
+    }
+  }
+
+  pub fn compare(self, a: &str, b: &str) -> Ordering {
+    let order = match self {
+      Collation::Binary => Ordering::Equal,
+      Collation::NoCase => a.to_lowercase().cmp(&b.to_lowercase()),
+      Collation::Natural => natural(&a.to_lowercase(), &b.to_lowercase()),
+    };
+    order.then_with(|| a.cmp(b))
+  }
+}
+
+// compares digit runs by value and everything else by code point
+fn natural(a: &str, b: &str) -> Ordering {
+  let (mut a, mut b) = (a, b);
+  loop {
+    let (x, y) = match (a.chars().next(), b.chars().next()) {
+      (None, None) => return Ordering::Equal,
+      (None, Some(_)) => return Ordering::Less,
+      (Some(_), None) => return Ordering::Greater,
+      (Some(x), Some(y)) => (x, y),
+    };
+
+    if x.is_ascii_digit() && y.is_ascii_digit() {
+      let (x_digits, x_rest) = a.split_at(a.find(|c: char| !c.is_ascii_digit()).unwrap_or(a.len()));
+      let (y_digits, y_rest) = b.split_at(b.find(|c: char| !c.is_ascii_digit()).unwrap_or(b.len()));
+      // without leading zeros, a longer run is a bigger number
+      let (x_digits, y_digits) = (x_digits.trim_start_matches('0'), y_digits.trim_start_matches('0'));
+      let order = x_digits.len().cmp(&y_digits.len()).then_with(|| x_digits.cmp(y_digits));
+      if order != Ordering::Equal {
+        return order;
+      }
+      a = x_rest;
+      b = y_rest;
+      continue;
+    }
+
+    if x != y {
+      return x.cmp(&y);
+    }
+    a = &a[x.len_utf8()..];
+    b = &b[y.len_utf8()..];
+  }
+}
@@ -1,36 +1,113 @@
-use crate::vm::{OpCode, Value};
+use std::fmt;
+use std::collections::HashMap;
+use serde::{Serialize, Deserialize};
+use crate::vm::{check_arity, Lambda, OpCode, RegexValue, Value, Vm, LAMBDA_FUNCTIONS, TRACKING_FUNCTIONS};
 use crate::lexer::{Lexer, Token, TokenKind};
+use crate::aggregate::Aggregate;
+use crate::verify::verify;
 
 pub struct Compiler {
   current: Token,
   prev: Token,
   lexer: Lexer,
   output: Vec<OpCode>,
+  // where each opcode in output came from, and the start of each expression
+  // and statement being compiled, innermost last
+  spans: Vec<Span>,
+  starts: Vec<Token>,
+  errors: Vec<CompileError>,
+  panic_mode: bool,
+  // set once a member chain has fanned out into many values (e.g. after
+  // `..`), so the rest of the chain is applied to each of them
+  fanned_out: bool,
+  // code for each `define NAME = expr`, spliced in wherever NAME is used
+  defines: HashMap<String, Vec<OpCode>>,
+  // set for the next expression when it's a print argument, where like awk
+  // a > that isn't nested in anything redirects the output
+  print_argument: bool,
+  // how many calls to first(), maxby() and friends there have been, to tell
+  // them apart
+  tracked_calls: usize,
+  // how many range patterns there have been, likewise
+  ranges: usize,
+  // the parameters of the lambdas being compiled, innermost last. they're
+  // kept in the call's frame rather than with the globals
+  locals: Vec<Vec<String>>,
 }
 
-#[derive(Clone, PartialEq, Debug)]
+#[derive(Clone, Debug)]
+pub struct CompileError {
+  pub line: usize,
+  pub col: usize,
+  pub message: String,
+}
+
+impl fmt::Display for CompileError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "error on line {}, column {}: {}", self.line, self.col, self.message)
+  }
+}
+
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
 pub enum JqaRuleKind {
   Begin,
   Match,
   End,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct JqaRule {
   pub pattern: Vec<OpCode>,
   pub body: Vec<OpCode>,
   pub kind: JqaRuleKind,
+  // where the rule starts in the program, and its text
+  pub line: usize,
+  pub source: String,
+  // where each opcode of the pattern and body came from. code nested in an
+  // opcode, like a lambda's body, is put down to that opcode
+  pub pattern_spans: Vec<Span>,
+  pub body_spans: Vec<Span>,
+}
+
+// the expression or statement an opcode was compiled from: the line and
+// column it starts at, and the range of characters it covers in its rule's
+// source
+#[derive(Clone, Default, PartialEq, Debug, Serialize, Deserialize)]
+pub struct Span {
+  pub line: usize,
+  pub col: usize,
+  pub start: usize,
+  pub end: usize,
+}
+
+impl JqaRule {
+  // the text of an opcode's expression, and where it is in the program
+  pub fn expression_at(&self, pattern: bool, op: usize) -> Option<(&Span, String)> {
+    let spans = if pattern { &self.pattern_spans } else { &self.body_spans };
+    let span = spans.get(op)?;
+    let text: String = self.source.chars().skip(span.start).take(span.end.saturating_sub(span.start)).collect();
+    return Some((span, String::from(text.trim())));
+  }
+}
+
+// one comma separated branch of a root selector
+#[derive(Clone, Debug)]
+pub struct SelectorBranch {
+  pub source: String,
+  pub code: Vec<OpCode>,
 }
 
 #[derive(PartialOrd, PartialEq)]
 enum Precedence {
   None = 0,
   Assignment,
+  Pipe,
   Logical,
   Equal,
   Comparison,
   Addition,
   Multiplication,
+  Unary,
   Func,
 }
 
@@ -40,14 +117,109 @@ struct ParseRule {
   prefix: Option<fn(&mut Compiler)>,
 }
 
+// the operator behind `+=` and friends
+fn compound_operator(kind: TokenKind) -> Option<OpCode> {
+  match kind {
+    TokenKind::PlusEqual => Some(OpCode::Add),
+    TokenKind::MinusEqual => Some(OpCode::Subtract),
+    TokenKind::StarEqual => Some(OpCode::Multiply),
+    TokenKind::SlashEqual => Some(OpCode::Divide),
+    _ => None,
+  }
+}
+
+fn increment_operator(kind: TokenKind) -> Option<OpCode> {
+  match kind {
+    TokenKind::PlusPlus => Some(OpCode::Add),
+    TokenKind::MinusMinus => Some(OpCode::Subtract),
+    _ => None,
+  }
+}
+
+// fold_spanned for code without spans
+fn fold_constants(code: &[OpCode]) -> Vec<OpCode> {
+  fold_spanned(code, &[]).0
+}
+
+// evaluates operators on literals at compile time, so `60 * 60 * 24` is
+// pushed as 86400 rather than worked out for every record. the spans for
+// the code are folded along with it, if there are any: a folded expression
+// has the span of its operator, which covers all of it
+fn fold_spanned(code: &[OpCode], spans: &[Span]) -> (Vec<OpCode>, Vec<Span>) {
+  let mut out: Vec<OpCode> = Vec::with_capacity(code.len());
+  let mut out_spans: Vec<Span> = Vec::with_capacity(spans.len());
+  for (i, op) in code.iter().enumerate() {
+    if let Some(span) = spans.get(i) {
+      out_spans.push(span.clone());
+    }
+    match op {
+      OpCode::Equal | OpCode::And | OpCode::Add | OpCode::Subtract |
+      OpCode::Multiply | OpCode::Divide | OpCode::Greater | OpCode::Less |
+      OpCode::GreaterEqual | OpCode::LessEqual | OpCode::NotEqual => {
+        let n = out.len();
+        if n >= 2 && matches!(out[n - 2..], [OpCode::PushImmediate(_), OpCode::PushImmediate(_)]) {
+          let mut expr: Vec<OpCode> = out.drain(n - 2..).collect();
+          expr.push(op.clone());
+          match Vm::eval_constant(&expr) {
+            Some(v) => {
+              out.push(OpCode::PushImmediate(v));
+              if !spans.is_empty() {
+                out_spans.drain(n - 2..n);
+              }
+            },
+            None => out.extend(expr),
+          }
+        } else {
+          out.push(op.clone());
+        }
+      },
+      // so -1 is a constant like any other number
+      OpCode::Negate | OpCode::Not if matches!(out.last(), Some(OpCode::PushImmediate(_))) => {
+        let n = out.len();
+        let mut expr: Vec<OpCode> = out.drain(n - 1..).collect();
+        expr.push(op.clone());
+        match Vm::eval_constant(&expr) {
+          Some(v) => {
+            out.push(OpCode::PushImmediate(v));
+            if !spans.is_empty() {
+              out_spans.drain(n - 1..n);
+            }
+          },
+          None => out.extend(expr),
+        }
+      },
+      OpCode::Filter(predicate) => out.push(OpCode::Filter(fold_constants(predicate))),
+      OpCode::MapFilter(predicate) => out.push(OpCode::MapFilter(fold_constants(predicate))),
+      OpCode::CallLambda(name, argc, lambda) => {
+        let lambda = Lambda { params: lambda.params.clone(), body: fold_constants(&lambda.body) };
+        out.push(OpCode::CallLambda(name.clone(), *argc, lambda));
+      },
+      OpCode::ForIn(name, body) => out.push(OpCode::ForIn(name.clone(), fold_constants(body))),
+      OpCode::ForEach(name, body) => out.push(OpCode::ForEach(name.clone(), fold_constants(body))),
+      OpCode::Range(range, start, end) => out.push(OpCode::Range(*range, fold_constants(start), fold_constants(end))),
+      _ => out.push(op.clone()),
+    }
+  }
+  return (out, out_spans);
+}
 
 impl Compiler {
   pub fn new(lexer: Lexer) -> Compiler {
     Compiler {
-      current: Token::new(TokenKind::EOF, 0),
-      prev: Token::new(TokenKind::EOF, 0),
-      lexer: lexer,
+      current: Token::new(TokenKind::EOF, 0, 0),
+      prev: Token::new(TokenKind::EOF, 0, 0),
+      lexer,
       output: Vec::new(),
+      spans: Vec::new(),
+      starts: Vec::new(),
+      errors: Vec::new(),
+      panic_mode: false,
+      fanned_out: false,
+      defines: HashMap::new(),
+      print_argument: false,
+      tracked_calls: 0,
+      ranges: 0,
+      locals: Vec::new(),
     }
   }
 
@@ -58,6 +230,11 @@ impl Compiler {
         prefix: Some(|comp: &mut Compiler| { comp.field() }),
         infix: None
       },
+      TokenKind::Regex => ParseRule {
+        prec: Precedence::None,
+        prefix: Some(|comp: &mut Compiler| { comp.regex() }),
+        infix: None,
+      },
       TokenKind::Str => ParseRule {
         prec: Precedence::None,
         prefix: Some(|comp: &mut Compiler| { comp.string() }),
@@ -73,16 +250,46 @@ impl Compiler {
         prefix: Some(|comp: &mut Compiler| { comp.variable() }),
         infix: None,
       },
+      TokenKind::LParen => ParseRule {
+        prec: Precedence::None,
+        prefix: Some(|comp: &mut Compiler| { comp.grouping() }),
+        infix: None,
+      },
+      TokenKind::Getline => ParseRule {
+        prec: Precedence::None,
+        prefix: Some(|comp: &mut Compiler| { comp.getline() }),
+        infix: None,
+      },
+      TokenKind::PlusPlus | TokenKind::MinusMinus => ParseRule {
+        prec: Precedence::None,
+        prefix: Some(|comp: &mut Compiler| { comp.prefix_increment() }),
+        infix: None,
+      },
       TokenKind::Dot => ParseRule {
         prec: Precedence::Func,
         prefix: None,
         infix: Some(|comp: &mut Compiler| { comp.member() }),
       },
-      TokenKind::EqualEqual => ParseRule {
+      TokenKind::DotDot => ParseRule {
+        prec: Precedence::Func,
+        prefix: None,
+        infix: Some(|comp: &mut Compiler| { comp.descendant() }),
+      },
+      TokenKind::EqualEqual | TokenKind::BangEqual => ParseRule {
+        prec: Precedence::Equal,
+        prefix: None,
+        infix: Some(|comp: &mut Compiler| { comp.binary() }),
+      },
+      TokenKind::Tilde | TokenKind::BangTilde => ParseRule {
         prec: Precedence::Equal,
         prefix: None,
         infix: Some(|comp: &mut Compiler| { comp.binary() }),
       },
+      TokenKind::PipeGreater => ParseRule {
+        prec: Precedence::Pipe,
+        prefix: None,
+        infix: Some(|comp: &mut Compiler| { comp.pipe() }),
+      },
       TokenKind::AmpersandAmpersand => ParseRule {
         prec: Precedence::Logical,
         prefix: None,
@@ -90,10 +297,15 @@ impl Compiler {
       },
       TokenKind::LSquare => ParseRule {
         prec: Precedence::Func,
-        prefix: None,
+        prefix: Some(|comp: &mut Compiler| { comp.array() }),
         infix: Some(|comp: &mut Compiler| { comp.computed_member() }),
       },
-      TokenKind::RAngle => ParseRule {
+      TokenKind::In => ParseRule {
+        prec: Precedence::Comparison,
+        prefix: None,
+        infix: Some(|comp: &mut Compiler| { comp.binary() }),
+      },
+      TokenKind::RAngle | TokenKind::LAngle | TokenKind::RAngleEqual | TokenKind::LAngleEqual => ParseRule {
         prec: Precedence::Comparison,
         prefix: None,
         infix: Some(|comp: &mut Compiler| { comp.binary() }),
@@ -103,9 +315,14 @@ impl Compiler {
         prefix: None,
         infix: Some(|comp: &mut Compiler| { comp.binary() }),
       },
+      TokenKind::Bang => ParseRule {
+        prec: Precedence::None,
+        prefix: Some(|comp: &mut Compiler| { comp.not() }),
+        infix: None,
+      },
       TokenKind::Minus => ParseRule {
         prec: Precedence::Addition,
-        prefix: None,
+        prefix: Some(|comp: &mut Compiler| { comp.negate() }),
         infix: Some(|comp: &mut Compiler| { comp.binary() }),
       },
       TokenKind::Star => ParseRule {
@@ -128,57 +345,117 @@ impl Compiler {
 
   // parsing utils
   fn advance(&mut self) {
-    let t = self.lexer.next_token();
-
-    match t.kind {
-      TokenKind::Error => self.fatal(format!("error on line {}: {}", t.line, t.str.unwrap())),
-      _ => {
-        self.prev = self.current.clone();
+    self.prev = self.current.clone();
+    loop {
+      let t = self.lexer.next_token();
+      if t.kind != TokenKind::Error {
         self.current = t;
+        break;
       }
+      // bad tokens are reported and skipped so parsing can carry on
+      let message = t.str.clone().unwrap();
+      self.error_at(&t, message);
     }
-
   }
 
   fn consume(&mut self, kind: TokenKind) {
     if self.current.kind != kind {
       self.fatal(format!("unexpected token {} expected {}", self.current, kind));
+      return;
     }
     self.advance();
   }
 
-  fn fatal(&self, message: String) {
-    panic!("{}", message);
+  fn fatal(&mut self, message: String) {
+    let token = self.current.clone();
+    self.error_at(&token, message);
+  }
+
+  fn error_at(&mut self, token: &Token, message: String) {
+    // only the first error is reported until the parser resynchronises,
+    // anything after it is likely to be noise
+    if self.panic_mode {
+      return;
+    }
+    self.panic_mode = true;
+    self.errors.push(CompileError { line: token.line, col: token.col, message });
+  }
+
+  // skip to the end of the current statement or rule
+  fn synchronize(&mut self) {
+    self.panic_mode = false;
+    while !self.at_statement_end() && self.current.kind != TokenKind::EOF {
+      self.advance();
+    }
   }
 
   // opcodes
   fn emit(&mut self, opcode: OpCode) {
+    // from the start of the innermost expression or statement to here
+    let start = self.starts.last().unwrap_or(&self.prev);
+    self.spans.push(Span {
+      line: start.line,
+      col: start.col,
+      start: start.offset,
+      end: self.prev.end.max(start.offset),
+    });
     self.output.push(opcode);
   }
 
+  // the code compiled so far and its spans, leaving none
+  fn take_output(&mut self) -> (Vec<OpCode>, Vec<Span>) {
+    (std::mem::take(&mut self.output), std::mem::take(&mut self.spans))
+  }
+
+  // compiles code to be nested in an opcode, like a lambda's body, apart
+  // from the code around it
+  fn nested<F: FnOnce(&mut Compiler)>(&mut self, compile: F) -> Vec<OpCode> {
+    let (output, spans) = self.take_output();
+    compile(self);
+    self.spans = spans;
+    return std::mem::replace(&mut self.output, output);
+  }
+
   // grammar
   fn expression(&mut self, prec: Precedence) {
+    let print_argument = std::mem::take(&mut self.print_argument);
     let prefix_rule = self.get_rule(self.current.kind);
-    if prefix_rule.prefix.is_none() {
-      self.fatal(format!("unexpected prefix {}", self.current));
+    self.fanned_out = false;
+    self.starts.push(self.current.clone());
+    match prefix_rule.prefix {
+      Some(prefix) => prefix(self),
+      None => {
+        self.fatal(format!("unexpected prefix {}", self.current));
+        self.starts.pop();
+        return;
+      },
     }
-    prefix_rule.prefix.unwrap()(self);
 
     while prec <= self.get_rule(self.current.kind).prec {
+      if print_argument && self.current.kind == TokenKind::RAngle {
+        break;
+      }
       let infix_rule = self.get_rule(self.current.kind);
-      if infix_rule.infix.is_none() {
-        self.fatal(format!("unexpected infix {}", self.current));
+      match infix_rule.infix {
+        Some(infix) => infix(self),
+        None => {
+          self.fatal(format!("unexpected infix {}", self.current));
+          break;
+        },
       }
-      infix_rule.infix.unwrap()(self);
     }
+    self.starts.pop();
   }
 
   fn statement(&mut self) {
+    self.starts.push(self.current.clone());
     match self.current.kind {
-      TokenKind::Print => {
-        self.consume(TokenKind::Print);
+      TokenKind::Print | TokenKind::Eprint => {
+        let eprint = self.current.kind == TokenKind::Eprint;
+        self.advance();
         let mut arg_count = 0;
-        while !self.at_statement_end() {
+        while !self.at_statement_end() && self.current.kind != TokenKind::RAngle {
+          self.print_argument = true;
           self.expression(Precedence::Assignment);
           arg_count += 1;
           if self.current.kind == TokenKind::Comma {
@@ -187,39 +464,172 @@ impl Compiler {
             break;
           }
         }
-        self.emit(OpCode::Print(arg_count));
+        if eprint {
+          self.emit(OpCode::PushImmediate(Value::Str(String::from("/dev/stderr"))));
+          self.emit(OpCode::PrintTo(arg_count));
+        } else if self.redirect() {
+          self.emit(OpCode::PrintTo(arg_count));
+        } else {
+          self.emit(OpCode::Print(arg_count));
+        }
+      },
+      TokenKind::Printf => {
+        self.consume(TokenKind::Printf);
+        // the format and then its arguments
+        let mut arg_count = 0;
+        loop {
+          self.print_argument = true;
+          self.expression(Precedence::Assignment);
+          arg_count += 1;
+          if self.current.kind != TokenKind::Comma || self.panic_mode {
+            break;
+          }
+          self.consume(TokenKind::Comma);
+        }
+        if self.redirect() {
+          self.emit(OpCode::PrintfTo(arg_count));
+        } else {
+          self.emit(OpCode::Printf(arg_count));
+        }
+      },
+      TokenKind::Let => {
+        self.consume(TokenKind::Let);
+        self.destructure();
+      },
+      TokenKind::LSquare => self.destructure(),
+      TokenKind::Getline => {
+        self.getline();
+        self.emit(OpCode::Pop);
+      },
+      TokenKind::For => self.for_in(),
+      TokenKind::Foreach => self.foreach(),
+      TokenKind::Delete => self.delete(),
+      TokenKind::Exit => {
+        self.consume(TokenKind::Exit);
+        let status = !self.at_statement_end();
+        if status {
+          self.expression(Precedence::Assignment);
+        }
+        self.emit(OpCode::Exit(status));
+      },
+      TokenKind::PlusPlus | TokenKind::MinusMinus => {
+        let op = increment_operator(self.current.kind).unwrap();
+        self.advance();
+        self.consume(TokenKind::Identifier);
+        if matches!(self.current.kind, TokenKind::LSquare | TokenKind::Dot) {
+          self.element_assignment(Some(op));
+        } else {
+          let token = self.prev.clone();
+          self.increment(&token, op, false);
+        }
       },
       TokenKind::Identifier => {
-        self.variable();
+        self.consume(TokenKind::Identifier);
+        if matches!(self.current.kind, TokenKind::LSquare | TokenKind::Dot) {
+          self.element_assignment(None);
+        } else {
+          let assignment = self.current.kind == TokenKind::Equal || compound_operator(self.current.kind).is_some();
+          self.named_variable();
+          // a call's result, the value before x++ or a variable on its own
+          // isn't needed
+          if !assignment {
+            self.emit(OpCode::Pop);
+          }
+        }
       },
       _ => {
         self.fatal(format!("unexpected token '{}' expected a statement", self.current));
+        self.advance();
       },
     }
+    self.starts.pop();
   }
 
-  fn at_statement_end(&self) -> bool {
-    match self.current.kind {
-      TokenKind::Semicolon | TokenKind::RCurly => true,
-      _ => false,
+  // `> "file"` after print's arguments, leaving the file on the stack
+  fn redirect(&mut self) -> bool {
+    if self.current.kind != TokenKind::RAngle {
+      return false;
     }
+    self.consume(TokenKind::RAngle);
+    self.expression(Precedence::Addition);
+    return true;
+  }
+
+  fn at_statement_end(&self) -> bool {
+    matches!(self.current.kind, TokenKind::Semicolon | TokenKind::RCurly)
   }
 
   fn field(&mut self) {
     self.consume(TokenKind::Dollar);
-    // TODO $name etc
+    // named fields, e.g. $A and $B in diff mode
+    if self.current.kind == TokenKind::Identifier {
+      self.advance();
+      let name = self.prev.str.clone().unwrap_or_default();
+      self.emit(OpCode::GetField(name));
+      return;
+    }
     self.emit(OpCode::GetField(String::from("")));
   }
 
+  fn grouping(&mut self) {
+    self.consume(TokenKind::LParen);
+    self.expression(Precedence::Assignment);
+    self.consume(TokenKind::RParen);
+  }
+
+  // `[a, b]` makes an array of the values
+  fn array(&mut self) {
+    self.consume(TokenKind::LSquare);
+    let mut count = 0;
+    while !matches!(self.current.kind, TokenKind::RSquare | TokenKind::EOF) {
+      self.expression(Precedence::Assignment);
+      count += 1;
+      if self.current.kind != TokenKind::Comma || self.panic_mode {
+        break;
+      }
+      self.consume(TokenKind::Comma);
+    }
+    self.consume(TokenKind::RSquare);
+    self.fanned_out = false;
+    self.emit(OpCode::MakeArray(count));
+  }
+
+  // `getline var < "file"` reads the file's next line into var, or into $
+  // without one. it gives 1, or 0 at the end of the file and -1 if it
+  // can't be read
+  fn getline(&mut self) {
+    self.consume(TokenKind::Getline);
+    let mut name = None;
+    if self.current.kind == TokenKind::Identifier {
+      name = Some(self.target());
+    }
+    self.consume(TokenKind::LAngle);
+    self.expression(Precedence::Addition);
+    self.emit(OpCode::Getline(name));
+  }
+
   fn binary(&mut self) {
     let token = self.current.clone();
     let prec = self.get_rule(token.kind).prec;
     self.advance();
-    self.expression(prec);
+    if matches!(token.kind, TokenKind::Tilde | TokenKind::BangTilde) && self.current.kind == TokenKind::Regex {
+      // `s ~ /re/` matches against the regex itself
+      self.advance();
+      self.emit(OpCode::PushImmediate(Value::Regex(RegexValue::new(self.prev.str.clone().unwrap_or_default()))));
+    } else {
+      self.expression(prec);
+    }
     match token.kind {
       TokenKind::EqualEqual => self.emit(OpCode::Equal),
       TokenKind::AmpersandAmpersand => self.emit(OpCode::And),
+      TokenKind::Tilde => self.emit(OpCode::Match),
+      TokenKind::BangTilde => self.emit(OpCode::NotMatch),
+      TokenKind::BangEqual => self.emit(OpCode::NotEqual),
       TokenKind::RAngle => self.emit(OpCode::Greater),
+      TokenKind::LAngle => self.emit(OpCode::Less),
+      TokenKind::RAngleEqual => self.emit(OpCode::GreaterEqual),
+      TokenKind::LAngleEqual => self.emit(OpCode::LessEqual),
+      TokenKind::In => self.emit(OpCode::In),
       TokenKind::Plus => self.emit(OpCode::Add),
       TokenKind::Minus => self.emit(OpCode::Subtract),
       TokenKind::Star => self.emit(OpCode::Multiply),
@@ -230,30 +640,464 @@ impl Compiler {
 
   fn variable(&mut self) {
     self.consume(TokenKind::Identifier);
+    let assignment = self.current.kind == TokenKind::Equal || compound_operator(self.current.kind).is_some();
+    self.named_variable();
+    // like awk, an assignment in an expression gives the value assigned
+    match self.output.last() {
+      Some(OpCode::SetGlobal(name)) if assignment => self.emit(OpCode::GetGlobal(name.clone())),
+      Some(OpCode::SetLocal(name)) if assignment => self.emit(OpCode::GetLocal(name.clone())),
+      _ => (),
+    }
+  }
+
+  // the rest of a variable, call or assignment once its name is consumed
+  fn named_variable(&mut self) {
+    let name = self.prev.str.clone().unwrap_or_default();
+    let local = self.locals.iter().any(|params| params.contains(&name));
+    let compound = compound_operator(self.current.kind);
+    if self.current.kind == TokenKind::LParen {
+      self.call(name, 0);
+    } else if let Some(op) = increment_operator(self.current.kind) {
+      // x++ gives the value from before
+      let token = self.prev.clone();
+      self.advance();
+      self.emit(if local { OpCode::GetLocal(name) } else { OpCode::GetGlobal(name) });
+      self.increment(&token, op, false);
+    } else if self.current.kind == TokenKind::Equal || compound.is_some() {
+      // assignment, or `x += v` and the like
+      let token = self.prev.clone();
+      self.check_assignable(&token);
+      self.advance();
+      if compound.is_some() {
+        self.emit(if local { OpCode::GetLocal(name.clone()) } else { OpCode::GetGlobal(name.clone()) });
+      }
+      self.value();
+      if let Some(op) = compound {
+        self.emit(op);
+      }
+      self.emit(if local { OpCode::SetLocal(name) } else { OpCode::SetGlobal(name) });
+    } else if local {
+      self.emit(OpCode::GetLocal(name));
+    } else if let Some(code) = self.defines.get(&name) {
+      // put down to where it's used rather than where it's defined
+      for op in code.clone().into_iter() {
+        self.emit(op);
+      }
+      self.fanned_out = false;
+    } else {
+      self.emit(OpCode::GetGlobal(name));
+    }
+  }
+
+  // ++x gives the value from after
+  fn prefix_increment(&mut self) {
+    let op = increment_operator(self.current.kind).unwrap();
+    self.advance();
+    self.consume(TokenKind::Identifier);
+    if matches!(self.current.kind, TokenKind::LSquare | TokenKind::Dot) {
+      self.fatal(String::from("++ and -- only work on elements as statements, e.g. a[k]++"));
+      return;
+    }
     let token = self.prev.clone();
-    if self.current.kind == TokenKind::Equal {
-      // assignment
-      self.consume(TokenKind::Equal);
-      self.expression(Precedence::Assignment);
-      self.emit(OpCode::SetGlobal(token.str.unwrap()));
+    self.increment(&token, op, true);
+  }
+
+  // adds or takes one from a variable, leaving its new value on the stack if
+  // asked
+  fn increment(&mut self, token: &Token, op: OpCode, leave: bool) {
+    let name = token.str.clone().unwrap_or_default();
+    self.check_assignable(token);
+    let local = self.locals.iter().any(|params| params.contains(&name));
+    let get = if local { OpCode::GetLocal(name.clone()) } else { OpCode::GetGlobal(name.clone()) };
+    self.emit(get.clone());
+    self.emit(OpCode::PushImmediate(Value::Num(1.0)));
+    self.emit(op);
+    self.emit(if local { OpCode::SetLocal(name) } else { OpCode::SetGlobal(name) });
+    if leave {
+      self.emit(get);
+    }
+  }
+
+  // `a[k] = v`, `a.b[k] += v` and so on, like awk's associative arrays.
+  // a and anything along the way that isn't an object or array yet becomes
+  // an object. `a[k]++` and `++a[k]` work as statements, the latter with
+  // its operator already consumed and given as increment
+  fn element_assignment(&mut self, increment: Option<OpCode>) {
+    let token = self.prev.clone();
+    let name = token.str.clone().unwrap_or_default();
+    self.check_assignable(&token);
+    if self.locals.iter().any(|params| params.contains(&name)) {
+      self.error_at(&token, format!("cannot assign to an element of parameter {}", name));
+    }
+
+    let depth = self.element_keys();
+    let increment = increment.or_else(|| {
+      let op = increment_operator(self.current.kind);
+      if op.is_some() {
+        self.advance();
+      }
+      op
+    });
+    if let Some(op) = increment {
+      self.emit(OpCode::PushImmediate(Value::Num(1.0)));
+      self.emit(OpCode::SetElement(name, depth, Some(Box::new(op))));
+      return;
+    }
+
+    let compound = compound_operator(self.current.kind);
+    if self.current.kind != TokenKind::Equal && compound.is_none() {
+      self.fatal(format!("unexpected token {} expected an assignment", self.current));
+      return;
+    }
+    self.advance();
+    self.value();
+    self.emit(OpCode::SetElement(name, depth, compound.map(Box::new)));
+  }
+
+  // the keys of `[k].b` and the like after a variable, leaving each on the
+  // stack. gives how many there were
+  fn element_keys(&mut self) -> usize {
+    let mut depth = 0;
+    loop {
+      match self.current.kind {
+        TokenKind::LSquare => {
+          self.consume(TokenKind::LSquare);
+          self.expression(Precedence::Assignment);
+          self.consume(TokenKind::RSquare);
+        },
+        TokenKind::Dot => {
+          self.consume(TokenKind::Dot);
+          self.consume(TokenKind::Identifier);
+          let key = self.prev.str.clone().unwrap_or_default();
+          self.emit(OpCode::PushImmediate(Value::Str(key)));
+        },
+        _ => break,
+      }
+      depth += 1;
+    }
+    return depth;
+  }
+
+  // `delete a[k]` takes a key out of an object or an element out of an
+  // array, and `delete a` empties a. `delete $.k` does the same to the
+  // record, which can't be deleted itself
+  fn delete(&mut self) {
+    self.consume(TokenKind::Delete);
+    let token = self.current.clone();
+    let name = if token.kind == TokenKind::Dollar {
+      self.consume(TokenKind::Dollar);
+      None
     } else {
-      self.emit(OpCode::GetGlobal(token.str.unwrap()));
+      Some(self.target())
+    };
+    if let Some(name) = name.as_ref().filter(|name| self.locals.iter().any(|params| params.contains(name))) {
+      self.error_at(&token, format!("cannot delete from parameter {}", name));
     }
+
+    let depth = self.element_keys();
+    if name.is_none() && depth == 0 {
+      self.error_at(&token, String::from("cannot delete the record, only its members"));
+    }
+    self.emit(OpCode::Delete(name, depth));
+  }
+
+  // `for (k in a) statement` runs the statement for each key of an object or
+  // index of an array
+  fn for_in(&mut self) {
+    self.consume(TokenKind::For);
+    self.consume(TokenKind::LParen);
+    let name = self.target();
+    self.consume(TokenKind::In);
+    self.expression(Precedence::Assignment);
+    self.consume(TokenKind::RParen);
+
+    let body = self.nested(|comp| {
+      if comp.current.kind == TokenKind::LCurly {
+        comp.block();
+      } else {
+        comp.statement();
+      }
+    });
+    self.emit(OpCode::ForIn(name, body));
+  }
+
+  // `foreach $.items { ... }` runs the statements for each element of an
+  // array or value of an object with $ as it, or with `as item` the
+  // variable item
+  fn foreach(&mut self) {
+    self.consume(TokenKind::Foreach);
+    self.expression(Precedence::Assignment);
+    let mut name = None;
+    if self.current.kind == TokenKind::As {
+      self.consume(TokenKind::As);
+      name = Some(self.target());
+    }
+
+    let body = self.nested(|comp| {
+      if comp.current.kind == TokenKind::LCurly {
+        comp.block();
+      } else {
+        comp.statement();
+      }
+    });
+    self.emit(OpCode::ForEach(name, body));
+  }
+
+  // `{ statement; statement }`, where the last semicolon is optional, as is
+  // one after a nested block
+  fn block(&mut self) {
+    self.consume(TokenKind::LCurly);
+    while !matches!(self.current.kind, TokenKind::RCurly | TokenKind::EOF) {
+      self.statement();
+      let after_block = self.prev.kind == TokenKind::RCurly;
+      if self.current.kind == TokenKind::Semicolon || (self.current.kind != TokenKind::RCurly && !after_block) {
+        self.consume(TokenKind::Semicolon);
+      }
+      if self.panic_mode {
+        self.synchronize();
+        if self.current.kind == TokenKind::Semicolon {
+          self.advance();
+        }
+      }
+    }
+    self.consume(TokenKind::RCurly);
+  }
+
+  fn check_assignable(&mut self, token: &Token) {
+    let name = token.str.clone().unwrap_or_default();
+    if self.defines.contains_key(&name) {
+      self.error_at(token, format!("cannot assign to {}, it is defined", name));
+    }
+  }
+
+  // a variable name in a destructuring pattern
+  fn target(&mut self) -> String {
+    self.consume(TokenKind::Identifier);
+    let token = self.prev.clone();
+    self.check_assignable(&token);
+    return token.str.unwrap_or_default();
+  }
+
+  // `let {name, age: years} = $` sets name to $.name and years to $.age,
+  // and `[first, second] = $.pair` (or `let [first, second]`) sets them from
+  // the elements in order
+  fn destructure(&mut self) {
+    let op = match self.current.kind {
+      TokenKind::LCurly => {
+        self.consume(TokenKind::LCurly);
+        let mut names = Vec::new();
+        while self.current.kind == TokenKind::Identifier {
+          self.consume(TokenKind::Identifier);
+          let token = self.prev.clone();
+          let key = token.str.clone().unwrap_or_default();
+          if self.current.kind == TokenKind::Colon {
+            self.consume(TokenKind::Colon);
+            let name = self.target();
+            names.push((key, name));
+          } else {
+            self.check_assignable(&token);
+            names.push((key.clone(), key));
+          }
+          if self.current.kind != TokenKind::Comma {
+            break;
+          }
+          self.consume(TokenKind::Comma);
+        }
+        self.consume(TokenKind::RCurly);
+        OpCode::DestructureObject(names)
+      },
+      TokenKind::LSquare => {
+        self.consume(TokenKind::LSquare);
+        let mut names = Vec::new();
+        while self.current.kind == TokenKind::Identifier {
+          names.push(self.target());
+          if self.current.kind != TokenKind::Comma {
+            break;
+          }
+          self.consume(TokenKind::Comma);
+        }
+        self.consume(TokenKind::RSquare);
+        OpCode::DestructureArray(names)
+      },
+      _ => {
+        self.fatal(format!("unexpected token {} expected {{ or [", self.current));
+        return;
+      },
+    };
+
+    self.consume(TokenKind::Equal);
+    self.expression(Precedence::Assignment);
+    self.emit(op);
+  }
+
+  // piped calls have their first argument on the stack already
+  fn call(&mut self, name: String, piped: usize) {
+    let token = self.prev.clone();
+    self.consume(TokenKind::LParen);
+    let mut arg_count = piped;
+    let mut lambda = None;
+    while !matches!(self.current.kind, TokenKind::RParen | TokenKind::EOF) {
+      // a function can only be the last argument
+      if self.current.kind == TokenKind::Pipe {
+        lambda = Some(self.lambda());
+        break;
+      }
+      self.value();
+      arg_count += 1;
+      if self.current.kind != TokenKind::Comma || self.panic_mode {
+        break;
+      }
+      self.consume(TokenKind::Comma);
+    }
+    self.consume(TokenKind::RParen);
+    self.fanned_out = false;
+    if let Err(message) = check_arity(&name, arg_count) {
+      self.error_at(&token, message);
+      return;
+    }
+
+    let takes_lambda = LAMBDA_FUNCTIONS.contains(&name.as_str());
+    match lambda {
+      Some(lambda) if takes_lambda => self.emit(OpCode::CallLambda(name, arg_count, lambda)),
+      Some(_) => self.error_at(&token, format!("{} does not take a function", name)),
+      None if takes_lambda => self.error_at(&token, format!("{} needs a function, e.g. |x| x", name)),
+      None if TRACKING_FUNCTIONS.contains(&name.as_str()) => {
+        self.emit(OpCode::CallTracked(name, arg_count, self.tracked_calls));
+        self.tracked_calls += 1;
+      },
+      None => self.emit(OpCode::Call(name, arg_count)),
+    }
+  }
+
+  // `v |> f(a)` is f(v, a), so chains read left to right
+  fn pipe(&mut self) {
+    self.consume(TokenKind::PipeGreater);
+    self.consume(TokenKind::Identifier);
+    let name = self.prev.str.clone().unwrap_or_default();
+    if self.current.kind != TokenKind::LParen {
+      self.fatal(format!("expected a call after |>, found {}", self.current));
+      return;
+    }
+    self.call(name, 1);
+  }
+
+  // `|x, y| expr`, compiled separately to be run by the builtin it's passed to
+  fn lambda(&mut self) -> Lambda {
+    self.consume(TokenKind::Pipe);
+    let mut params = Vec::new();
+    while self.current.kind == TokenKind::Identifier {
+      self.advance();
+      params.push(self.prev.str.clone().unwrap_or_default());
+      if self.current.kind != TokenKind::Comma {
+        break;
+      }
+      self.consume(TokenKind::Comma);
+    }
+    self.consume(TokenKind::Pipe);
+
+    self.locals.push(params.clone());
+    let body = self.nested(|comp| comp.expression(Precedence::Assignment));
+    self.locals.pop();
+    return Lambda { params, body };
   }
 
   fn member(&mut self) {
     self.consume(TokenKind::Dot);
+    if self.current.kind == TokenKind::Star {
+      self.consume(TokenKind::Star);
+      self.wildcard();
+      return;
+    }
     self.consume(TokenKind::Identifier);
-    let token = self.prev.clone();
-    self.emit(OpCode::PushImmediate(Value::Str(token.str.unwrap())));
-    self.emit(OpCode::GetMember);
+    let name = self.prev.str.clone().unwrap_or_default();
+    self.emit(OpCode::PushImmediate(Value::Str(name)));
+    self.emit_get_member();
   }
 
   fn computed_member(&mut self) {
     self.consume(TokenKind::LSquare);
-    self.expression(Precedence::Assignment);
+    if self.current.kind == TokenKind::Star {
+      self.consume(TokenKind::Star);
+      self.consume(TokenKind::RSquare);
+      self.wildcard();
+      return;
+    }
+    if self.current.kind == TokenKind::Question {
+      self.filter();
+      return;
+    }
+    let fanned_out = self.fanned_out;
+    let has_start = self.current.kind != TokenKind::Colon;
+    if has_start {
+      self.expression(Precedence::Assignment);
+    }
+
+    if self.current.kind == TokenKind::Colon {
+      self.consume(TokenKind::Colon);
+      let has_end = self.current.kind != TokenKind::RSquare;
+      if has_end {
+        self.expression(Precedence::Assignment);
+      }
+      self.consume(TokenKind::RSquare);
+      self.fanned_out = fanned_out;
+      if self.fanned_out {
+        self.emit(OpCode::MapSlice(has_start, has_end));
+      } else {
+        self.emit(OpCode::Slice(has_start, has_end));
+      }
+      self.fanned_out = true;
+      return;
+    }
+
+    self.fanned_out = fanned_out;
+    self.consume(TokenKind::RSquare);
+    self.emit_get_member();
+  }
+
+  fn descendant(&mut self) {
+    self.consume(TokenKind::DotDot);
+    self.consume(TokenKind::Identifier);
+    let name = self.prev.str.clone().unwrap_or_default();
+    self.emit(OpCode::PushImmediate(Value::Str(name)));
+    if self.fanned_out {
+      self.emit(OpCode::MapDescend);
+    } else {
+      self.emit(OpCode::Descend);
+    }
+    self.fanned_out = true;
+  }
+
+  // [? predicate] keeps the elements the predicate is true for, with $
+  // bound to each element in turn
+  fn filter(&mut self) {
+    self.consume(TokenKind::Question);
+    let fanned_out = self.fanned_out;
+    let predicate = self.nested(|comp| comp.expression(Precedence::Assignment));
     self.consume(TokenKind::RSquare);
-    self.emit(OpCode::GetMember);
+
+    if fanned_out {
+      self.emit(OpCode::MapFilter(predicate));
+    } else {
+      self.emit(OpCode::Filter(predicate));
+    }
+    self.fanned_out = true;
+  }
+
+  fn wildcard(&mut self) {
+    if self.fanned_out {
+      self.emit(OpCode::MapWildcard);
+    } else {
+      self.emit(OpCode::Wildcard);
+    }
+    self.fanned_out = true;
+  }
+
+  fn emit_get_member(&mut self) {
+    if self.fanned_out {
+      self.emit(OpCode::MapMember);
+    } else {
+      self.emit(OpCode::GetMember);
+    }
   }
 
   fn string(&mut self) {
@@ -262,14 +1106,86 @@ impl Compiler {
     self.emit(OpCode::PushImmediate(Value::Str(token.str.unwrap())));
   }
 
+  // an expression whose value is kept, on the right of an assignment or as
+  // an argument. there a `/regex/` by itself is the regex, so it can be
+  // stored and passed around, rather than whether the record matches it
+  fn value(&mut self) {
+    let start = (self.output.len(), self.current.clone());
+    self.expression(Precedence::Assignment);
+    let (len, first) = start;
+    if first.kind == TokenKind::Regex && self.prev.offset == first.offset && self.output.len() == len + 3 {
+      self.output.truncate(len);
+      self.spans.truncate(len);
+      self.emit(OpCode::PushImmediate(Value::Regex(RegexValue::new(first.str.unwrap_or_default()))));
+    }
+  }
+
+  // a `/regex/` on its own matches the record, like awk
+  fn regex(&mut self) {
+    self.consume(TokenKind::Regex);
+    self.emit(OpCode::GetField(String::from("")));
+    self.emit(OpCode::PushImmediate(Value::Str(self.prev.str.clone().unwrap_or_default())));
+    self.emit(OpCode::Match);
+  }
+
+  // -x binds tighter than any binary operator, so -a * b is (-a) * b
+  fn negate(&mut self) {
+    self.consume(TokenKind::Minus);
+    self.expression(Precedence::Unary);
+    self.emit(OpCode::Negate);
+  }
+
+  // !x is 1 for any value that isn't truthy, 0 otherwise
+  fn not(&mut self) {
+    self.consume(TokenKind::Bang);
+    self.expression(Precedence::Unary);
+    self.emit(OpCode::Not);
+  }
+
   fn number(&mut self) {
     self.consume(TokenKind::Num);
-    let num: f64 = self.prev.clone().str.unwrap().parse().unwrap();
+    // underscores only separate the digits
+    let num: f64 = self.prev.str.as_ref().unwrap().replace('_', "").parse().unwrap();
     self.emit(OpCode::PushImmediate(Value::Num(num)));
   }
 
+  // `define NAME = expr` names an expression for use in later rules
+  fn define(&mut self) {
+    self.consume(TokenKind::Define);
+    self.consume(TokenKind::Identifier);
+    let token = self.prev.clone();
+    let name = token.str.clone().unwrap_or_default();
+    if self.defines.contains_key(&name) {
+      self.error_at(&token, format!("{} is already defined", name));
+    }
+
+    self.consume(TokenKind::Equal);
+    self.expression(Precedence::Assignment);
+    let (code, _) = self.take_output();
+    if !self.panic_mode {
+      self.defines.insert(name, code);
+    }
+    if self.current.kind == TokenKind::Semicolon {
+      self.advance();
+    }
+  }
+
+  // the end of a `start, end` range pattern, with the start already compiled
+  fn range(&mut self, start: &Token) {
+    let (begin, _) = self.take_output();
+    self.consume(TokenKind::Comma);
+    let end = self.nested(|comp| comp.expression(Precedence::Assignment));
+    self.starts.push(start.clone());
+    self.emit(OpCode::Range(self.ranges, begin, end));
+    self.starts.pop();
+    self.ranges += 1;
+  }
+
   fn compile_rule(&mut self) -> JqaRule {
     let mut rule_kind = JqaRuleKind::Match;
+    let line = self.current.line;
+    let start = self.current.offset;
+    let pattern_start = self.current.clone();
 
     match self.current.kind {
       // no pattern
@@ -284,46 +1200,172 @@ impl Compiler {
         self.consume(TokenKind::End);
       },
       // pattern
-      _ => self.expression(Precedence::Assignment),
+      _ => {
+        self.expression(Precedence::Assignment);
+        if self.current.kind == TokenKind::Comma {
+          self.range(&pattern_start);
+        }
+        if self.panic_mode {
+          // resume at the rule body
+          while !matches!(self.current.kind, TokenKind::LCurly | TokenKind::EOF) {
+            self.advance();
+          }
+          self.panic_mode = false;
+        }
+      },
     }
 
-    let pattern = self.output.clone();
-    self.output.clear();
+    let (code, spans) = self.take_output();
+    let (mut pattern, mut pattern_spans) = fold_spanned(&code, &spans);
+    // a pattern that's always true is the same as no pattern
+    if let [OpCode::PushImmediate(v)] = pattern.as_slice() {
+      if v.clone().truthy() {
+        pattern.clear();
+        pattern_spans.clear();
+      }
+    }
 
+    let body_start = self.current.clone();
     if self.current.kind != TokenKind::LCurly {
+      // the print of a rule without a body is put down to the whole rule
+      self.starts.push(pattern_start.clone());
       self.emit(OpCode::Print(0));
+      self.starts.pop();
     } else {
-      self.consume(TokenKind::LCurly);
-      while self.current.kind != TokenKind::RCurly {
-        self.statement();
-        if self.current.kind != TokenKind::RCurly {
-          self.consume(TokenKind::Semicolon);
-        }
+      self.block();
+    }
+    let (code, spans) = self.take_output();
+    let (body, mut body_spans) = fold_spanned(&code, &spans);
+
+    // code that got this far without a syntax error but would leave the
+    // stack wrong is a compiler bug or something like `x = 1` as a pattern
+    if self.errors.is_empty() {
+      if let Err(message) = verify(&pattern, if pattern.is_empty() { 0 } else { 1 }, "the pattern") {
+        self.error_at(&pattern_start, message);
+      } else if let Err(message) = verify(&body, 0, "the rule body") {
+        self.error_at(&body_start, message);
+      }
+    }
+
+    // spans are kept relative to the rule's source
+    for span in pattern_spans.iter_mut().chain(body_spans.iter_mut()) {
+      span.start -= start;
+      span.end -= start;
+    }
+    let source = String::from(self.lexer.text(start, self.current.offset).trim());
+    JqaRule { pattern, body, kind: rule_kind, line, source, pattern_spans, body_spans }
+  }
+
+  pub fn compile_selector(&mut self) -> Result<Vec<SelectorBranch>, Vec<CompileError>> {
+    self.advance();
+    let mut branches = Vec::new();
+
+    loop {
+      let start = self.current.offset;
+      self.expression(Precedence::Assignment);
+      let source = self.lexer.text(start, self.current.offset);
+      let (code, _) = self.take_output();
+      branches.push(SelectorBranch {
+        source: String::from(source.trim()),
+        code: fold_constants(&code),
+      });
+
+      if self.current.kind != TokenKind::Comma {
+        break;
       }
-      self.consume(TokenKind::RCurly);
+      self.consume(TokenKind::Comma);
+    }
+
+    if self.current.kind != TokenKind::EOF {
+      self.fatal(format!("unexpected token {} expected {}", self.current, TokenKind::EOF));
     }
-    let body = self.output.clone();
-    self.output.clear();
 
-    JqaRule { pattern, body, kind: rule_kind }
+    if !self.errors.is_empty() {
+      return Err(self.errors.clone());
+    }
+    return Ok(branches);
   }
 
-  pub fn compile_expression(&mut self) -> Vec<OpCode> {
+  // a single expression on its own, e.g. for --group-by or eval
+  pub fn compile_expression(&mut self) -> Result<Vec<OpCode>, Vec<CompileError>> {
     self.advance();
+    let start = self.current.clone();
     self.expression(Precedence::Assignment);
-    return self.output.clone();
+    if self.current.kind != TokenKind::EOF {
+      self.fatal(format!("unexpected token {} expected {}", self.current, TokenKind::EOF));
+    }
+    if let (true, Err(message)) = (self.errors.is_empty(), verify(&self.output, 1, "the expression")) {
+      self.error_at(&start, message);
+    }
+
+    if !self.errors.is_empty() {
+      return Err(self.errors.clone());
+    }
+    return Ok(fold_constants(&self.output));
   }
 
-  pub fn compile_rules(&mut self) -> Vec<JqaRule> {
+  // a comma separated list of aggregates for --agg, e.g. `sum($.sales), count()`.
+  // each gives the aggregate and the code for its argument
+  pub fn compile_aggregates(&mut self) -> Result<Vec<(Aggregate, Vec<OpCode>)>, Vec<CompileError>> {
+    self.advance();
+    let mut aggregates = Vec::new();
+
+    loop {
+      self.consume(TokenKind::Identifier);
+      let token = self.prev.clone();
+      let name = token.str.clone().unwrap_or_default();
+      let aggregate = Aggregate::from_name(&name);
+      if aggregate.is_none() && !self.panic_mode {
+        self.error_at(&token, format!("unknown aggregate {}", name));
+      }
+
+      self.consume(TokenKind::LParen);
+      if self.current.kind != TokenKind::RParen {
+        self.expression(Precedence::Assignment);
+      } else if aggregate.is_some_and(|a| a.needs_argument()) {
+        self.fatal(format!("{} needs an argument", name));
+      }
+      self.consume(TokenKind::RParen);
+
+      let (code, _) = self.take_output();
+      if let Some(aggregate) = aggregate {
+        aggregates.push((aggregate, fold_constants(&code)));
+      }
+
+      if self.current.kind != TokenKind::Comma || self.panic_mode {
+        break;
+      }
+      self.consume(TokenKind::Comma);
+    }
+
+    if self.current.kind != TokenKind::EOF {
+      self.fatal(format!("unexpected token {} expected {}", self.current, TokenKind::EOF));
+    }
+
+    if !self.errors.is_empty() {
+      return Err(self.errors.clone());
+    }
+    return Ok(aggregates);
+  }
+
+  pub fn compile_rules(&mut self) -> Result<Vec<JqaRule>, Vec<CompileError>> {
     // prime the lexer
     self.advance();
     let mut rules = Vec::new();
 
     while self.current.kind != TokenKind::EOF {
-      let rule = self.compile_rule();
-      rules.push(rule);
+      if self.current.kind == TokenKind::Define {
+        self.define();
+      } else {
+        let rule = self.compile_rule();
+        rules.push(rule);
+      }
+      self.panic_mode = false;
     }
 
-    return rules;
+    if !self.errors.is_empty() {
+      return Err(self.errors.clone());
+    }
+    return Ok(rules);
   }
 }
\ No newline at end of file
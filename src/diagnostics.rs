@@ -0,0 +1,103 @@
+use std::panic;
+use std::process;
+use serde::Serialize;
+use crate::compiler::CompileError;
+use crate::lint::Warning;
+use crate::output;
+use crate::vm::{running_expression, RecordTimeout, UserError};
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+  Text,
+  Json,
+}
+
+#[derive(Serialize)]
+struct Diagnostic<'a> {
+  file: Option<&'a str>,
+  line: Option<usize>,
+  column: Option<usize>,
+  message: &'a str,
+  severity: &'a str,
+  // for runtime errors, the expression being run
+  #[serde(skip_serializing_if = "Option::is_none")]
+  expression: Option<&'a str>,
+}
+
+// writes compile errors, warnings and runtime errors to stderr, either as
+// plain text or one json object per line for editors and ci tools
+pub struct Diagnostics {
+  pub format: Format,
+  // the program file, if the program didn't come from the command line
+  pub file: Option<String>,
+}
+
+impl Diagnostics {
+  fn json(&self, line: Option<usize>, column: Option<usize>, message: &str, severity: &str) {
+    let diagnostic = Diagnostic { file: self.file.as_deref(), line, column, message, severity, expression: None };
+    eprintln!("{}", serde_json::to_string(&diagnostic).unwrap());
+  }
+
+  pub fn compile_error(&self, err: &CompileError) {
+    match self.format {
+      Format::Text => eprintln!("{}", err),
+      Format::Json => self.json(Some(err.line), Some(err.col), &err.message, "error"),
+    }
+  }
+
+  pub fn warning(&self, warning: &Warning) {
+    match self.format {
+      Format::Text => eprintln!("{}", warning),
+      Format::Json => self.json(Some(warning.line), None, &warning.message, "warning"),
+    }
+  }
+
+  // runtime errors are raised as panics, so in json mode they're reported
+  // from a panic hook. so are calls to error(), which also choose the exit
+  // code
+  pub fn install_panic_hook(&self) {
+    let format = self.format;
+    let file = self.file.clone();
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+      let payload = info.payload();
+      let diagnostics = Diagnostics { format, file: file.clone() };
+      // caught by the vm, which skips the record
+      if payload.is::<RecordTimeout>() {
+        return;
+      }
+      // so what was printed before the error comes out before it
+      output::try_flush();
+
+      if let Some(err) = payload.downcast_ref::<UserError>() {
+        match format {
+          Format::Text => eprintln!("error: {}", err.message),
+          Format::Json => diagnostics.json(None, None, &err.message, "error"),
+        }
+        process::exit(err.code);
+      }
+
+      // runtime errors say which expression they came from
+      let running = running_expression();
+      if format != Format::Json {
+        default_hook(info);
+        if let Some((line, col, text)) = running {
+          eprintln!("in `{}` on line {}, column {}", text, line, col);
+        }
+        return;
+      }
+      let message = payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|s| s.as_str()))
+        .unwrap_or("unknown error");
+      let diagnostic = Diagnostic {
+        file: diagnostics.file.as_deref(),
+        line: running.as_ref().map(|r| r.0),
+        column: running.as_ref().map(|r| r.1),
+        message,
+        severity: "error",
+        expression: running.as_ref().map(|r| r.2.as_str()),
+      };
+      eprintln!("{}", serde_json::to_string(&diagnostic).unwrap());
+    }));
+  }
+}
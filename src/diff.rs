@@ -0,0 +1,73 @@
+use serde_json::Value;
+use crate::lexer::is_identifier;
+
+// an object key or array index that's present on at least one side
+pub enum Key {
+  Name(String),
+  Index(usize),
+}
+
+impl Key {
+  // how the key is written in a selector, e.g. `.name`, `["two words"]` or `[2]`
+  pub fn path(&self) -> String {
+    match self {
+      Key::Name(name) if is_identifier(name) => format!(".{}", name),
+      Key::Name(name) => format!("[{}]", serde_json::to_string(name).unwrap()),
+      Key::Index(i) => format!("[{}]", i),
+    }
+  }
+
+  pub fn get<'a>(&self, v: &'a Value) -> Option<&'a Value> {
+    match (self, v) {
+      (Key::Name(name), Value::Object(o)) => o.get(name),
+      (Key::Index(i), Value::Array(a)) => a.get(*i),
+      _ => None,
+    }
+  }
+}
+
+// the keys of two objects, a's first and then any only in b, or the indexes
+// of two arrays. values of different kinds have nothing in common to walk
+pub fn keys(a: &Value, b: &Value) -> Vec<Key> {
+  match (a, b) {
+    (Value::Object(a), Value::Object(b)) => {
+      let mut keys: Vec<Key> = a.keys().map(|k| Key::Name(k.clone())).collect();
+      keys.extend(b.keys().filter(|k| !a.contains_key(*k)).map(|k| Key::Name(k.clone())));
+      keys
+    },
+    (Value::Array(a), Value::Array(b)) => (0..a.len().max(b.len())).map(Key::Index).collect(),
+    _ => Vec::new(),
+  }
+}
+
+// the structural differences between two values, one per line:
+//
+//   - $.path: value           only in a
+//   + $.path: value           only in b
+//   ~ $.path: old -> new      changed
+//
+// uses an explicit stack rather than recursion so deep input is safe
+pub fn differences(a: &Value, b: &Value) -> Vec<String> {
+  let mut out = Vec::new();
+  let mut stack = vec![(String::from("$"), Some(a), Some(b))];
+
+  while let Some((path, a, b)) = stack.pop() {
+    match (a, b) {
+      (Some(a), None) => out.push(format!("- {}: {}", path, a)),
+      (None, Some(b)) => out.push(format!("+ {}: {}", path, b)),
+      (Some(a), Some(b)) if a == b => (),
+      (Some(a), Some(b)) => {
+        let same_kind = (a.is_object() && b.is_object()) || (a.is_array() && b.is_array());
+        if same_kind {
+          for key in keys(a, b).iter().rev() {
+            stack.push((format!("{}{}", path, key.path()), key.get(a), key.get(b)));
+          }
+        } else {
+          out.push(format!("~ {}: {} -> {}", path, a, b));
+        }
+      },
+      (None, None) => (),
+    }
+  }
+  return out;
+}
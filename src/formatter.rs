@@ -0,0 +1,231 @@
+use crate::lexer::{quote, Lexer, Token, TokenKind};
+
+const INDENT: &str = "  ";
+
+fn token_text(token: &Token) -> String {
+  match token.kind {
+    TokenKind::Str => quote(token.str.as_ref().unwrap()),
+    _ => format!("{}", token),
+  }
+}
+
+fn space_between(prev: &Token, cur: &Token) -> bool {
+  // member access, slices and the like hug their operands
+  let tight_after = matches!(prev.kind,
+    TokenKind::Dot | TokenKind::DotDot | TokenKind::LSquare | TokenKind::LParen | TokenKind::Colon | TokenKind::Bang);
+  let tight_before = matches!(cur.kind,
+    TokenKind::Dot | TokenKind::DotDot | TokenKind::RSquare |
+    TokenKind::RParen | TokenKind::Colon | TokenKind::Comma | TokenKind::Semicolon);
+  // a [ after a value subscripts it, anywhere else it starts an array
+  let subscript = cur.kind == TokenKind::LSquare && matches!(prev.kind,
+    TokenKind::Dollar | TokenKind::Identifier | TokenKind::Str | TokenKind::Num | TokenKind::RSquare | TokenKind::RParen);
+  // calls hug their arguments, `sum($.x)`
+  let call = prev.kind == TokenKind::Identifier && cur.kind == TokenKind::LParen;
+  // as do ++ and --, `n++` and `++n`
+  let increment = matches!(cur.kind, TokenKind::PlusPlus | TokenKind::MinusMinus) &&
+    matches!(prev.kind, TokenKind::Identifier | TokenKind::RSquare);
+  let incremented = matches!(prev.kind, TokenKind::PlusPlus | TokenKind::MinusMinus) && cur.kind == TokenKind::Identifier;
+  !tight_after && !tight_before && !subscript && !call && !increment && !incremented
+}
+
+// whether a token ends a value, making a - after it a subtraction rather
+// than a negation
+fn ends_operand(token: &Token) -> bool {
+  matches!(token.kind,
+    TokenKind::Identifier | TokenKind::Num | TokenKind::Str | TokenKind::Regex | TokenKind::Dollar |
+    TokenKind::RParen | TokenKind::RSquare | TokenKind::PlusPlus | TokenKind::MinusMinus)
+}
+
+// the braces of `let {a, b} = ...` aren't a body
+fn is_pattern_brace(tokens: &[Token], i: usize) -> bool {
+  i > 0 && tokens[i - 1].kind == TokenKind::Let
+}
+
+// a body with a single statement stays on one line as `{ print }`
+fn is_inline_body(tokens: &[Token], open: usize) -> bool {
+  let mut depth = 0;
+  let mut separators = 0;
+  let mut nested = false;
+  for (i, token) in tokens.iter().enumerate().skip(open) {
+    match token.kind {
+      TokenKind::LCurly => {
+        depth += 1;
+        nested |= i > open && !is_pattern_brace(tokens, i);
+      },
+      TokenKind::RCurly => {
+        depth -= 1;
+        if depth == 0 {
+          return separators == 0 && !nested;
+        }
+      },
+      TokenKind::Semicolon if depth == 1 && tokens.get(i + 1).is_some_and(|t| t.kind != TokenKind::RCurly) => {
+        separators += 1;
+      },
+      _ => (),
+    }
+  }
+  return false;
+}
+
+struct Formatter {
+  out: String,
+  line: String,
+  depth: usize,
+}
+
+impl Formatter {
+  fn newline(&mut self) {
+    let line = std::mem::take(&mut self.line);
+    self.out.push_str(line.trim_end());
+    self.out.push('\n');
+  }
+
+  fn start_line(&mut self) {
+    if !self.line.trim().is_empty() {
+      self.newline();
+    }
+    self.line = INDENT.repeat(self.depth);
+  }
+
+  fn at_line_start(&self) -> bool {
+    self.line.trim().is_empty()
+  }
+
+  fn push(&mut self, text: &str, space: bool) {
+    if space && !self.at_line_start() {
+      self.line.push(' ');
+    }
+    self.line.push_str(text);
+  }
+}
+
+// reprints a program from its tokens with canonical spacing, one rule per
+// line and multi-statement bodies indented one statement per line. @if and
+// the other directives are kept as they are, on lines of their own
+pub fn format(program: &str) -> String {
+  let mut out = String::new();
+  let mut code = String::new();
+  for line in program.lines() {
+    if line.trim_start().starts_with('@') {
+      out.push_str(&format_code(&std::mem::take(&mut code)));
+      out.push_str(line.trim());
+      out.push('\n');
+    } else {
+      code.push_str(line);
+      code.push('\n');
+    }
+  }
+  out.push_str(&format_code(&code));
+  return out;
+}
+
+fn format_code(program: &str) -> String {
+  let mut lexer = Lexer::new(program);
+  let mut tokens = Vec::new();
+  loop {
+    let token = lexer.next_token();
+    if token.kind == TokenKind::EOF {
+      break;
+    }
+    tokens.push(token);
+  }
+
+  let mut f = Formatter { out: String::new(), line: String::new(), depth: 0 };
+  let mut inline_bodies = Vec::new();
+  // between the pipes of a lambda's parameters, `|x, y|`
+  let mut in_params = false;
+  // inside `let {...}`
+  let mut in_pattern = false;
+
+  for (i, token) in tokens.iter().enumerate() {
+    let prev = if i > 0 { Some(&tokens[i - 1]) } else { None };
+    let in_inline_body = inline_bodies.last().copied().unwrap_or(false);
+
+    match token.kind {
+      TokenKind::LCurly if is_pattern_brace(&tokens, i) => {
+        f.push("{", true);
+        in_pattern = true;
+      },
+      TokenKind::RCurly if in_pattern => {
+        f.push("}", false);
+        in_pattern = false;
+      },
+      TokenKind::LCurly => {
+        let inline = is_inline_body(&tokens, i);
+        f.push("{", true);
+        f.depth += 1;
+        inline_bodies.push(inline);
+        if !inline {
+          f.start_line();
+        }
+      },
+      TokenKind::RCurly => {
+        inline_bodies.pop();
+        f.depth -= 1;
+        if in_inline_body {
+          f.push("}", true);
+        } else {
+          let needs_semicolon = prev.is_some_and(|p| !matches!(p.kind, TokenKind::Semicolon | TokenKind::LCurly | TokenKind::RCurly));
+          if needs_semicolon {
+            f.push(";", false);
+          }
+          f.start_line();
+          f.push("}", false);
+        }
+        if f.depth == 0 {
+          f.newline();
+        } else if tokens.get(i + 1).is_some_and(|t| !matches!(t.kind, TokenKind::Semicolon | TokenKind::RCurly)) {
+          // a statement after a nested body, e.g. a for loop's
+          f.start_line();
+        }
+      },
+      TokenKind::Semicolon => {
+        if !in_inline_body {
+          // nested bodies don't need one after them
+          if prev.is_some_and(|p| p.kind != TokenKind::RCurly) {
+            f.push(";", false);
+          }
+          f.start_line();
+        }
+      },
+      _ => {
+        if let Some(prev) = prev {
+          // rules are split by the newlines between them, keeping at most
+          // one blank line
+          if f.depth == 0 && token.line > prev.line {
+            if !f.at_line_start() {
+              f.newline();
+            }
+            if token.line > prev.line + 1 {
+              f.out.push('\n');
+            }
+          }
+        }
+        let mut space = prev.is_some_and(|p| space_between(p, token));
+        if token.kind == TokenKind::Pipe {
+          space &= !in_params;
+          in_params = !in_params;
+        } else if prev.is_some_and(|p| p.kind == TokenKind::Pipe) && in_params {
+          space = false;
+        } else if in_pattern {
+          // `{name, age: years}`
+          space = prev.is_some_and(|p| match p.kind {
+            TokenKind::LCurly => false,
+            TokenKind::Colon => true,
+            _ => space_between(p, token),
+          });
+        }
+        // negation hugs what it negates, `x = -1`
+        if prev.is_some_and(|p| p.kind == TokenKind::Minus) && !(i >= 2 && ends_operand(&tokens[i - 2])) {
+          space = false;
+        }
+        f.push(&token_text(token), space);
+      },
+    }
+  }
+
+  if !f.at_line_start() {
+    f.newline();
+  }
+  return f.out;
+}
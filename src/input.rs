@@ -0,0 +1,590 @@
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use flate2::bufread::MultiGzDecoder;
+use regex::Regex;
+use serde::de::{self, DeserializeSeed, Deserializer, MapAccess, SeqAccess, Visitor};
+use crate::vm::UserError;
+
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+// values are parsed, copied, printed and compared by recursing into them, so
+// rather than handling any depth, input nested deeper than --max-depth is an
+// error and --max-depth can't go past what's known to fit on the stack
+pub const MAX_DEPTH_LIMIT: usize = 2000;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+  // a single document or a stream of them, e.g. ndjson
+  Json,
+  Csv,
+  Yaml,
+  Raw,
+}
+
+impl Format {
+  pub fn from_name(name: &str) -> Option<Format> {
+    match name {
+      "json" => Some(Format::Json),
+      "csv" => Some(Format::Csv),
+      "yaml" => Some(Format::Yaml),
+      "raw" => Some(Format::Raw),
+      _ => None,
+    }
+  }
+
+  // guesses the format from a file's extension, or failing that from the
+  // start of it. anything unrecognised is read as json
+  pub fn detect(path: Option<&str>, head: &[u8]) -> Format {
+    let extension = path.and_then(|p| std::path::Path::new(p).extension())
+      .and_then(|e| e.to_str())
+      .map(|e| e.to_ascii_lowercase());
+    match extension.as_deref() {
+      Some("json") | Some("ndjson") | Some("jsonl") | Some("geojson") => return Format::Json,
+      Some("csv") => return Format::Csv,
+      Some("yaml") | Some("yml") => return Format::Yaml,
+      Some("txt") | Some("log") => return Format::Raw,
+      _ => (),
+    }
+
+    let head = String::from_utf8_lossy(head);
+    let text = head.trim_start();
+    let first_line = text.lines().next().unwrap_or("");
+    if text.starts_with(['{', '[', '"']) {
+      return Format::Json;
+    }
+    // a document marker, a list item or a key
+    if text.starts_with("---") || text.starts_with("- ") || Regex::new(r"^[\w.-]+:(\s|$)").unwrap().is_match(first_line) {
+      return Format::Yaml;
+    }
+    if first_line.contains(',') {
+      return Format::Csv;
+    }
+    return Format::Json;
+  }
+}
+
+// what happens to a key that's in an object more than once, for --dup-keys
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum DupKeys {
+  First,
+  Last,
+  Error,
+}
+
+impl DupKeys {
+  pub fn from_name(name: &str) -> Option<DupKeys> {
+    match name {
+      "first" => Some(DupKeys::First),
+      "last" => Some(DupKeys::Last),
+      "error" => Some(DupKeys::Error),
+      _ => None,
+    }
+  }
+}
+
+// what happens to a document bigger than --max-record-size
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Oversized {
+  Error,
+  // with a warning
+  Skip,
+}
+
+impl Oversized {
+  pub fn from_name(name: &str) -> Option<Oversized> {
+    match name {
+      "error" => Some(Oversized::Error),
+      "skip" => Some(Oversized::Skip),
+      _ => None,
+    }
+  }
+}
+
+// a top level json value from the input and where it came from
+pub struct Document {
+  pub value: serde_json::Value,
+  pub filename: String,
+  // byte offset and line of the start of the document
+  pub offset: usize,
+  pub line: usize,
+  // the dotted paths of keys that were in their object more than once
+  pub duplicates: Vec<String>,
+  // where the document is in its file when it's one of a stream of them,
+  // like a line of ndjson, which makes its selection a single record
+  pub stream_index: Option<usize>,
+}
+
+// where a value is in its document, only written out for a duplicate
+#[derive(Clone, Copy)]
+enum Path<'a> {
+  Root,
+  Key(&'a Path<'a>, &'a str),
+  Index(&'a Path<'a>, usize),
+}
+
+impl<'a> fmt::Display for Path<'a> {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let (parent, segment) = match self {
+      Path::Root => return Ok(()),
+      Path::Key(parent, key) => (parent, key.to_string()),
+      Path::Index(parent, i) => (parent, i.to_string()),
+    };
+    match parent {
+      Path::Root => write!(f, "{}", segment),
+      _ => write!(f, "{}.{}", parent, segment),
+    }
+  }
+}
+
+// builds a json value like serde_json::Value's own deserializing, except
+// that duplicate keys follow the policy and are noted down
+struct ValueSeed<'a> {
+  policy: DupKeys,
+  path: Path<'a>,
+  duplicates: &'a mut Vec<String>,
+}
+
+impl<'de, 'a> DeserializeSeed<'de> for ValueSeed<'a> {
+  type Value = serde_json::Value;
+
+  fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<serde_json::Value, D::Error> {
+    deserializer.deserialize_any(self)
+  }
+}
+
+impl<'de, 'a> Visitor<'de> for ValueSeed<'a> {
+  type Value = serde_json::Value;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str("a JSON value")
+  }
+
+  fn visit_bool<E>(self, b: bool) -> Result<serde_json::Value, E> {
+    Ok(serde_json::Value::Bool(b))
+  }
+
+  fn visit_i64<E>(self, n: i64) -> Result<serde_json::Value, E> {
+    Ok(serde_json::Value::from(n))
+  }
+
+  fn visit_u64<E>(self, n: u64) -> Result<serde_json::Value, E> {
+    Ok(serde_json::Value::from(n))
+  }
+
+  fn visit_f64<E>(self, n: f64) -> Result<serde_json::Value, E> {
+    Ok(serde_json::Number::from_f64(n).map_or(serde_json::Value::Null, serde_json::Value::Number))
+  }
+
+  fn visit_str<E>(self, s: &str) -> Result<serde_json::Value, E> {
+    Ok(serde_json::Value::String(String::from(s)))
+  }
+
+  fn visit_string<E>(self, s: String) -> Result<serde_json::Value, E> {
+    Ok(serde_json::Value::String(s))
+  }
+
+  fn visit_unit<E>(self) -> Result<serde_json::Value, E> {
+    Ok(serde_json::Value::Null)
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<serde_json::Value, A::Error> {
+    let ValueSeed { policy, path, duplicates } = self;
+    let mut items = Vec::new();
+    loop {
+      let seed = ValueSeed { policy, path: Path::Index(&path, items.len()), duplicates: &mut *duplicates };
+      match seq.next_element_seed(seed)? {
+        Some(item) => items.push(item),
+        None => break,
+      }
+    }
+    Ok(serde_json::Value::Array(items))
+  }
+
+  fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<serde_json::Value, A::Error> {
+    let ValueSeed { policy, path, duplicates } = self;
+    let mut o = serde_json::Map::new();
+    while let Some(key) = map.next_key::<String>()? {
+      let value = map.next_value_seed(ValueSeed { policy, path: Path::Key(&path, &key), duplicates: &mut *duplicates })?;
+      if o.contains_key(&key) {
+        let key_path = Path::Key(&path, &key).to_string();
+        match policy {
+          DupKeys::Error => return Err(de::Error::custom(format!("duplicate key {}", key_path))),
+          DupKeys::First => {
+            duplicates.push(key_path);
+            continue;
+          },
+          DupKeys::Last => duplicates.push(key_path),
+        }
+      }
+      o.insert(key, value);
+    }
+    Ok(serde_json::Value::Object(o))
+  }
+}
+
+// watches the json passing through it on the way to serde_json. it fails
+// once the input nests deeper than max_depth, tracking nesting as the bytes
+// go past rather than recursing so hostile input is rejected before it can
+// overflow the stack. it also notes where each top level document starts,
+// and cuts off any document bigger than max_size, either failing or
+// leaving it out. the line of one that's left out is put in skipped
+struct Scanner<T> {
+  inner: T,
+  max_depth: usize,
+  depth: usize,
+  in_string: bool,
+  escaped: bool,
+  // inside a top level number, true, false or null
+  in_scalar: bool,
+  offset: usize,
+  line: usize,
+  starts: Rc<RefCell<VecDeque<(usize, usize)>>>,
+  max_size: Option<(usize, Oversized)>,
+  // the size of the current document so far
+  size: usize,
+  // read past the end of what's been handed on, when a document is cut off
+  pending: Vec<u8>,
+  // a document's been cut off, but what came before it hasn't been read yet
+  cut_off: bool,
+  skipping: bool,
+  skipped: Rc<Cell<Option<usize>>>,
+  // the line the input went past max_depth on
+  too_deep: Rc<Cell<Option<usize>>>,
+}
+
+impl<T> Scanner<T> {
+  fn new(inner: T, max_depth: usize, starts: Rc<RefCell<VecDeque<(usize, usize)>>>) -> Scanner<T> {
+    Scanner {
+      inner,
+      max_depth,
+      depth: 0,
+      in_string: false,
+      escaped: false,
+      in_scalar: false,
+      offset: 0,
+      line: 1,
+      starts,
+      max_size: None,
+      size: 0,
+      pending: Vec::new(),
+      cut_off: false,
+      skipping: false,
+      skipped: Rc::new(Cell::new(None)),
+      too_deep: Rc::new(Cell::new(None)),
+    }
+  }
+
+  fn document_start(&mut self) {
+    self.starts.borrow_mut().push_back((self.offset, self.line));
+    self.size = 0;
+  }
+
+  fn in_document(&self) -> bool {
+    self.depth > 0 || self.in_string || self.in_scalar
+  }
+
+  // the error that stops serde_json part way through a document that's
+  // too big. when it's skipped, the rest of it is read and dropped first
+  fn oversized(&mut self) -> io::Error {
+    let (max_size, oversized) = self.max_size.unwrap();
+    let (_, line) = self.starts.borrow_mut().pop_back().unwrap_or((0, self.line));
+    if oversized == Oversized::Skip {
+      self.skipping = true;
+      self.skipped.set(Some(line));
+    }
+    io::Error::new(io::ErrorKind::InvalidData, format!(
+      "the document on line {} is bigger than the --max-record-size of {} bytes", line, max_size))
+  }
+}
+
+impl<T: Read> Read for Scanner<T> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.cut_off {
+      self.cut_off = false;
+      return Err(self.oversized());
+    }
+    loop {
+      let n = if self.pending.is_empty() {
+        self.inner.read(buf)?
+      } else {
+        let n = self.pending.len().min(buf.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        n
+      };
+
+      // bytes that are skipped are dropped from buf, the rest move up
+      let mut kept = 0;
+      for i in 0..n {
+        let b = buf[i];
+        let inside = self.in_document();
+        self.scan(b)?;
+        self.offset += 1;
+        if b == b'\n' {
+          self.line += 1;
+        }
+
+        if self.skipping {
+          self.skipping = self.in_document();
+          continue;
+        }
+        if inside || self.in_document() {
+          self.size += 1;
+          if self.max_size.is_some_and(|(max_size, _)| self.size > max_size) {
+            self.pending.splice(0..0, buf[i + 1..n].iter().copied());
+            if kept == 0 {
+              return Err(self.oversized());
+            }
+            self.cut_off = true;
+            return Ok(kept);
+          }
+        }
+        buf[kept] = b;
+        kept += 1;
+      }
+
+      // when all of it was skipped there's more to read
+      if kept > 0 || n == 0 {
+        return Ok(kept);
+      }
+    }
+  }
+}
+
+impl<T> Scanner<T> {
+  fn scan(&mut self, b: u8) -> io::Result<()> {
+    if self.in_string {
+      if self.escaped {
+        self.escaped = false;
+      } else if b == b'\\' {
+        self.escaped = true;
+      } else if b == b'"' {
+        self.in_string = false;
+      }
+      return Ok(());
+    }
+
+    match b {
+      b'"' => {
+        if self.depth == 0 {
+          self.document_start();
+        }
+        self.in_string = true;
+      },
+      b'[' | b'{' => {
+        if self.depth == 0 {
+          self.document_start();
+        }
+        self.depth += 1;
+        if self.depth > self.max_depth {
+          self.too_deep.set(Some(self.line));
+          return Err(io::Error::new(io::ErrorKind::InvalidData, "input is nested too deeply"));
+        }
+      },
+      b']' | b'}' => self.depth = self.depth.saturating_sub(1),
+      b' ' | b'\t' | b'\r' | b'\n' => self.in_scalar = false,
+      _ => {
+        if self.depth == 0 && !self.in_scalar {
+          self.in_scalar = true;
+          self.document_start();
+        }
+      },
+    }
+    Ok(())
+  }
+}
+
+// how many bytes of input have been read, after decompressing, for STATS.
+// shared, as the input
+// can be read on a thread of its own
+static BYTES_READ: AtomicUsize = AtomicUsize::new(0);
+
+pub fn bytes_read() -> usize {
+  BYTES_READ.load(Ordering::Relaxed)
+}
+
+struct Counted<T> {
+  inner: T,
+}
+
+impl<T: Read> Read for Counted<T> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    BYTES_READ.fetch_add(n, Ordering::Relaxed);
+    Ok(n)
+  }
+}
+
+// counts what's read from rdr towards bytes_read
+pub fn counted(rdr: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+  return Box::new(Counted { inner: rdr });
+}
+
+// whether input starts like a gzip file does
+pub fn is_gzip(head: &[u8]) -> bool {
+  head.starts_with(&[0x1f, 0x8b])
+}
+
+// decompresses gzipped input, of any format. anything else is left alone
+pub fn decompress(rdr: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+  let mut rdr = io::BufReader::new(rdr);
+  let head = io::BufRead::fill_buf(&mut rdr).unwrap_or_else(|err| panic!("error reading input: {}", err));
+  if is_gzip(head) {
+    return Box::new(MultiGzDecoder::new(rdr));
+  }
+  return Box::new(rdr);
+}
+
+// fails on json that couldn't be parsed. input that's nested too deeply is
+// an ordinary error, as it's the input at fault rather than jqawk
+fn parse_error(err: serde_json::Error, too_deep: Option<usize>, max_depth: usize, filename: &str) -> ! {
+  let line = match too_deep {
+    Some(line) => line,
+    None => panic!("error parsing JSON: {}", err),
+  };
+  let of = if filename.is_empty() { String::new() } else { format!(" of {}", filename) };
+  let message = format!("input is nested more than {} levels deep on line {}{}", max_depth, line, of);
+  std::panic::panic_any(UserError { message, code: 1 });
+}
+
+// reads a stream of whitespace separated json documents (a single document,
+// ndjson, or several pretty printed documents back to back) one at a time
+pub fn read_json<'a, T>(rdr: T, filename: &str, max_depth: usize, dup_keys: DupKeys) -> impl Iterator<Item = Document> + 'a
+  where T: Read + 'a {
+  return read_json_at(rdr, filename, max_depth, dup_keys, None, (0, 1));
+}
+
+// like read_json, for input that's already been read up to the given offset
+// and line, so OFFSET and LINE carry on from there for --resume. documents
+// bigger than max_size bytes fail or are skipped, for --max-record-size
+pub fn read_json_at<'a, T>(rdr: T, filename: &str, max_depth: usize, dup_keys: DupKeys,
+  max_size: Option<(usize, Oversized)>, (offset, line): (usize, usize)) -> impl Iterator<Item = Document> + 'a
+  where T: Read + 'a {
+  let starts = Rc::new(RefCell::new(VecDeque::new()));
+  let mut scanner = Scanner::new(rdr, max_depth, starts.clone());
+  scanner.offset = offset;
+  scanner.line = line;
+  scanner.max_size = max_size;
+  let skipped = scanner.skipped.clone();
+  let too_deep = scanner.too_deep.clone();
+  let rdr = io::BufReader::new(scanner);
+  let mut de = serde_json::Deserializer::from_reader(rdr);
+  // depth is already bounded by the scanner, at most MAX_DEPTH_LIMIT, so
+  // serde_json's own fixed limit of 128 isn't needed
+  de.disable_recursion_limit();
+
+  let filename = String::from(filename);
+  let mut docs = std::iter::from_fn(move || loop {
+    // skips the whitespace between documents, only failing when there's
+    // another one or the input couldn't be read
+    match de.end() {
+      Ok(()) => return None,
+      Err(err) if err.is_io() => parse_error(err, too_deep.take(), max_depth, &filename),
+      Err(_) => (),
+    }
+    let mut duplicates = Vec::new();
+    let seed = ValueSeed { policy: dup_keys, path: Path::Root, duplicates: &mut duplicates };
+    let value = match seed.deserialize(&mut de) {
+      Ok(value) => value,
+      Err(err) => match skipped.take() {
+        Some(line) if err.is_io() => {
+          let (max_size, _) = max_size.unwrap();
+          let of = if filename.is_empty() { String::new() } else { format!(" of {}", filename) };
+          eprintln!("warning: skipped the document on line {}{}, it's bigger than {} bytes", line, of, max_size);
+          continue;
+        },
+        _ => parse_error(err, too_deep.take(), max_depth, &filename),
+      },
+    };
+    let (offset, line) = starts.borrow_mut().pop_front().unwrap_or((0, 1));
+    return Some(Document { value, filename: filename.clone(), offset, line, duplicates, stream_index: None });
+  }).peekable();
+
+  // a file is a stream when there's more than one document in it, which for
+  // the first one means waiting for the start of the next. a resumed file
+  // was already part way through one
+  let mut index = 0;
+  std::iter::from_fn(move || {
+    let mut doc = docs.next()?;
+    if index > 0 || offset > 0 || docs.peek().is_some() {
+      doc.stream_index = Some(index);
+    }
+    index += 1;
+    return Some(doc);
+  })
+}
+
+// reads text for --raw-input as a single document holding an array of its
+// records. like awk's RS, the separator is a newline by default, a single
+// character is taken literally and anything longer is a regex. an empty
+// separator is paragraph mode, where records are split by blank lines
+pub fn read_raw<T: Read>(mut rdr: T, filename: &str, separator: &str) -> Document {
+  let mut text = String::new();
+  rdr.read_to_string(&mut text).unwrap_or_else(|err| panic!("error reading input: {}", err));
+
+  let mut records: Vec<&str> = match separator.chars().count() {
+    0 => {
+      text = String::from(text.trim_matches('\n'));
+      Regex::new("\n\n+").unwrap().split(&text).collect()
+    },
+    1 => text.split(separator).collect(),
+    _ => Regex::new(separator)
+      .unwrap_or_else(|err| panic!("invalid --record-sep {}: {}", separator, err))
+      .split(&text)
+      .collect(),
+  };
+  // a separator at the very end doesn't start another record
+  if records.last() == Some(&"") {
+    records.pop();
+  }
+
+  let records = records.into_iter().map(|r| serde_json::Value::String(String::from(r))).collect();
+  Document { value: serde_json::Value::Array(records), filename: String::from(filename), offset: 0, line: 1, duplicates: Vec::new(), stream_index: None }
+}
+
+// a csv value, as a number if it looks like one
+fn csv_value(field: &str) -> serde_json::Value {
+  match field.trim().parse::<f64>() {
+    Ok(n) if !field.trim().is_empty() => serde_json::Number::from_f64(n)
+      .map_or(serde_json::Value::String(String::from(field)), serde_json::Value::Number),
+    _ => serde_json::Value::String(String::from(field)),
+  }
+}
+
+// reads csv as a single document holding an array with an object for each
+// row, keyed by the header row
+pub fn read_csv<T: Read>(rdr: T, filename: &str) -> Document {
+  let mut reader = csv::Reader::from_reader(rdr);
+  let headers = reader.headers().unwrap_or_else(|err| panic!("error parsing CSV: {}", err)).clone();
+  let rows = reader.records().map(|record| {
+    let record = record.unwrap_or_else(|err| panic!("error parsing CSV: {}", err));
+    let row = headers.iter().zip(record.iter())
+      .map(|(header, field)| (String::from(header), csv_value(field)))
+      .collect();
+    serde_json::Value::Object(row)
+  }).collect();
+  Document { value: serde_json::Value::Array(rows), filename: String::from(filename), offset: 0, line: 1, duplicates: Vec::new(), stream_index: None }
+}
+
+// reads each document in a yaml stream
+pub fn read_yaml<T: Read>(mut rdr: T, filename: &str) -> Vec<Document> {
+  use serde::Deserialize;
+
+  let mut text = String::new();
+  rdr.read_to_string(&mut text).unwrap_or_else(|err| panic!("error reading input: {}", err));
+  let mut docs: Vec<Document> = serde_yaml::Deserializer::from_str(&text).map(|doc| {
+    let value = serde_json::Value::deserialize(doc).unwrap_or_else(|err| panic!("error parsing YAML: {}", err));
+    Document { value, filename: String::from(filename), offset: 0, line: 1, duplicates: Vec::new(), stream_index: None }
+  }).collect();
+  // like json, several documents are a stream of records
+  if docs.len() > 1 {
+    for (i, doc) in docs.iter_mut().enumerate() {
+      doc.stream_index = Some(i);
+    }
+  }
+  return docs;
+}
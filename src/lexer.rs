@@ -1,28 +1,60 @@
 use std::fmt;
 
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Copy, Clone, PartialEq, Debug)]
 pub enum TokenKind {
     Dollar,
     Dot,
+    DotDot,
     Plus,
     Minus,
     Star,
     Slash,
     Equal,
     EqualEqual,
+    PlusEqual,
+    MinusEqual,
+    PlusPlus,
+    MinusMinus,
+    StarEqual,
+    SlashEqual,
     AmpersandAmpersand,
+    Pipe,
+    PipeGreater,
+    Tilde,
+    Bang,
+    BangTilde,
     LCurly,
     RCurly,
     LSquare,
     RSquare,
+    LParen,
+    RParen,
     LAngle,
     RAngle,
+    LAngleEqual,
+    RAngleEqual,
+    BangEqual,
     Comma,
+    Colon,
+    Question,
     Semicolon,
     Str,
+    Regex,
     Num,
     Identifier,
     Print,
+    Printf,
+    Eprint,
+    Getline,
+    Define,
+    Let,
+    For,
+    In,
+    Foreach,
+    As,
+    Exit,
+    Delete,
     Begin,
     End,
     Error, 
@@ -34,23 +66,54 @@ impl fmt::Display for TokenKind {
       write!(f, "{}", match self {
         TokenKind::Dollar => "$",
         TokenKind::Dot => ".",
+        TokenKind::DotDot => "..",
         TokenKind::Plus => "+",
         TokenKind::Minus => "-",
         TokenKind::Star => "*",
         TokenKind::Slash => "/",
         TokenKind::Equal => "=",
         TokenKind::EqualEqual => "==",
+        TokenKind::PlusEqual => "+=",
+        TokenKind::MinusEqual => "-=",
+        TokenKind::PlusPlus => "++",
+        TokenKind::MinusMinus => "--",
+        TokenKind::StarEqual => "*=",
+        TokenKind::SlashEqual => "/=",
         TokenKind::AmpersandAmpersand => "&&",
+        TokenKind::Pipe => "|",
+        TokenKind::PipeGreater => "|>",
+        TokenKind::Tilde => "~",
+        TokenKind::Bang => "!",
+        TokenKind::BangTilde => "!~",
         TokenKind::LCurly => "{",
         TokenKind::RCurly => "}",
         TokenKind::LSquare => "[",
         TokenKind::RSquare => "]",
+        TokenKind::LParen => "(",
+        TokenKind::RParen => ")",
         TokenKind::LAngle => "<",
         TokenKind::RAngle => ">",
+        TokenKind::LAngleEqual => "<=",
+        TokenKind::RAngleEqual => ">=",
+        TokenKind::BangEqual => "!=",
         TokenKind::Comma => ",",
+        TokenKind::Colon => ":",
+        TokenKind::Question => "?",
         TokenKind::Semicolon => ";",
         TokenKind::Print => "print",
+        TokenKind::Printf => "printf",
+        TokenKind::Eprint => "eprint",
+        TokenKind::Getline => "getline",
+        TokenKind::Define => "define",
+        TokenKind::Let => "let",
+        TokenKind::For => "for",
+        TokenKind::In => "in",
+        TokenKind::Foreach => "foreach",
+        TokenKind::As => "as",
+        TokenKind::Exit => "exit",
+        TokenKind::Delete => "delete",
         TokenKind::Str => "<string>",
+        TokenKind::Regex => "<regex>",
         TokenKind::Num => "<num>",
         TokenKind::Identifier => "<identifier>",
         TokenKind::Begin => "BEGIN",
@@ -66,6 +129,11 @@ pub struct Token {
   pub kind: TokenKind,
   pub str: Option<String>,
   pub line: usize,
+  pub col: usize,
+  // index of the token's first character in the source, and of the one
+  // after its last
+  pub offset: usize,
+  pub end: usize,
 }
 
 impl fmt::Display for Token {
@@ -73,44 +141,95 @@ impl fmt::Display for Token {
       match self.kind {
         TokenKind::Str | TokenKind::Identifier | TokenKind::Num =>
           write!(f, "{}", self.str.as_ref().unwrap()),
+        TokenKind::Regex => write!(f, "/{}/", self.str.as_ref().unwrap().replace('/', "\\/")),
         _ => write!(f, "{}", self.kind),
       }
     }
 }
 
 impl Token {
-  pub fn new(kind: TokenKind, line: usize) -> Token {
+  pub fn new(kind: TokenKind, line: usize, col: usize) -> Token {
     Token {
-      kind: kind,
+      kind,
       str: None,
       line,
+      col,
+      offset: 0,
+      end: 0,
     }
   }
 }
 
+fn is_identifier_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// writes a string back out as a literal, the reverse of Lexer::string
+pub fn quote(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            _ => out.push(c),
+        }
+    }
+    out.push('"');
+    return out;
+}
+
+// whether a name can be written as a bare identifier, e.g. after `.`
+pub fn is_identifier(s: &str) -> bool {
+    match s.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => s.chars().all(is_identifier_char),
+        _ => false,
+    }
+}
+
 #[derive(Debug)]
 pub struct Lexer {
-    src: String,
+    src: Vec<char>,
     pos: usize,
     token_start: usize,
     line: usize,
+    line_start: usize,
+    // whether the last token ended a value, so a / after it divides it
+    // rather than starting a regex
+    after_operand: bool,
 }
 
 impl Lexer {
     pub fn new(src: &str) -> Lexer {
         Lexer {
-            src: String::from(src),
+            src: src.chars().collect(),
             pos: 0,
             token_start: 0,
             line: 1,
+            line_start: 0,
+            after_operand: false,
         }
     }
 
+    // tokens are positioned at their first character
+    fn col(&self) -> usize {
+        self.token_start - self.line_start + 1
+    }
+
+    pub fn text(&self, start: usize, end: usize) -> String {
+        self.src[start..end].iter().collect()
+    }
+
     fn simple_token(&self, kind: TokenKind) -> Token {
         Token {
             kind,
             str: None,
             line: self.line,
+            col: self.col(),
+            offset: self.token_start,
+            end: self.pos,
         }
     }
 
@@ -119,6 +238,9 @@ impl Lexer {
             kind,
             str: Some(String::from(str)),
             line: self.line,
+            col: self.col(),
+            offset: self.token_start,
+            end: self.pos,
         }
     }
 
@@ -127,26 +249,34 @@ impl Lexer {
             kind: TokenKind::Error,
             str: Some(message),
             line: self.line,
+            col: self.col(),
+            offset: self.token_start,
+            end: self.pos,
         }
     }
 
     fn advance(&mut self) -> Option<char> {
-        if self.pos <= self.src.len() {
-            self.pos += 1
+        let c = self.src.get(self.pos).copied();
+        if c.is_some() {
+            self.pos += 1;
         }
-        self.src.chars().nth(self.pos - 1)
+        c
     }
     fn peek(&mut self) -> Option<char> {
-        self.src.chars().nth(self.pos)
+        self.src.get(self.pos).copied()
+    }
+    fn peek_at(&self, n: usize) -> Option<char> {
+        self.src.get(self.pos + n).copied()
     }
 
     fn skip_whitespace(&mut self) {
         loop {
             match self.peek() {
-                Some(' ') | Some('\r') => self.pos += 1,
+                Some(' ') | Some('\t') | Some('\r') => self.pos += 1,
                 Some('\n') => {
                     self.pos += 1;
                     self.line += 1;
+                    self.line_start = self.pos;
                 }
                 _ => break,
             }
@@ -154,41 +284,136 @@ impl Lexer {
     }
 
     fn identifier(&mut self) -> Token {
-        while self.peek().unwrap_or_default().is_ascii_alphabetic() {
+        while is_identifier_char(self.peek().unwrap_or_default()) {
             self.advance();
         }
-        let ident = &self.src[self.token_start..self.pos];
+        let ident = self.text(self.token_start, self.pos);
 
-        match ident {
+        match ident.as_str() {
           "print" => self.simple_token(TokenKind::Print),
+          "printf" => self.simple_token(TokenKind::Printf),
+          "eprint" => self.simple_token(TokenKind::Eprint),
+          "getline" => self.simple_token(TokenKind::Getline),
+          "define" => self.simple_token(TokenKind::Define),
+          "let" => self.simple_token(TokenKind::Let),
+          "for" => self.simple_token(TokenKind::For),
+          "in" => self.simple_token(TokenKind::In),
+          "foreach" => self.simple_token(TokenKind::Foreach),
+          "as" => self.simple_token(TokenKind::As),
+          "exit" => self.simple_token(TokenKind::Exit),
+          "delete" => self.simple_token(TokenKind::Delete),
           "BEGIN" => self.simple_token(TokenKind::Begin),
           "END" => self.simple_token(TokenKind::End),
-          _ => self.str_token(TokenKind::Identifier, ident),
+          _ => self.str_token(TokenKind::Identifier, &ident),
         }
     }
 
+    // digits with an optional fraction and exponent, like 3.14, .5 or 1e6.
+    // underscores can separate the digits, as in 1_000_000
     fn number(&mut self) -> Token {
-        while self.peek().unwrap_or_default().is_ascii_digit() {
+        self.digits();
+        if self.peek() == Some('.') && self.peek_at(1).unwrap_or_default().is_ascii_digit() {
             self.advance();
+            self.digits();
+        }
+        if matches!(self.peek(), Some('e') | Some('E')) {
+            let sign = matches!(self.peek_at(1), Some('+') | Some('-')) as usize;
+            if self.peek_at(1 + sign).unwrap_or_default().is_ascii_digit() {
+                self.pos += 1 + sign;
+                self.digits();
+            }
+        }
+        let num = self.text(self.token_start, self.pos);
+        return self.str_token(TokenKind::Num, &num);
+    }
+
+    fn digits(&mut self) {
+        loop {
+            match self.peek() {
+                Some(c) if c.is_ascii_digit() => self.pos += 1,
+                Some('_') if self.peek_at(1).unwrap_or_default().is_ascii_digit() => self.pos += 1,
+                _ => return,
+            }
         }
-        let num = &self.src[self.token_start..self.pos];
-        return self.str_token(TokenKind::Num, num);
     }
 
     fn string(&mut self) -> Token {
+        let mut str_content = String::new();
         loop {
             match self.peek() {
                 Some('"') => break,
-                Some(_) => { self.advance(); },
-                None => return self.err_token(String::from("unexpected EOF in string")),
+                // leave the newline for skip_whitespace so line numbers stay right
+                Some('\n') | None => return self.err_token(String::from("unterminated string")),
+                Some('\\') => {
+                    self.advance();
+                    match self.peek() {
+                        Some('\n') | None => return self.err_token(String::from("unterminated string")),
+                        Some(c) => {
+                            self.advance();
+                            match c {
+                                'n' => str_content.push('\n'),
+                                't' => str_content.push('\t'),
+                                'r' => str_content.push('\r'),
+                                '"' | '\\' | '/' => str_content.push(c),
+                                // like awk, unknown escapes are kept as they are
+                                _ => {
+                                    str_content.push('\\');
+                                    str_content.push(c);
+                                },
+                            }
+                        },
+                    }
+                },
+                Some(c) => {
+                    self.advance();
+                    str_content.push(c);
+                },
             }
         }
         self.advance();
-        let str_content = &self.src[self.token_start + 1 .. self.pos - 1];
-        return self.str_token(TokenKind::Str, str_content);
+        return self.str_token(TokenKind::Str, &str_content);
     }
 
     pub fn next_token(&mut self) -> Token {
+        let token = self.scan_token();
+        self.after_operand = matches!(token.kind,
+            TokenKind::Identifier | TokenKind::Num | TokenKind::Str | TokenKind::Regex | TokenKind::Dollar |
+            TokenKind::RParen | TokenKind::RSquare | TokenKind::PlusPlus | TokenKind::MinusMinus);
+        return token;
+    }
+
+    // `/regex/`, where the only escape is \/ for a slash. the rest are left
+    // for the regex
+    fn regex(&mut self) -> Token {
+        let mut pattern = String::new();
+        loop {
+            match self.peek() {
+                Some('/') => break,
+                // leave the newline for skip_whitespace so line numbers stay right
+                Some('\n') | None => return self.err_token(String::from("unterminated regex")),
+                Some('\\') => {
+                    self.advance();
+                    match self.peek() {
+                        Some('\n') | None => return self.err_token(String::from("unterminated regex")),
+                        Some('/') => pattern.push('/'),
+                        Some(c) => {
+                            pattern.push('\\');
+                            pattern.push(c);
+                        },
+                    }
+                    self.advance();
+                },
+                Some(c) => {
+                    self.advance();
+                    pattern.push(c);
+                },
+            }
+        }
+        self.advance();
+        return self.str_token(TokenKind::Regex, &pattern);
+    }
+
+    fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
         self.token_start = self.pos;
 
@@ -197,7 +422,7 @@ impl Lexer {
             None => return self.simple_token(TokenKind::EOF),
         };
 
-        if c.is_ascii_alphabetic() {
+        if c.is_ascii_alphabetic() || c == '_' {
             return self.identifier();
         }
 
@@ -211,7 +436,31 @@ impl Lexer {
 
         match c {
             '$' => return self.simple_token(TokenKind::Dollar),
-            '.' => return self.simple_token(TokenKind::Dot),
+            '.' if !self.after_operand && self.peek().unwrap_or_default().is_ascii_digit() => {
+                self.pos -= 1;
+                return self.number();
+            },
+            '.' => {
+                if self.peek() == Some('.') {
+                    self.advance();
+                    return self.simple_token(TokenKind::DotDot);
+                }
+                return self.simple_token(TokenKind::Dot);
+            },
+            '+' | '-' if self.peek() == Some(c) => {
+                self.advance();
+                return self.simple_token(if c == '+' { TokenKind::PlusPlus } else { TokenKind::MinusMinus });
+            },
+            '/' if !self.after_operand => return self.regex(),
+            '+' | '-' | '*' | '/' if self.peek() == Some('=') => {
+                self.advance();
+                return self.simple_token(match c {
+                    '+' => TokenKind::PlusEqual,
+                    '-' => TokenKind::MinusEqual,
+                    '*' => TokenKind::StarEqual,
+                    _ => TokenKind::SlashEqual,
+                });
+            },
             '+' => return self.simple_token(TokenKind::Plus),
             '-' => return self.simple_token(TokenKind::Minus),
             '*' => return self.simple_token(TokenKind::Star),
@@ -220,15 +469,38 @@ impl Lexer {
             '}' => return self.simple_token(TokenKind::RCurly),
             '[' => return self.simple_token(TokenKind::LSquare),
             ']' => return self.simple_token(TokenKind::RSquare),
+            '(' => return self.simple_token(TokenKind::LParen),
+            ')' => return self.simple_token(TokenKind::RParen),
+            '<' | '>' if self.peek() == Some('=') => {
+                self.advance();
+                return self.simple_token(if c == '<' { TokenKind::LAngleEqual } else { TokenKind::RAngleEqual });
+            },
             '<' => return self.simple_token(TokenKind::LAngle),
             '>' => return self.simple_token(TokenKind::RAngle),
             ',' => return self.simple_token(TokenKind::Comma),
+            ':' => return self.simple_token(TokenKind::Colon),
+            '?' => return self.simple_token(TokenKind::Question),
             ';' => return self.simple_token(TokenKind::Semicolon),
-            '&' => {
-                if self.peek() == Some('&') {
+            '~' => return self.simple_token(TokenKind::Tilde),
+            '|' => {
+                if self.peek() == Some('>') {
                     self.advance();
-                    return self.simple_token(TokenKind::AmpersandAmpersand);
+                    return self.simple_token(TokenKind::PipeGreater);
                 }
+                return self.simple_token(TokenKind::Pipe);
+            },
+            '!' if self.peek() == Some('~') => {
+                self.advance();
+                return self.simple_token(TokenKind::BangTilde);
+            },
+            '!' if self.peek() == Some('=') => {
+                self.advance();
+                return self.simple_token(TokenKind::BangEqual);
+            },
+            '!' => return self.simple_token(TokenKind::Bang),
+            '&' if self.peek() == Some('&') => {
+                self.advance();
+                return self.simple_token(TokenKind::AmpersandAmpersand);
             },
             '=' => {
                 if self.peek() == Some('=') {
@@ -0,0 +1,196 @@
+use std::collections::HashSet;
+use std::fmt;
+use regex_syntax::hir::{Hir, HirKind, Look};
+use crate::compiler::{JqaRule, JqaRuleKind};
+use crate::vm::{OpCode, Value, Vm, BUILTIN_VARIABLES};
+
+pub struct Warning {
+  pub line: usize,
+  pub message: String,
+}
+
+impl fmt::Display for Warning {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "warning on line {}: {}", self.line, self.message)
+  }
+}
+
+// calls func for each opcode in order, including ones nested in filters and
+// lambdas
+fn walk<F: FnMut(&OpCode)>(code: &[OpCode], func: &mut F) {
+  for op in code.iter() {
+    func(op);
+    match op {
+      OpCode::Filter(nested) | OpCode::MapFilter(nested) => walk(nested, func),
+      OpCode::CallLambda(_, _, lambda) => walk(&lambda.body, func),
+      OpCode::ForIn(_, body) | OpCode::ForEach(_, body) => walk(body, func),
+      OpCode::Range(_, start, end) => {
+        walk(start, func);
+        walk(end, func);
+      },
+      _ => (),
+    }
+  }
+}
+
+// the variables an opcode assigns to
+fn assigned_by(op: &OpCode) -> Vec<&String> {
+  match op {
+    OpCode::SetGlobal(name) | OpCode::Getline(Some(name)) | OpCode::SetElement(name, _, _) |
+    OpCode::ForIn(name, _) | OpCode::ForEach(Some(name), _) => vec![name],
+    OpCode::DestructureObject(names) => names.iter().map(|(_, name)| name).collect(),
+    OpCode::DestructureArray(names) => names.iter().collect(),
+    _ => Vec::new(),
+  }
+}
+
+// the regexes written out in code: `/re/` literals, and strings that are
+// matched against straight away like `s ~ "re"`
+fn regex_literals(code: &[OpCode]) -> Vec<String> {
+  let mut patterns = Vec::new();
+  let mut last_string = None;
+  walk(code, &mut |op| {
+    match op {
+      OpCode::PushImmediate(Value::Regex(re)) => patterns.push(String::from(re.pattern())),
+      OpCode::Match | OpCode::NotMatch => patterns.extend(last_string.take()),
+      _ => (),
+    }
+    last_string = match op {
+      OpCode::PushImmediate(Value::Str(s)) => Some(s.clone()),
+      _ => None,
+    };
+  });
+  return patterns;
+}
+
+// whether every way through a regex is impossible, like `a^b` with
+// something before the start of the text, or `a$b` with something after the
+// end. without the m flag ^ and $ are only the ends of the whole text
+fn impossible(hir: &Hir) -> bool {
+  let consumes = |hir: &Hir| hir.properties().minimum_len().is_some_and(|len| len > 0);
+  match hir.kind() {
+    HirKind::Concat(parts) => parts.iter().enumerate().any(|(i, part)| {
+      impossible(part) || match part.kind() {
+        HirKind::Look(Look::Start) => parts[..i].iter().any(consumes),
+        HirKind::Look(Look::End) => parts[i + 1..].iter().any(consumes),
+        _ => false,
+      }
+    }),
+    HirKind::Alternation(branches) => branches.iter().all(impossible),
+    HirKind::Capture(capture) => impossible(&capture.sub),
+    HirKind::Repetition(repetition) => repetition.min > 0 && impossible(&repetition.sub),
+    _ => false,
+  }
+}
+
+// whether a regex can never match anything. one that doesn't parse is left
+// for the error when it's used
+fn never_matches(pattern: &str) -> bool {
+  match regex_syntax::Parser::new().parse(pattern) {
+    // an empty class, like [a&&b], has no minimum length as nothing matches
+    Ok(hir) => hir.properties().minimum_len().is_none() || impossible(&hir),
+    Err(_) => false,
+  }
+}
+
+fn rules_of_kind(rules: &[JqaRule], kind: JqaRuleKind) -> impl Iterator<Item = &JqaRule> {
+  rules.iter().filter(move |rule| rule.kind == kind)
+}
+
+struct Linter {
+  warnings: Vec<Warning>,
+  assigned: HashSet<String>,
+  warned: HashSet<String>,
+}
+
+impl Linter {
+  fn warn(&mut self, line: usize, message: String) {
+    self.warnings.push(Warning { line, message });
+  }
+
+  // reports variables read before anything could have assigned them
+  fn check_reads(&mut self, rule: &JqaRule) {
+    let mut reads = Vec::new();
+    for code in [&rule.pattern, &rule.body].iter() {
+      walk(code, &mut |op| match op {
+        OpCode::GetGlobal(name) => reads.push((name.clone(), true)),
+        _ => reads.extend(assigned_by(op).into_iter().map(|name| (name.clone(), false))),
+      });
+    }
+
+    for (name, is_read) in reads.into_iter() {
+      if !is_read {
+        self.assigned.insert(name);
+      } else if !self.assigned.contains(&name) && !self.warned.contains(&name) {
+        self.warn(rule.line, format!("variable '{}' is read before it is assigned", name));
+        self.warned.insert(name);
+      }
+    }
+  }
+}
+
+// looks for likely mistakes in a compiled program. predefined holds
+// variables set from outside the program, e.g. with -v
+pub fn lint(rules: &[JqaRule], predefined: &[&str]) -> Vec<Warning> {
+  let mut linter = Linter {
+    warnings: Vec::new(),
+    assigned: BUILTIN_VARIABLES.iter().chain(predefined.iter()).map(|s| String::from(*s)).collect(),
+    warned: HashSet::new(),
+  };
+
+  for rule in rules.iter() {
+    for code in [&rule.pattern, &rule.body].iter() {
+      for pattern in regex_literals(code).into_iter().filter(|p| never_matches(p)) {
+        linter.warn(rule.line, format!("regex /{}/ can never match", pattern));
+      }
+    }
+
+    let mut assigns = false;
+    walk(&rule.pattern, &mut |op| assigns |= !assigned_by(op).is_empty());
+    if assigns {
+      linter.warn(rule.line, String::from("assignment in pattern, did you mean ==?"));
+    } else if let Some(v) = Vm::eval_constant(&rule.pattern) {
+      if !v.truthy() {
+        linter.warn(rule.line, String::from("pattern is always false"));
+      }
+    }
+  }
+
+  // rules are checked in the order they run. match rules run repeatedly, so
+  // an assignment in any of them counts for all of them
+  for rule in rules_of_kind(rules, JqaRuleKind::Begin) {
+    linter.check_reads(rule);
+  }
+  for rule in rules_of_kind(rules, JqaRuleKind::Match) {
+    walk(&rule.body, &mut |op| for name in assigned_by(op) {
+      linter.assigned.insert(name.clone());
+    });
+  }
+  for rule in rules_of_kind(rules, JqaRuleKind::Match) {
+    linter.check_reads(rule);
+  }
+  for rule in rules_of_kind(rules, JqaRuleKind::End) {
+    linter.check_reads(rule);
+  }
+
+  let mut read = HashSet::new();
+  for rule in rules.iter() {
+    walk(&rule.pattern, &mut |op| if let OpCode::GetGlobal(name) = op { read.insert(name.clone()); });
+    walk(&rule.body, &mut |op| if let OpCode::GetGlobal(name) = op { read.insert(name.clone()); });
+  }
+  // builtins like IGNORECASE are read by the vm itself
+  let mut reported: HashSet<String> = BUILTIN_VARIABLES.iter().map(|s| String::from(*s)).collect();
+  for rule in rules.iter() {
+    walk(&rule.body, &mut |op| for name in assigned_by(op) {
+      if !read.contains(name) && reported.insert(name.clone()) {
+        linter.warnings.push(Warning {
+          line: rule.line,
+          message: format!("variable '{}' is assigned but never read", name),
+        });
+      }
+    });
+  }
+
+  linter.warnings.sort_by_key(|w| w.line);
+  return linter.warnings;
+}
@@ -1,46 +1,200 @@
+#![allow(clippy::needless_return)]
+
 mod lexer;
 mod compiler;
 mod vm;
+mod cache;
+mod input;
+mod spec;
+mod lint;
+mod formatter;
+mod diagnostics;
+mod sample;
+mod aggregate;
+mod diff;
+mod schema;
+mod table;
+mod printf;
+mod verify;
+mod collate;
+mod checkpoint;
+mod preprocess;
+mod pipeline;
+mod output;
 
 use lexer::Lexer;
-use compiler::Compiler;
+use compiler::{Compiler, CompileError, JqaRule, SelectorBranch};
 use vm::Vm;
+use diagnostics::Diagnostics;
 
-use clap::{App, Arg, ArgMatches};
-use atty;
+use clap::{App, Arg, ArgMatches, SubCommand};
 use std::fs;
 use std::fs::File;
 use std::io;
+use std::io::Seek;
+use std::process;
+use std::time::Duration;
 
-fn run_program_file<T>(path: &str, rdr: T, selector: &str)
-    where T: std::io::Read {
-    let content = fs::read_to_string(path)
-        .expect("error reading program file");
+fn report_compile_errors(diagnostics: &Diagnostics, errors: Vec<CompileError>) -> ! {
+    for err in errors.iter() {
+        diagnostics.compile_error(err);
+    }
+    process::exit(1);
+}
 
-    let lexer = Lexer::new(content.as_str());
+fn compile_program(program: &str, cache_dir: Option<&str>, diagnostics: &Diagnostics) -> Vec<JqaRule> {
+    // @if sections depend on the environment, so only what's left of the
+    // program is cached
+    let program = &preprocess::preprocess(program)
+        .unwrap_or_else(|errors| report_compile_errors(diagnostics, errors));
+    if let Some(dir) = cache_dir {
+        if let Some(rules) = cache::load(dir, program) {
+            return rules;
+        }
+    }
+
+    let lexer = Lexer::new(program);
     let mut compiler = Compiler::new(lexer);
-    let rules = compiler.compile_rules();
+    let rules = compiler.compile_rules()
+        .unwrap_or_else(|errors| report_compile_errors(diagnostics, errors));
+
+    if let Some(dir) = cache_dir {
+        cache::store(dir, program, &rules);
+    }
+    return rules;
+}
 
-    let s_lexer = Lexer::new(selector);
-    let mut s_compiler = Compiler::new(s_lexer);
-    let selector_program = s_compiler.compile_expression();
+// a rule that never matched is usually a typo'd field name, so those are
+// called out
+fn explain(rules: &[JqaRule], hits: &[usize]) {
+    for (rule, count) in rules.iter().zip(hits.iter()) {
+        let marker = if *count == 0 { "!" } else { " " };
+        let source = rule.source.replace('\n', "\n         ");
+        eprintln!("{}{:>6}  {}", marker, count, source);
+    }
+}
 
-    let mut vm = Vm::new(false);
-    vm.run(rdr, selector_program, rules);
+// whether there's no program argument: it's in a file, generated, or with
+// --schema and a single argument, left out to just report invalid records
+fn no_program(matches: &ArgMatches) -> bool {
+    has_program_sources(matches) || matches.is_present("group_by") || matches.is_present("agg")
+        || (matches.is_present("schema") && !matches.is_present("INPUT"))
 }
 
-fn get_input(matches: &ArgMatches) -> Box<dyn io::Read> {
-    if matches.is_present("INPUT") {
-        let file = File::open(matches.value_of("INPUT").unwrap())
-            .expect("error opening input file");
-        return Box::new(file);
+fn has_program_sources(matches: &ArgMatches) -> bool {
+    matches.is_present("program_file") || matches.is_present("snippet")
+}
+
+// the -f files and -e snippets joined in the order they were given, so a
+// generated filter can follow a library of rules
+fn program_sources(matches: &ArgMatches) -> String {
+    let mut sources: Vec<(usize, String)> = Vec::new();
+    if let (Some(indices), Some(paths)) = (matches.indices_of("program_file"), matches.values_of("program_file")) {
+        for (i, path) in indices.zip(paths) {
+            let program = fs::read_to_string(path)
+                .unwrap_or_else(|err| panic!("error reading program file {}: {}", path, err));
+            sources.push((i, program));
+        }
+    }
+    if let (Some(indices), Some(snippets)) = (matches.indices_of("snippet"), matches.values_of("snippet")) {
+        sources.extend(indices.zip(snippets.map(String::from)));
     }
+    sources.sort_by_key(|(i, _)| *i);
+    return sources.into_iter().map(|(_, source)| source).collect::<Vec<_>>().join("\n");
+}
 
-    if atty::isnt(atty::Stream::Stdin) {
-        return Box::new(io::stdin());
+// the input files in order. without a program argument the first
+// positional argument is one of them
+fn input_paths<'a>(matches: &'a ArgMatches) -> Vec<&'a str> {
+    let mut paths = Vec::new();
+    if no_program(matches) {
+        paths.extend(matches.value_of("PROGRAM"));
     }
+    paths.extend(matches.values_of("INPUT").into_iter().flatten());
+    return paths;
+}
+
+// like cat, - reads stdin. without any input files stdin is read too,
+// unless it's a terminal, where there's an empty object instead or with
+// --interactive whatever is typed. gzipped input is decompressed. offset
+// skips that many bytes of the decompressed input, for --resume
+fn get_input(path: Option<&str>, interactive: bool, offset: u64) -> Box<dyn io::Read + Send> {
+    let input: Box<dyn io::Read + Send> = match path {
+        Some("-") => Box::new(io::stdin()),
+        Some(path) => {
+            let file = File::open(path)
+                .unwrap_or_else(|err| panic!("error opening input file {}: {}", path, err));
+            let mut reader = io::BufReader::new(file);
+            let head = io::BufRead::fill_buf(&mut reader)
+                .unwrap_or_else(|err| panic!("error reading input file {}: {}", path, err));
+            // only a file that isn't compressed can seek past what's been read
+            if !input::is_gzip(head) {
+                reader.seek(io::SeekFrom::Start(offset))
+                    .unwrap_or_else(|err| panic!("error reading input file {}: {}", path, err));
+                return Box::new(reader);
+            }
+            Box::new(reader)
+        },
+        None if atty::isnt(atty::Stream::Stdin) => Box::new(io::stdin()),
+        None if interactive => {
+            eprintln!("reading JSON from the terminal, press Ctrl-D to finish");
+            Box::new(io::stdin())
+        },
+        None => return Box::new("{}".as_bytes()),
+    };
+
+    let mut input = input::decompress(input);
+    // what's been read before is read again and dropped
+    io::copy(&mut io::Read::take(&mut input, offset), &mut io::sink())
+        .unwrap_or_else(|err| panic!("error reading input: {}", err));
+    return input;
+}
+
+fn compile_selector(selector: &str, format: diagnostics::Format) -> Vec<SelectorBranch> {
+    let mut compiler = Compiler::new(Lexer::new(selector));
+    // the selector never comes from the program file
+    let diagnostics = Diagnostics { format, file: None };
+    return compiler.compile_selector()
+        .unwrap_or_else(|errors| report_compile_errors(&diagnostics, errors));
+}
 
-    return Box::new("{}".as_bytes());
+// the first document in a file, for diff
+fn read_document(path: &str, max_depth: usize, dup_keys: input::DupKeys) -> input::Document {
+    let file = File::open(path).expect("error opening input file");
+    return input::read_json(file, path, max_depth, dup_keys).next()
+        .unwrap_or_else(|| panic!("{} has no JSON in it", path));
+}
+
+fn diff(matches: &ArgMatches, diff_matches: &ArgMatches, max_depth: usize, dup_keys: input::DupKeys, diagnostics: &Diagnostics) {
+    let rules = match diff_matches.value_of("PROGRAM") {
+        Some(program) => compile_program(program, matches.value_of("compile_cache"), diagnostics),
+        None => Vec::new(),
+    };
+    let selector = compile_selector(diff_matches.value_of("root").unwrap(), diagnostics.format);
+
+    let a = read_document(diff_matches.value_of("A").unwrap(), max_depth, dup_keys);
+    let b = read_document(diff_matches.value_of("B").unwrap(), max_depth, dup_keys);
+    let mut vm = Vm::new(false);
+    vm.run_diff(a, b, selector, rules);
+    if let Some(code) = vm.exit_code() {
+        process::exit(code);
+    }
+}
+
+// evaluates one expression against the first document of the input, for
+// using jqawk as a calculator
+fn eval(eval_matches: &ArgMatches, max_depth: usize, dup_keys: input::DupKeys, diagnostics: &Diagnostics) {
+    let expr = eval_matches.value_of("EXPRESSION").unwrap();
+    let code = Compiler::new(Lexer::new(expr)).compile_expression()
+        .unwrap_or_else(|errors| report_compile_errors(&Diagnostics { format: diagnostics.format, file: None }, errors));
+    let selector = compile_selector(eval_matches.value_of("root").unwrap(), diagnostics.format);
+
+    let path = eval_matches.value_of("INPUT");
+    let reader = io::BufReader::new(get_input(path, false, 0));
+    let doc = input::read_json(reader, path.unwrap_or(""), max_depth, dup_keys).next()
+        .unwrap_or_else(|| panic!("{} has no JSON in it", path.unwrap_or("the input")));
+    let mut vm = Vm::new(false);
+    vm.run_eval(doc, selector, code);
 }
 
 fn main() {
@@ -53,33 +207,511 @@ fn main() {
             .long("root")
             .takes_value(true)
             .default_value("$")
-            .hide_default_value(true))
+            .hide_default_value(true)
+            .global(true))
+        .arg(Arg::with_name("root_for")
+            .help("uses a different root selector for one input file, given by its path or name")
+            .long("root-for")
+            .takes_value(true)
+            .value_name("FILE=SELECTOR")
+            .multiple(true)
+            .number_of_values(1))
         .arg(Arg::with_name("program_file")
             .short("f")
-            .help("a script file to run")
-            .takes_value(true))
+            .help("a script file to run, which can be given more than once")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("snippet")
+            .short("e")
+            .help("rules to run, joined with any others and -f files in order")
+            .takes_value(true)
+            .value_name("RULES")
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("compile_cache")
+            .long("compile-cache")
+            .help("a directory to cache compiled programs in")
+            .takes_value(true)
+            .value_name("DIR"))
+        .arg(Arg::with_name("raw_input")
+            .short("R")
+            .long("raw-input")
+            .help("reads the input as text, each line becoming a string record, the same as --from raw"))
+        .arg(Arg::with_name("record_sep")
+            .long("record-sep")
+            .help("splits raw input into records on this string, a regex if it's longer than one character, or blank lines if it's empty")
+            .takes_value(true)
+            .value_name("SEP"))
+        .arg(Arg::with_name("from")
+            .long("from")
+            .help("the format of the input, by default guessed from each file's extension or contents")
+            .takes_value(true)
+            .possible_values(&["auto", "json", "csv", "yaml", "raw"])
+            .default_value("auto"))
+        .arg(Arg::with_name("max_depth")
+            .long("max-depth")
+            .help("the deepest nesting allowed in the input, 1000 by default and at most 2000. deeper input is an error")
+            .takes_value(true)
+            .value_name("N"))
+        .arg(Arg::with_name("dup_keys")
+            .long("dup-keys")
+            .help("which value a key that's in a JSON object more than once keeps, or whether it's an error. DUPKEYS lists them either way")
+            .takes_value(true)
+            .possible_values(&["first", "last", "error"])
+            .default_value("last"))
+        .arg(Arg::with_name("assign")
+            .short("v")
+            .help("assigns a global variable before the program runs")
+            .takes_value(true)
+            .value_name("NAME=VALUE")
+            .multiple(true)
+            .number_of_values(1))
+        .arg(Arg::with_name("argfile")
+            .long("argfile")
+            .help("parses a JSON file and assigns it to a global variable")
+            .takes_value(true)
+            .value_names(&["NAME", "FILE"])
+            .multiple(true)
+            .number_of_values(2))
+        .arg(Arg::with_name("null_as")
+            .long("null-as")
+            .help("how print shows null and missing values, empty by default")
+            .takes_value(true)
+            .value_name("STR"))
+        .arg(Arg::with_name("collate")
+            .long("collate")
+            .help("how strings are ordered by comparisons, maxby() and minby(): by code point, ignoring case, or naturally with numbers by value")
+            .takes_value(true)
+            .possible_values(&["binary", "nocase", "natural"])
+            .default_value("binary"))
+        .arg(Arg::with_name("escape_control")
+            .long("escape-control")
+            .help("escapes control characters in printed strings, like \\n and \\u0007, so each print stays on one line"))
+        .arg(Arg::with_name("join_output")
+            .short("j")
+            .long("join-output")
+            .help("doesn't end printed lines with a newline, the same as setting ORS to \"\""))
+        .arg(Arg::with_name("seq")
+            .long("seq")
+            .help("prints each value as JSON preceded by an ASCII record separator, as in RFC 7464")
+            .conflicts_with("join_output"))
+        .arg(Arg::with_name("interactive")
+            .short("i")
+            .long("interactive")
+            .help("reads JSON values typed at the terminal when there are no input files, until Ctrl-D"))
+        .arg(Arg::with_name("lint")
+            .long("lint")
+            .help("checks the program for likely mistakes without running it"))
+        .arg(Arg::with_name("fmt")
+            .long("fmt")
+            .help("prints the program in canonical format instead of running it"))
+        .arg(Arg::with_name("diagnostics")
+            .long("diagnostics")
+            .help("the format errors and warnings are written to stderr in")
+            .takes_value(true)
+            .possible_values(&["text", "json"])
+            .default_value("text"))
+        .arg(Arg::with_name("explain")
+            .long("explain")
+            .help("reports how many records each rule matched after running"))
+        .arg(Arg::with_name("dry_run")
+            .long("dry-run")
+            .help("reads all the input and tests every pattern, but runs no rule bodies (BEGIN and END included), then reports how many records each rule would have matched and carries on past records whose patterns fail")
+            .conflicts_with("checkpoint"))
+        .arg(Arg::with_name("sample")
+            .long("sample")
+            .help("runs the rules on each record with this probability")
+            .takes_value(true)
+            .value_name("RATE"))
+        .arg(Arg::with_name("sample_n")
+            .long("sample-n")
+            .help("runs the rules on N records chosen at random from the whole input")
+            .takes_value(true)
+            .value_name("N")
+            .conflicts_with("sample"))
+        .arg(Arg::with_name("seed")
+            .long("seed")
+            .help("seeds the random choices made by --sample and --sample-n")
+            .takes_value(true)
+            .value_name("N"))
+        .arg(Arg::with_name("window")
+            .long("window")
+            .help("how many earlier records PREV keeps, 1 by default")
+            .takes_value(true)
+            .value_name("N"))
+        .arg(Arg::with_name("record_timeout")
+            .long("record-timeout")
+            .help("skips any record whose rules take longer than this, e.g. 100ms, with a warning")
+            .takes_value(true)
+            .value_name("DURATION"))
+        .arg(Arg::with_name("max_rate")
+            .long("max-rate")
+            .help("processes at most N records a second. input is read ahead by up to 256 documents and 1MB, after that a faster writer is held up")
+            .takes_value(true)
+            .value_name("N"))
+        .arg(Arg::with_name("max_record_size")
+            .long("max-record-size")
+            .help("the biggest a JSON document can be, e.g. 100MB, with --oversized saying what happens to bigger ones")
+            .takes_value(true)
+            .value_name("SIZE"))
+        .arg(Arg::with_name("oversized")
+            .long("oversized")
+            .help("whether a document bigger than --max-record-size is an error or skipped with a warning")
+            .takes_value(true)
+            .possible_values(&["error", "skip"])
+            .default_value("error"))
+        .arg(Arg::with_name("checkpoint")
+            .long("checkpoint")
+            .help("saves NR, the globals and where the input is up to in a file as it goes, for --resume")
+            .takes_value(true)
+            .value_name("FILE"))
+        .arg(Arg::with_name("checkpoint_every")
+            .long("checkpoint-every")
+            .help("how many documents are read between checkpoints")
+            .takes_value(true)
+            .value_name("N")
+            .default_value("1000"))
+        .arg(Arg::with_name("resume")
+            .long("resume")
+            .help("carries on from the --checkpoint file, if there is one, without running BEGIN again")
+            .requires("checkpoint"))
+        .arg(Arg::with_name("group_by")
+            .long("group-by")
+            .help("groups records by an expression, printing a line per group after the input")
+            .takes_value(true)
+            .value_name("EXPR"))
+        .arg(Arg::with_name("agg")
+            .long("agg")
+            .help("the aggregates printed for each group, e.g. 'sum($.sales), count()'")
+            .takes_value(true)
+            .value_name("LIST"))
+        .arg(Arg::with_name("schema")
+            .long("schema")
+            .help("validates each record against a JSON schema, reporting invalid ones if there's no program")
+            .takes_value(true)
+            .value_name("FILE"))
+        .arg(Arg::with_name("to")
+            .long("to")
+            .help("how printed output is written, table collects it into aligned columns")
+            .takes_value(true)
+            .possible_values(&["text", "table"])
+            .default_value("text"))
+        .arg(Arg::with_name("number_format")
+            .long("number-format")
+            .help("how printed numbers are written: auto, fixed:N for N decimals, or sci[:N] for scientific notation")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .default_value("auto"))
+        .arg(Arg::with_name("test")
+            .long("test")
+            .help("runs the cases in a jqawk test spec file")
+            .takes_value(true)
+            .value_name("SPEC")
+            .conflicts_with_all(&["program_file", "snippet", "PROGRAM"]))
         .arg(Arg::with_name("PROGRAM")
-            .help("the jqawk program to run")
-            .conflicts_with("program_file"))
+            .help("the jqawk program to run"))
         .arg(Arg::with_name("INPUT")
-            .help("the input file"))
+            .help("the input files, read in order, with - for stdin")
+            .multiple(true))
+        .arg(Arg::with_name("ARGS")
+            .help("strings for the program in ARGV, given after --")
+            .multiple(true)
+            .last(true))
+        .arg(Arg::with_name("jsonargs")
+            .long("jsonargs")
+            .help("parses the arguments after -- as JSON rather than taking them as strings"))
+        .subcommand(SubCommand::with_name("diff")
+            .about("compares two JSON documents under the root selector, exiting with 1 if they differ, like diff")
+            .arg(Arg::with_name("A")
+                .help("the first document")
+                .required(true))
+            .arg(Arg::with_name("B")
+                .help("the second document")
+                .required(true))
+            .arg(Arg::with_name("PROGRAM")
+                .help("a program run with $A and $B bound, instead of printing the differences")))
+        .subcommand(SubCommand::with_name("eval")
+            .about("evaluates one expression against the whole document and prints the result")
+            .arg(Arg::with_name("EXPRESSION")
+                .help("the expression, with $ as the root")
+                .required(true))
+            .arg(Arg::with_name("INPUT")
+                .help("the document, stdin if it's not given")))
         .get_matches();
 
+    if let Some(path) = matches.value_of("test") {
+        let passed = spec::run_file(path);
+        process::exit(if passed { 0 } else { 1 });
+    }
+
     let selector = matches.value_of("root").unwrap();
-    let reader = io::BufReader::new(get_input(&matches));
-    
-    if matches.is_present("program_file") {
-        run_program_file(matches.value_of("program_file").unwrap(), reader, selector);
+
+    let format = match matches.value_of("diagnostics") {
+        Some("json") => diagnostics::Format::Json,
+        _ => diagnostics::Format::Text,
+    };
+    // line numbers are only in a file's terms when it's the whole program
+    let single_file = matches.occurrences_of("program_file") == 1 && !matches.is_present("snippet");
+    let diagnostics = Diagnostics {
+        format,
+        file: matches.value_of("program_file").filter(|_| single_file).map(String::from),
+    };
+    diagnostics.install_panic_hook();
+
+    let max_depth = match matches.value_of("max_depth") {
+        Some(n) => n.parse().expect("--max-depth must be a number"),
+        None => input::DEFAULT_MAX_DEPTH,
+    };
+    if max_depth > input::MAX_DEPTH_LIMIT {
+        eprintln!("--max-depth can be at most {}, as nested values are handled recursively", input::MAX_DEPTH_LIMIT);
+        process::exit(1);
+    }
+    let dup_keys = input::DupKeys::from_name(matches.value_of("dup_keys").unwrap()).unwrap();
+
+    if let Some(diff_matches) = matches.subcommand_matches("diff") {
+        diff(&matches, diff_matches, max_depth, dup_keys, &diagnostics);
+        return;
+    }
+    if let Some(eval_matches) = matches.subcommand_matches("eval") {
+        eval(eval_matches, max_depth, dup_keys, &diagnostics);
+        return;
+    }
+
+    let (program, rules) = if matches.is_present("group_by") || matches.is_present("agg") {
+        let agg = matches.value_of("agg").unwrap_or("count()");
+        let rules = aggregate::rules(matches.value_of("group_by"), agg)
+            .unwrap_or_else(|errors| report_compile_errors(&Diagnostics { format, file: None }, errors));
+        (String::new(), rules)
+    } else if matches.is_present("schema") && no_program(&matches) && !has_program_sources(&matches) {
+        (String::new(), Vec::new())
     } else {
-        let lexer = Lexer::new(matches.value_of("PROGRAM").unwrap());
-        let mut compiler = Compiler::new(lexer);
-        let rules = compiler.compile_rules();
+        let program = match has_program_sources(&matches) {
+            true => program_sources(&matches),
+            false => String::from(matches.value_of("PROGRAM").unwrap()),
+        };
+        let rules = compile_program(program.as_str(), matches.value_of("compile_cache"), &diagnostics);
+        (program, rules)
+    };
+
+    if matches.is_present("fmt") {
+        print!("{}", formatter::format(program.as_str()));
+        return;
+    }
 
-        let s_lexer = Lexer::new(selector);
-        let mut s_compiler = Compiler::new(s_lexer);
-        let selector_program = s_compiler.compile_expression();
+    if matches.is_present("lint") {
+        let mut predefined: Vec<&str> = matches.values_of("assign").into_iter().flatten()
+            .filter_map(|a| a.split_once('=').map(|(name, _)| name))
+            .collect();
+        predefined.extend(matches.values_of("argfile").into_iter().flatten().step_by(2));
+        let warnings = lint::lint(&rules, &predefined);
+        for warning in warnings.iter() {
+            diagnostics.warning(warning);
+        }
+        process::exit(if warnings.is_empty() { 0 } else { 1 });
+    }
+
+    let number_format = matches.value_of("number_format").unwrap();
+    let number_format = printf::NumberFormat::from_name(number_format).unwrap_or_else(|| {
+        eprintln!("invalid --number-format '{}', expected auto, fixed:N or sci[:N]", number_format);
+        process::exit(1);
+    });
+
+    let selector_program = compile_selector(selector, format);
+    let mut roots_for = Vec::new();
+    for root_for in matches.values_of("root_for").into_iter().flatten() {
+        match root_for.split_once('=') {
+            Some((file, selector)) => roots_for.push((file, compile_selector(selector, format))),
+            None => {
+                eprintln!("invalid --root-for '{}', expected FILE=SELECTOR", root_for);
+                process::exit(1);
+            },
+        }
+    }
 
-        let mut vm = Vm::new(false);
-        vm.run(reader, selector_program, rules);
+    let mut paths: Vec<Option<&str>> = input_paths(&matches).into_iter().map(Some).collect();
+    if paths.is_empty() {
+        paths.push(None);
+    }
+    let oversized = input::Oversized::from_name(matches.value_of("oversized").unwrap()).unwrap();
+    let max_record_size = matches.value_of("max_record_size").map(|size| match vm::parse_size(size) {
+        Some(bytes) if bytes >= 1.0 => (bytes as usize, oversized),
+        _ => {
+            eprintln!("invalid --max-record-size '{}', expected a size like 100MB", size);
+            process::exit(1);
+        },
+    });
+    let checkpoint = matches.value_of("checkpoint");
+    let resumed = checkpoint.filter(|_| matches.is_present("resume")).and_then(checkpoint::load);
+    // the files before the one the checkpoint is in have already been read
+    let mut resume_at = None;
+    if let Some(state) = &resumed {
+        match paths.iter().position(|path| path.unwrap_or("") == state.filename) {
+            Some(i) => paths.drain(..i),
+            None => {
+                eprintln!("the checkpoint is part way through '{}', which isn't one of the inputs", state.filename);
+                process::exit(1);
+            },
+        };
+        resume_at = Some((state.offset, state.line));
+    }
+    let format = match matches.value_of("from") {
+        _ if matches.is_present("raw_input") => Some(input::Format::Raw),
+        Some(name) => input::Format::from_name(name),
+        None => None,
+    };
+    let separator = String::from(matches.value_of("record_sep").unwrap_or("\n"));
+    let interactive = matches.is_present("interactive");
+    let checkpointing = checkpoint.is_some();
+    let paths: Vec<Option<String>> = paths.into_iter().map(|path| path.map(String::from)).collect();
+    // each file is only opened once the ones before it have been read. the
+    // documents are read and parsed ahead of the program on other threads
+    let docs = pipeline::documents(move || paths.into_iter().flat_map(move |path| {
+        let path = path.as_deref();
+        // only the first file carries on part way through
+        let (offset, line) = resume_at.take().unwrap_or((0, 1));
+        let input = pipeline::read_ahead(input::counted(get_input(path, interactive, offset as u64)));
+        let mut reader = io::BufReader::new(input);
+        let format = format.unwrap_or_else(|| {
+            let head = io::BufRead::fill_buf(&mut reader).unwrap_or_else(|err| panic!("error reading input: {}", err));
+            input::Format::detect(path, head)
+        });
+        // only json is read a document at a time, so it's the only input a
+        // checkpoint can be part way through
+        if checkpointing && format != input::Format::Json {
+            panic!("--checkpoint only works with JSON input");
+        }
+        // like awk, FILENAME is empty when reading stdin without naming it
+        let filename = path.unwrap_or("");
+        let docs: Box<dyn Iterator<Item = input::Document>> = match format {
+            input::Format::Json => Box::new(input::read_json_at(reader, filename, max_depth, dup_keys, max_record_size, (offset, line))),
+            input::Format::Csv => Box::new(std::iter::once(input::read_csv(reader, filename))),
+            input::Format::Yaml => Box::new(input::read_yaml(reader, filename).into_iter()),
+            input::Format::Raw => Box::new(std::iter::once(input::read_raw(reader, filename, &separator))),
+        };
+        docs
+    }));
+
+    let mut vm = Vm::new(false);
+    // before -v, so what's given on the command line wins
+    if let Some(state) = resumed {
+        vm.resume(state);
+    }
+    if let Some(path) = checkpoint {
+        let every = matches.value_of("checkpoint_every").unwrap().parse()
+            .expect("--checkpoint-every must be a number");
+        vm.checkpoint(path, every);
+    }
+    for assignment in matches.values_of("assign").into_iter().flatten() {
+        match assignment.split_once('=') {
+            Some((name, value)) => vm.assign(name, value),
+            None => {
+                eprintln!("invalid -v assignment '{}', expected NAME=VALUE", assignment);
+                process::exit(1);
+            },
+        }
     }
-}
\ No newline at end of file
+
+    for (file, selector) in roots_for.into_iter() {
+        vm.root_for(file, selector);
+    }
+
+    if let Some(null) = matches.value_of("null_as") {
+        vm.assign("NULLSTR", null);
+    }
+
+    let args: Vec<serde_json::Value> = matches.values_of("ARGS").into_iter().flatten().map(|arg| {
+        if !matches.is_present("jsonargs") {
+            return serde_json::Value::String(String::from(arg));
+        }
+        serde_json::from_str(arg).unwrap_or_else(|err| panic!("error parsing argument {}: {}", arg, err))
+    }).collect();
+    vm.assign("ARGC", &args.len().to_string());
+    vm.assign_json("ARGV", serde_json::Value::Array(args));
+
+    let argfiles: Vec<&str> = matches.values_of("argfile").into_iter().flatten().collect();
+    for pair in argfiles.chunks(2) {
+        let file = File::open(pair[1])
+            .unwrap_or_else(|err| panic!("error opening {}: {}", pair[1], err));
+        let value = serde_json::from_reader(io::BufReader::new(file))
+            .unwrap_or_else(|err| panic!("error parsing {}: {}", pair[1], err));
+        vm.assign_json(pair[0], value);
+    }
+
+    let seed = matches.value_of("seed").map(|n| n.parse().expect("--seed must be a number"));
+    if let Some(rate) = matches.value_of("sample") {
+        let rate: f64 = rate.parse().expect("--sample must be a number");
+        if !(0.0..=1.0).contains(&rate) {
+            eprintln!("--sample must be between 0 and 1");
+            process::exit(1);
+        }
+        vm.sample(sample::Sample::Rate(rate), seed);
+    }
+    if let Some(n) = matches.value_of("sample_n") {
+        let n = n.parse().expect("--sample-n must be a number");
+        vm.sample(sample::Sample::Count(n), seed);
+    }
+    if let Some(timeout) = matches.value_of("record_timeout") {
+        match vm::parse_with_unit(timeout) {
+            Some(seconds) if seconds > 0.0 => vm.record_timeout(Duration::from_secs_f64(seconds)),
+            _ => {
+                eprintln!("invalid --record-timeout '{}', expected a duration like 100ms", timeout);
+                process::exit(1);
+            },
+        }
+    }
+    if let Some(rate) = matches.value_of("max_rate") {
+        match rate.parse::<f64>() {
+            Ok(rate) if rate > 0.0 && rate.is_finite() => vm.max_rate(rate),
+            _ => {
+                eprintln!("invalid --max-rate '{}', expected a number of records a second", rate);
+                process::exit(1);
+            },
+        }
+    }
+    if let Some(n) = matches.value_of("window") {
+        vm.window(n.parse().expect("--window must be a number"));
+    }
+    if matches.is_present("join_output") {
+        vm.assign("ORS", "");
+    }
+    if matches.is_present("seq") {
+        vm.json_seq();
+    }
+    if matches.is_present("escape_control") {
+        vm.escape_control();
+    }
+    if matches.value_of("to") == Some("table") {
+        vm.collect_table();
+    }
+    vm.number_format(number_format);
+    vm.collate(collate::Collation::from_name(matches.value_of("collate").unwrap()).unwrap());
+    let reporting = rules.is_empty();
+    if let Some(path) = matches.value_of("schema") {
+        vm.validate(schema::Schema::load(path), reporting);
+    }
+    if matches.is_present("dry_run") {
+        vm.dry_run();
+    }
+    vm.run(docs, selector_program, rules.clone());
+    if reporting && vm.invalid_count() > 0 {
+        process::exit(1);
+    }
+
+    if matches.is_present("dry_run") {
+        explain(&rules, vm.rule_hits());
+        match vm.failed_records() {
+            0 => return,
+            1 => eprintln!("1 record hit an error"),
+            n => eprintln!("{} records hit an error", n),
+        }
+        process::exit(1);
+    }
+
+    if matches.is_present("explain") {
+        explain(&rules, vm.rule_hits());
+    }
+    if let Some(code) = vm.exit_code() {
+        process::exit(code);
+    }
+}
@@ -0,0 +1,53 @@
+use std::io::{self, Write};
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+// what's been printed to stdout but not written yet. like awk, output to a
+// terminal goes out a line at a time so an interactive pipeline sees it
+// straight away, while anything else is written in blocks, which is much
+// faster for big outputs. it's shared rather than kept by the vm so the
+// panic hook can flush it before reporting an error
+static STDOUT: Mutex<Vec<u8>> = Mutex::new(Vec::new());
+static LINE_BUFFERED: OnceLock<bool> = OnceLock::new();
+
+const BLOCK_SIZE: usize = 64 * 1024;
+
+fn line_buffered() -> bool {
+  *LINE_BUFFERED.get_or_init(|| atty::is(atty::Stream::Stdout))
+}
+
+// a panic while printing doesn't leave anything half done, so a poisoned
+// lock is still fine to use
+fn buffer() -> MutexGuard<'static, Vec<u8>> {
+  STDOUT.lock().unwrap_or_else(|err| err.into_inner())
+}
+
+fn write_out(buf: &mut Vec<u8>) -> io::Result<()> {
+  let mut stdout = io::stdout().lock();
+  let result = stdout.write_all(buf).and_then(|_| stdout.flush());
+  buf.clear();
+  return result;
+}
+
+pub fn print(text: &str) {
+  let mut buf = buffer();
+  buf.extend_from_slice(text.as_bytes());
+  if buf.len() < BLOCK_SIZE && !(line_buffered() && text.contains('\n')) {
+    return;
+  }
+  let result = write_out(&mut buf);
+  // the lock's let go first so the panic hook can still flush
+  drop(buf);
+  result.unwrap_or_else(|err| panic!("error writing output: {}", err));
+}
+
+pub fn flush() -> io::Result<()> {
+  return write_out(&mut buffer());
+}
+
+// for the panic hook, which can't wait for a lock another thread holds, or
+// report a second error
+pub fn try_flush() {
+  if let Ok(mut buf) = STDOUT.try_lock() {
+    let _ = write_out(&mut buf);
+  }
+}
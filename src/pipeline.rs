@@ -0,0 +1,117 @@
+use std::io::{self, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use crate::input::Document;
+
+// how far each stage can get ahead of the next. enough to keep them all
+// busy, while a slow program still holds up the input rather than it
+// piling up in memory
+const CHUNKS_AHEAD: usize = 16;
+const CHUNK_SIZE: usize = 64 * 1024;
+const DOCUMENTS_AHEAD: usize = 256;
+
+// with a single cpu the threads only get in each other's way
+fn threaded() -> bool {
+  thread::available_parallelism().is_ok_and(|n| n.get() > 1)
+}
+
+// as big as the main thread's, since documents are parsed recursively
+const STACK_SIZE: usize = 8 * 1024 * 1024;
+
+// named so a panic on one says where it came from
+fn spawn<F: FnOnce() + Send + 'static>(name: &str, f: F) {
+  thread::Builder::new()
+    .name(String::from(name))
+    .stack_size(STACK_SIZE)
+    .spawn(f)
+    .unwrap_or_else(|err| panic!("error starting the {} thread: {}", name, err));
+}
+
+// reads on a thread of its own, so decompressing the input overlaps with
+// parsing it
+pub fn read_ahead(inner: Box<dyn Read + Send>) -> Box<dyn Read + Send> {
+  if !threaded() {
+    return inner;
+  }
+  return Box::new(ReadAhead::new(inner));
+}
+
+struct ReadAhead {
+  chunks: Receiver<io::Result<Vec<u8>>>,
+  chunk: Vec<u8>,
+  pos: usize,
+}
+
+impl ReadAhead {
+  fn new(mut inner: Box<dyn Read + Send>) -> ReadAhead {
+    let (tx, rx) = mpsc::sync_channel(CHUNKS_AHEAD);
+    spawn("reader", move || {
+      loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let result = match inner.read(&mut chunk) {
+          Ok(0) => return,
+          Ok(n) => {
+            chunk.truncate(n);
+            Ok(chunk)
+          },
+          Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+          Err(err) => Err(err),
+        };
+        let failed = result.is_err();
+        // the reader's gone when its input wasn't needed after all
+        if tx.send(result).is_err() || failed {
+          return;
+        }
+      }
+    });
+    ReadAhead { chunks: rx, chunk: Vec::new(), pos: 0 }
+  }
+}
+
+impl Read for ReadAhead {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if self.pos == self.chunk.len() {
+      match self.chunks.recv() {
+        Ok(chunk) => {
+          self.chunk = chunk?;
+          self.pos = 0;
+        },
+        Err(_) => return Ok(0),
+      }
+    }
+    let n = buf.len().min(self.chunk.len() - self.pos);
+    buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+    self.pos += n;
+    Ok(n)
+  }
+}
+
+// parses documents on a thread of their own, ahead of the program running
+// on them. the input's read on another thread again with read_ahead. the
+// thread isn't waited for, as it can be stuck reading input that's no
+// longer needed once the program exits
+pub fn documents<'a, F, I>(parse: F) -> Box<dyn Iterator<Item = Document> + 'a>
+  where F: FnOnce() -> I + Send + 'static, I: Iterator<Item = Document> + 'a {
+  if !threaded() {
+    return Box::new(parse());
+  }
+  // documents are sent one at a time rather than batched up, so input
+  // that trickles in, like a log being followed, isn't held back
+  let (tx, rx) = mpsc::sync_channel(DOCUMENTS_AHEAD);
+  spawn("parser", move || {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+      for doc in parse() {
+        if tx.send(Ok(doc)).is_err() {
+          return;
+        }
+      }
+    }));
+    // the error's already been reported by the panic hook, it only needs
+    // to stop the program in the same place it would have without threads
+    if let Err(payload) = result {
+      let _ = tx.send(Err(payload));
+    }
+  });
+  Box::new(rx.into_iter().map(|doc| doc.unwrap_or_else(|payload| panic::resume_unwind(payload))))
+}
@@ -0,0 +1,110 @@
+use std::collections::HashSet;
+use std::env;
+use crate::compiler::CompileError;
+
+// one @if section, and whether its lines are being kept
+struct Section {
+  line: usize,
+  // whether the lines outside the section are kept
+  outer: bool,
+  kept: bool,
+  seen_else: bool,
+}
+
+// resolves the directives that pick which parts of a program are compiled,
+// each on a line of its own:
+//
+//   @define NAME        sets a flag for @if
+//   @if COND            keeps the lines up to @else or @endif if COND holds
+//   @else
+//   @endif
+//
+// where COND is a flag, ENVIRON["NAME"] for an environment variable that's
+// set and not empty, or either with a ! in front. the lines that are left
+// out, and the directives themselves, become blank lines so errors still
+// point at the right line
+pub fn preprocess(program: &str) -> Result<String, Vec<CompileError>> {
+  let mut defined = HashSet::new();
+  let mut sections: Vec<Section> = Vec::new();
+  let mut errors = Vec::new();
+  let mut out = String::with_capacity(program.len());
+
+  for (i, text) in program.split('\n').enumerate() {
+    if i > 0 {
+      out.push('\n');
+    }
+    let line = i + 1;
+    let keeping = sections.last().is_none_or(|s| s.kept);
+    let directive = match text.trim_start().strip_prefix('@') {
+      Some(directive) => directive.trim_end(),
+      None => {
+        if keeping {
+          out.push_str(text);
+        }
+        continue;
+      },
+    };
+    let col = text.len() - text.trim_start().len() + 1;
+    let mut error = |message: String| errors.push(CompileError { line, col, message });
+
+    let (name, arg) = directive.split_once(char::is_whitespace).unwrap_or((directive, ""));
+    let arg = arg.trim();
+    match name {
+      "define" if is_name(arg) => {
+        if keeping {
+          defined.insert(String::from(arg));
+        }
+      },
+      "define" => error(format!("expected a name after @define, found '{}'", arg)),
+      "if" => match condition(arg, &defined) {
+        Some(holds) => sections.push(Section { line, outer: keeping, kept: keeping && holds, seen_else: false }),
+        None => {
+          error(format!("expected NAME or ENVIRON[\"NAME\"] after @if, found '{}'", arg));
+          // the section still needs an @endif
+          sections.push(Section { line, outer: keeping, kept: false, seen_else: false });
+        },
+      },
+      "else" | "endif" if !arg.is_empty() => error(format!("unexpected '{}' after @{}", arg, name)),
+      "else" => match sections.last_mut() {
+        Some(section) if !section.seen_else => {
+          section.kept = section.outer && !section.kept;
+          section.seen_else = true;
+        },
+        Some(_) => error(String::from("@else after @else")),
+        None => error(String::from("@else without @if")),
+      },
+      "endif" => {
+        if sections.pop().is_none() {
+          error(String::from("@endif without @if"));
+        }
+      },
+      _ => error(format!("unknown directive @{}", name)),
+    }
+  }
+
+  for section in sections.iter() {
+    errors.push(CompileError { line: section.line, col: 1, message: String::from("@if without @endif") });
+  }
+  if !errors.is_empty() {
+    return Err(errors);
+  }
+  return Ok(out);
+}
+
+fn is_name(s: &str) -> bool {
+  s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') &&
+    s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn condition(cond: &str, defined: &HashSet<String>) -> Option<bool> {
+  if let Some(cond) = cond.strip_prefix('!') {
+    return condition(cond.trim_start(), defined).map(|holds| !holds);
+  }
+  if let Some(var) = cond.strip_prefix("ENVIRON[\"").and_then(|s| s.strip_suffix("\"]")) {
+    return Some(env::var(var).is_ok_and(|v| !v.is_empty()));
+  }
+  if is_name(cond) {
+    return Some(defined.contains(cond));
+  }
+  return None;
+}
@@ -0,0 +1,271 @@
+use crate::vm::Value;
+
+// one %... conversion, e.g. %-8.2f
+#[derive(Default)]
+struct Spec {
+  left: bool,
+  zero: bool,
+  plus: bool,
+  space: bool,
+  width: usize,
+  precision: Option<usize>,
+}
+
+impl Spec {
+  fn pad(&self, s: String, numeric: bool) -> String {
+    let len = s.chars().count();
+    if len >= self.width {
+      return s;
+    }
+    let fill = self.width - len;
+    if self.left {
+      return s + &" ".repeat(fill);
+    }
+    if self.zero && numeric {
+      // zeros go after the sign
+      let (sign, digits) = match s.chars().next() {
+        Some(c @ ('-' | '+' | ' ')) => (c.to_string(), &s[1..]),
+        _ => (String::new(), s.as_str()),
+      };
+      return sign + &"0".repeat(fill) + digits;
+    }
+    return " ".repeat(fill) + &s;
+  }
+
+  fn sign(&self, n: f64, s: String) -> String {
+    if n >= 0.0 && self.plus {
+      return format!("+{}", s);
+    }
+    if n >= 0.0 && self.space {
+      return format!(" {}", s);
+    }
+    return s;
+  }
+}
+
+// c's %e, with at least two digits in the exponent: 1.500000e+03. without
+// a precision the mantissa has as few digits as it takes
+fn exponent(n: f64, precision: Option<usize>, upper: bool) -> String {
+  let s = match precision {
+    Some(precision) => format!("{:.*e}", precision, n),
+    None => format!("{:e}", n),
+  };
+  let (mantissa, exp) = s.split_once('e').unwrap();
+  let exp: i32 = exp.parse().unwrap();
+  let sign = if exp < 0 { '-' } else { '+' };
+  let s = format!("{}e{}{:02}", mantissa, sign, exp.abs());
+  if upper { s.to_uppercase() } else { s }
+}
+
+// c's %g, whichever of %e and %f is shorter, without trailing zeros
+fn general(n: f64, precision: usize, upper: bool) -> String {
+  let precision = precision.max(1);
+  if n == 0.0 {
+    return String::from("0");
+  }
+  let exp = n.abs().log10().floor() as i32;
+  if exp < -4 || exp >= precision as i32 {
+    let s = exponent(n, Some(precision - 1), upper);
+    let (mantissa, exp) = s.split_at(s.find(['e', 'E']).unwrap());
+    return format!("{}{}", trim_zeros(mantissa), exp);
+  }
+  return trim_zeros(&format!("{:.*}", (precision as i32 - 1 - exp).max(0) as usize, n));
+}
+
+fn trim_zeros(s: &str) -> String {
+  if !s.contains('.') {
+    return String::from(s);
+  }
+  String::from(s.trim_end_matches('0').trim_end_matches('.'))
+}
+
+// how print and json output write numbers, set with --number-format.
+// printf has its own conversions and isn't affected
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum NumberFormat {
+  // as few digits as it takes, without a fraction for whole numbers
+  #[default]
+  Auto,
+  // fixed:2, always that many decimals
+  Fixed(usize),
+  // sci, or sci:3 for that many decimals: 1.500e+03
+  Sci(Option<usize>),
+}
+
+impl NumberFormat {
+  pub fn from_name(name: &str) -> Option<NumberFormat> {
+    match name {
+      "auto" => Some(NumberFormat::Auto),
+      "sci" => Some(NumberFormat::Sci(None)),
+      _ => {
+        let (kind, decimals) = name.split_once(':')?;
+        let decimals = decimals.parse().ok()?;
+        match kind {
+          "fixed" => Some(NumberFormat::Fixed(decimals)),
+          "sci" => Some(NumberFormat::Sci(Some(decimals))),
+          _ => None,
+        }
+      },
+    }
+  }
+
+  pub fn number(self, n: f64) -> String {
+    match self {
+      NumberFormat::Auto => format!("{}", n),
+      NumberFormat::Fixed(decimals) => format!("{:.*}", decimals, n),
+      NumberFormat::Sci(decimals) => exponent(n, decimals, false),
+    }
+  }
+
+  // compact json with every number written in this format
+  pub fn json(self, v: &serde_json::Value) -> String {
+    match v {
+      serde_json::Value::Number(n) if self != NumberFormat::Auto => self.number(n.as_f64().unwrap_or(0.0)),
+      serde_json::Value::Array(a) if self != NumberFormat::Auto => {
+        let items: Vec<String> = a.iter().map(|v| self.json(v)).collect();
+        format!("[{}]", items.join(","))
+      },
+      serde_json::Value::Object(o) if self != NumberFormat::Auto => {
+        let members: Vec<String> = o.iter()
+          .map(|(k, v)| format!("{}:{}", serde_json::Value::String(k.clone()), self.json(v)))
+          .collect();
+        format!("{{{}}}", members.join(","))
+      },
+      _ => v.to_string(),
+    }
+  }
+
+  // a value as print shows it
+  pub fn text(self, v: &Value) -> String {
+    match v {
+      Value::Num(n) => self.number(*n),
+      Value::Array(v) | Value::Object(v) => self.json(v),
+      _ => format!("{}", v),
+    }
+  }
+}
+
+// control characters written as escapes, \n, \t and \r or else like
+// \u0007, so that a string prints on one line
+pub fn escape_control(s: &str) -> String {
+  if !s.contains(char::is_control) {
+    return String::from(s);
+  }
+  let mut out = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '\n' => out.push_str("\\n"),
+      '\t' => out.push_str("\\t"),
+      '\r' => out.push_str("\\r"),
+      c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+      c => out.push(c),
+    }
+  }
+  return out;
+}
+
+fn convert(conv: char, spec: &Spec, arg: Option<&Value>) -> String {
+  let n = arg.map_or(0.0, |a| a.as_f64());
+  match conv {
+    'd' | 'i' => {
+      let s = spec.sign(n, format!("{}", n.trunc() as i64));
+      spec.pad(s, true)
+    },
+    'o' => spec.pad(format!("{:o}", n.trunc() as i64), true),
+    'x' => spec.pad(format!("{:x}", n.trunc() as i64), true),
+    'X' => spec.pad(format!("{:X}", n.trunc() as i64), true),
+    'f' | 'F' => {
+      let s = spec.sign(n, format!("{:.*}", spec.precision.unwrap_or(6), n));
+      spec.pad(s, true)
+    },
+    'e' | 'E' => {
+      let s = spec.sign(n, exponent(n, Some(spec.precision.unwrap_or(6)), conv == 'E'));
+      spec.pad(s, true)
+    },
+    'g' | 'G' => {
+      let s = spec.sign(n, general(n, spec.precision.unwrap_or(6), conv == 'G'));
+      spec.pad(s, true)
+    },
+    'c' => {
+      let c = match arg {
+        Some(Value::Num(n)) => char::from_u32(*n as u32).map(String::from).unwrap_or_default(),
+        Some(v) => format!("{}", v).chars().next().map(String::from).unwrap_or_default(),
+        None => String::new(),
+      };
+      spec.pad(c, false)
+    },
+    // compact and pretty json
+    'j' | 'J' => {
+      let json = arg.map_or(serde_json::Value::Null, |a| a.to_json());
+      let s = if conv == 'j' {
+        serde_json::to_string(&json).unwrap()
+      } else {
+        serde_json::to_string_pretty(&json).unwrap()
+      };
+      spec.pad(s, false)
+    },
+    // %s
+    _ => {
+      let mut s = arg.map_or(String::new(), |a| format!("{}", a));
+      if let Some(precision) = spec.precision {
+        s = s.chars().take(precision).collect();
+      }
+      spec.pad(s, false)
+    },
+  }
+}
+
+// formats like c's printf: %d %i %o %x %X %f %e %g %c %s and %%, with the
+// usual flags, width and precision. %j and %J give values as compact and
+// pretty printed json. missing arguments are treated as empty
+pub fn sprintf(fmt: &str, args: &[Value]) -> String {
+  let mut out = String::new();
+  let mut args = args.iter();
+  let mut chars = fmt.chars().peekable();
+
+  while let Some(c) = chars.next() {
+    if c != '%' {
+      out.push(c);
+      continue;
+    }
+
+    let mut spec = Spec::default();
+    while let Some(flag) = chars.peek() {
+      match flag {
+        '-' => spec.left = true,
+        '0' => spec.zero = true,
+        '+' => spec.plus = true,
+        ' ' => spec.space = true,
+        _ => break,
+      }
+      chars.next();
+    }
+    while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+      spec.width = spec.width * 10 + d as usize;
+      chars.next();
+    }
+    if chars.peek() == Some(&'.') {
+      chars.next();
+      let mut precision = 0;
+      while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+        precision = precision * 10 + d as usize;
+        chars.next();
+      }
+      spec.precision = Some(precision);
+    }
+
+    match chars.next() {
+      Some('%') => out.push('%'),
+      Some(conv @ ('d' | 'i' | 'o' | 'x' | 'X' | 'f' | 'F' | 'e' | 'E' | 'g' | 'G' | 'c' | 's' | 'j' | 'J')) => {
+        out.push_str(&convert(conv, &spec, args.next()));
+      },
+      // anything else is left as it was
+      Some(other) => {
+        out.push('%');
+        out.push(other);
+      },
+      None => out.push('%'),
+    }
+  }
+  return out;
+}
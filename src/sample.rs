@@ -0,0 +1,88 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy)]
+pub enum Sample {
+  // keep each record with this probability
+  Rate(f64),
+  // keep this many records chosen uniformly from the whole input
+  Count(usize),
+}
+
+// xorshift64*, plenty for picking records and no extra dependency
+struct Rng(u64);
+
+impl Rng {
+  fn new(seed: Option<u64>) -> Rng {
+    let seed = seed.unwrap_or_else(|| {
+      SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_nanos() as u64)
+    });
+    // splitmix64 spreads out nearby seeds. the state must never be zero
+    let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    Rng((z ^ (z >> 31)) | 1)
+  }
+
+  fn next_u64(&mut self) -> u64 {
+    self.0 ^= self.0 >> 12;
+    self.0 ^= self.0 << 25;
+    self.0 ^= self.0 >> 27;
+    self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+  }
+
+  // uniform in [0, 1)
+  fn next_f64(&mut self) -> f64 {
+    (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+  }
+
+  // uniform in [0, n)
+  fn below(&mut self, n: usize) -> usize {
+    (self.next_f64() * n as f64) as usize
+  }
+}
+
+// picks which records the rules see. rate sampling decides as each record
+// goes past, while reservoir sampling can't know until the input ends, so it
+// holds on to its records and gives them back, in input order, from finish
+pub struct Sampler<T> {
+  sample: Sample,
+  rng: Rng,
+  seen: usize,
+  reservoir: Vec<(usize, T)>,
+}
+
+impl<T> Sampler<T> {
+  pub fn new(sample: Sample, seed: Option<u64>) -> Sampler<T> {
+    Sampler { sample, rng: Rng::new(seed), seen: 0, reservoir: Vec::new() }
+  }
+
+  // gives the record back if it should be evaluated now
+  pub fn offer(&mut self, item: T) -> Option<T> {
+    let seen = self.seen;
+    self.seen += 1;
+    match self.sample {
+      Sample::Rate(rate) => {
+        if self.rng.next_f64() < rate {
+          return Some(item);
+        }
+      },
+      Sample::Count(n) => {
+        if seen < n {
+          self.reservoir.push((seen, item));
+        } else {
+          let i = self.rng.below(seen + 1);
+          if i < n {
+            self.reservoir[i] = (seen, item);
+          }
+        }
+      },
+    }
+    return None;
+  }
+
+  pub fn finish(&mut self) -> Vec<T> {
+    let mut reservoir = std::mem::take(&mut self.reservoir);
+    reservoir.sort_by_key(|(seen, _)| *seen);
+    reservoir.into_iter().map(|(_, item)| item).collect()
+  }
+}
@@ -0,0 +1,170 @@
+use std::fs::File;
+use std::io::BufReader;
+use serde_json::Value;
+use crate::diff::Key;
+
+// validates json against a json schema. the common keywords are supported:
+// type, enum, const, properties, required, additionalProperties, items,
+// minItems, maxItems, minLength, maxLength, minimum, maximum,
+// exclusiveMinimum, exclusiveMaximum, allOf, anyOf and oneOf. anything else
+// in the schema is ignored
+pub struct Schema {
+  root: Value,
+}
+
+fn type_name(v: &Value) -> &'static str {
+  match v {
+    Value::Null => "null",
+    Value::Bool(_) => "boolean",
+    Value::Number(_) => "number",
+    Value::String(_) => "string",
+    Value::Array(_) => "array",
+    Value::Object(_) => "object",
+  }
+}
+
+fn has_type(v: &Value, name: &str) -> bool {
+  match name {
+    "integer" => v.as_f64().is_some_and(|n| n.fract() == 0.0),
+    _ => type_name(v) == name,
+  }
+}
+
+impl Schema {
+  pub fn load(path: &str) -> Schema {
+    let file = File::open(path).unwrap_or_else(|err| panic!("error opening schema {}: {}", path, err));
+    let root = serde_json::from_reader(BufReader::new(file))
+      .unwrap_or_else(|err| panic!("error parsing schema {}: {}", path, err));
+    Schema { root }
+  }
+
+  // every way the value breaks the schema, as `path: problem`
+  pub fn validate(&self, v: &Value) -> Vec<String> {
+    let mut errors = Vec::new();
+    check(&self.root, v, "$", &mut errors);
+    return errors;
+  }
+}
+
+fn check(schema: &Value, v: &Value, path: &str, errors: &mut Vec<String>) {
+  let schema = match schema {
+    Value::Bool(true) => return,
+    Value::Bool(false) => {
+      errors.push(format!("{}: not allowed", path));
+      return;
+    },
+    Value::Object(o) => o,
+    _ => return,
+  };
+
+  if let Some(types) = schema.get("type") {
+    let allowed: Vec<&str> = match types {
+      Value::String(s) => vec![s.as_str()],
+      Value::Array(a) => a.iter().filter_map(|t| t.as_str()).collect(),
+      _ => Vec::new(),
+    };
+    if !allowed.is_empty() && !allowed.iter().any(|t| has_type(v, t)) {
+      errors.push(format!("{}: expected {}, got {}", path, allowed.join(" or "), type_name(v)));
+      return;
+    }
+  }
+
+  if let Some(Value::Array(options)) = schema.get("enum") {
+    if !options.contains(v) {
+      errors.push(format!("{}: {} is not one of {}", path, v, Value::Array(options.clone())));
+    }
+  }
+  if let Some(expected) = schema.get("const") {
+    if expected != v {
+      errors.push(format!("{}: expected {}, got {}", path, expected, v));
+    }
+  }
+
+  for keyword in ["allOf", "anyOf", "oneOf"].iter() {
+    if let Some(Value::Array(subschemas)) = schema.get(*keyword) {
+      let passing = subschemas.iter().filter(|s| {
+        let mut sub = Vec::new();
+        check(s, v, path, &mut sub);
+        sub.is_empty()
+      }).count();
+      let ok = match *keyword {
+        "allOf" => passing == subschemas.len(),
+        "anyOf" => passing > 0,
+        _ => passing == 1,
+      };
+      if !ok {
+        errors.push(format!("{}: does not match {}", path, keyword));
+      }
+    }
+  }
+
+  match v {
+    Value::Object(o) => {
+      if let Some(Value::Array(required)) = schema.get("required") {
+        for name in required.iter().filter_map(|r| r.as_str()) {
+          if !o.contains_key(name) {
+            errors.push(format!("{}: missing required property {}", path, name));
+          }
+        }
+      }
+
+      let properties = schema.get("properties").and_then(|p| p.as_object());
+      for (name, value) in o.iter() {
+        let child = format!("{}{}", path, Key::Name(name.clone()).path());
+        match (properties.and_then(|p| p.get(name)), schema.get("additionalProperties")) {
+          (Some(sub), _) => check(sub, value, &child, errors),
+          (None, Some(Value::Bool(false))) => errors.push(format!("{}: unexpected property", child)),
+          (None, Some(sub)) => check(sub, value, &child, errors),
+          (None, None) => (),
+        }
+      }
+    },
+    Value::Array(a) => {
+      if let Some(items) = schema.get("items") {
+        for (i, item) in a.iter().enumerate() {
+          check(items, item, &format!("{}{}", path, Key::Index(i).path()), errors);
+        }
+      }
+      if let Some(min) = schema.get("minItems").and_then(|n| n.as_u64()) {
+        if (a.len() as u64) < min {
+          errors.push(format!("{}: has {} items, fewer than {}", path, a.len(), min));
+        }
+      }
+      if let Some(max) = schema.get("maxItems").and_then(|n| n.as_u64()) {
+        if a.len() as u64 > max {
+          errors.push(format!("{}: has {} items, more than {}", path, a.len(), max));
+        }
+      }
+    },
+    Value::String(s) => {
+      let len = s.chars().count() as u64;
+      if let Some(min) = schema.get("minLength").and_then(|n| n.as_u64()) {
+        if len < min {
+          errors.push(format!("{}: shorter than {} characters", path, min));
+        }
+      }
+      if let Some(max) = schema.get("maxLength").and_then(|n| n.as_u64()) {
+        if len > max {
+          errors.push(format!("{}: longer than {} characters", path, max));
+        }
+      }
+    },
+    Value::Number(n) => {
+      let n = n.as_f64().unwrap_or(0.0);
+      let bound = |keyword: &str| schema.get(keyword).and_then(|b| b.as_f64());
+      if let Some(min) = bound("minimum").filter(|min| n < *min) {
+        errors.push(format!("{}: {} is less than {}", path, n, min));
+      }
+      if let Some(max) = bound("maximum").filter(|max| n > *max) {
+        errors.push(format!("{}: {} is more than {}", path, n, max));
+      }
+      if let Some(min) = bound("exclusiveMinimum").filter(|min| n <= *min) {
+        errors.push(format!("{}: {} is not more than {}", path, n, min));
+      }
+      if let Some(max) = bound("exclusiveMaximum").filter(|max| n >= *max) {
+        errors.push(format!("{}: {} is not less than {}", path, n, max));
+      }
+    },
+    _ => (),
+  }
+}
@@ -0,0 +1,172 @@
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+// a spec file holds any number of cases, each made of sections:
+//
+//   === name of the case
+//   --- args
+//   -r
+//   $.items
+//   --- program
+//   { print $.name }
+//   --- input
+//   { "items": [{ "name": "a" }] }
+//   --- output
+//   a
+//
+// args are one per line and optional, as are the program (when it's given
+// with -f instead) and input. cases run in the spec file's directory, so
+// -f and input files are found next to it. trailing blank lines in a section
+// are ignored so cases can be spaced out, and a section's text ends with a
+// newline unless its header says no-newline:
+//
+//   --- output no-newline
+#[derive(Debug, Default)]
+struct Case {
+  name: String,
+  args: Vec<String>,
+  program: String,
+  input: String,
+  output: String,
+}
+
+fn section_text(lines: &[&str], newline: bool) -> String {
+  let mut end = lines.len();
+  while end > 0 && lines[end - 1].trim().is_empty() {
+    end -= 1;
+  }
+  if end == 0 {
+    return String::new();
+  }
+  let text = lines[..end].join("\n");
+  if newline {
+    return text + "\n";
+  }
+  return text;
+}
+
+// stores a finished section in the current case. sections are identified by
+// their header and the line they started on
+fn finish_section(cases: &mut [Case], section: Option<(&str, usize)>, lines: &mut Vec<&str>) -> Result<(), String> {
+  if let Some((header, line)) = section {
+    let (name, newline) = match header.split_once(char::is_whitespace) {
+      Some((name, option)) if option.trim() == "no-newline" => (name, false),
+      Some((_, option)) => return Err(format!("line {}: unknown section option '{}'", line, option.trim())),
+      None => (header, true),
+    };
+    let case = match cases.last_mut() {
+      Some(case) => case,
+      None => return Err(format!("line {}: section '{}' outside of a case", line, name)),
+    };
+    let text = section_text(lines, newline);
+    match name {
+      "args" => case.args = text.lines().map(String::from).collect(),
+      "program" => case.program = text,
+      "input" => case.input = text,
+      "output" => case.output = text,
+      _ => return Err(format!("line {}: unknown section '{}'", line, name)),
+    }
+  }
+  lines.clear();
+  return Ok(());
+}
+
+fn parse(src: &str) -> Result<Vec<Case>, String> {
+  let mut cases = Vec::new();
+  let mut section = None;
+  let mut lines = Vec::new();
+
+  for (i, line) in src.lines().enumerate() {
+    if let Some(name) = line.strip_prefix("===") {
+      finish_section(&mut cases, section.take(), &mut lines)?;
+      cases.push(Case { name: String::from(name.trim()), ..Case::default() });
+    } else if let Some(name) = line.strip_prefix("---") {
+      finish_section(&mut cases, section.take(), &mut lines)?;
+      section = Some((name.trim(), i + 1));
+    } else if section.is_some() {
+      lines.push(line);
+    } else if !line.trim().is_empty() {
+      return Err(format!("line {}: expected a === case or --- section", i + 1));
+    }
+  }
+  finish_section(&mut cases, section.take(), &mut lines)?;
+
+  return Ok(cases);
+}
+
+// runs the case with this same jqawk binary in dir, giving the output or
+// an error
+fn run_case(case: &Case, dir: &Path) -> Result<String, String> {
+  let exe = env::current_exe().map_err(|e| e.to_string())?;
+  let mut command = Command::new(exe);
+  command.current_dir(dir).args(&case.args);
+  if !case.program.is_empty() {
+    command.arg(&case.program);
+  }
+  let mut child = command
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .map_err(|e| e.to_string())?;
+
+  child.stdin.as_mut().unwrap().write_all(case.input.as_bytes())
+    .map_err(|e| e.to_string())?;
+  let output = child.wait_with_output().map_err(|e| e.to_string())?;
+
+  if !output.status.success() {
+    return Err(String::from_utf8_lossy(&output.stderr).to_string());
+  }
+  return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+}
+
+fn indent(text: &str) -> String {
+  text.lines().map(|l| format!("    {}\n", l)).collect()
+}
+
+// runs every case in a spec file and reports on them, returning whether
+// they all passed
+pub fn run_file(path: &str) -> bool {
+  let src = match fs::read_to_string(path) {
+    Ok(src) => src,
+    Err(err) => {
+      eprintln!("error reading {}: {}", path, err);
+      return false;
+    },
+  };
+
+  let cases = match parse(&src) {
+    Ok(cases) => cases,
+    Err(err) => {
+      eprintln!("error in {}: {}", path, err);
+      return false;
+    },
+  };
+
+  let dir = match Path::new(path).parent() {
+    Some(dir) if !dir.as_os_str().is_empty() => dir,
+    _ => Path::new("."),
+  };
+  let mut failed = 0;
+  for case in cases.iter() {
+    match run_case(case, dir) {
+      Ok(output) if output == case.output => println!("PASS {}", case.name),
+      Ok(output) => {
+        failed += 1;
+        println!("FAIL {}", case.name);
+        print!("  expected:\n{}  got:\n{}", indent(&case.output), indent(&output));
+      },
+      Err(err) => {
+        failed += 1;
+        println!("FAIL {}", case.name);
+        print!("  error:\n{}", indent(&err));
+      },
+    }
+  }
+
+  println!("{} passed, {} failed", cases.len() - failed, failed);
+  return failed == 0;
+}
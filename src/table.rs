@@ -0,0 +1,84 @@
+use crate::printf::{escape_control, NumberFormat};
+use crate::vm::Value;
+
+enum Row {
+  Values(Vec<Value>),
+  Object(serde_json::Map<String, serde_json::Value>),
+}
+
+// collects printed rows for --to table. printing an object adds a row with
+// a column per key, which also gives the table its headers. anything else
+// fills the columns in order
+#[derive(Default)]
+pub struct Table {
+  rows: Vec<Row>,
+  // object keys in the order they were first seen
+  headers: Vec<String>,
+}
+
+fn cell(v: &Value, numbers: NumberFormat, escape: bool) -> (String, bool) {
+  let text = numbers.text(v);
+  (if escape { escape_control(&text) } else { text }, matches!(v, Value::Num(_)))
+}
+
+impl Table {
+  pub fn add(&mut self, mut values: Vec<Value>) {
+    if let [Value::Object(serde_json::Value::Object(o))] = values.as_mut_slice() {
+      for key in o.keys() {
+        if !self.headers.contains(key) {
+          self.headers.push(key.clone());
+        }
+      }
+      self.rows.push(Row::Object(std::mem::take(o)));
+      return;
+    }
+    self.rows.push(Row::Values(values));
+  }
+
+  // the rows as aligned text, numbers to the right and everything else to
+  // the left of their columns. with escape, control characters in strings
+  // are escaped so they can't break a row
+  pub fn render(&self, numbers: NumberFormat, escape: bool) -> String {
+    let mut lines: Vec<Vec<(String, bool)>> = Vec::new();
+    if !self.headers.is_empty() {
+      lines.push(self.headers.iter().map(|h| (h.clone(), false)).collect());
+    }
+    for row in self.rows.iter() {
+      lines.push(match row {
+        Row::Values(values) => values.iter().map(|v| cell(v, numbers, escape)).collect(),
+        Row::Object(o) => self.headers.iter()
+          .map(|h| o.get(h).map_or((String::new(), false), |v| cell(&Value::from(v.clone()), numbers, escape)))
+          .collect(),
+      });
+    }
+
+    let columns = lines.iter().map(|l| l.len()).max().unwrap_or(0);
+    let mut widths = vec![0; columns];
+    for line in lines.iter() {
+      for (i, (text, _)) in line.iter().enumerate() {
+        widths[i] = widths[i].max(text.chars().count());
+      }
+    }
+
+    let mut out = String::new();
+    for (n, line) in lines.iter().enumerate() {
+      let cells: Vec<String> = line.iter().enumerate().map(|(i, (text, numeric))| {
+        if *numeric {
+          format!("{:>width$}", text, width = widths[i])
+        } else {
+          format!("{:<width$}", text, width = widths[i])
+        }
+      }).collect();
+      out.push_str(cells.join("  ").trim_end());
+      out.push('\n');
+
+      // a rule under the headers
+      if n == 0 && !self.headers.is_empty() {
+        let rule: Vec<String> = widths.iter().map(|w| "-".repeat(*w)).collect();
+        out.push_str(&rule.join("  "));
+        out.push('\n');
+      }
+    }
+    return out;
+  }
+}
@@ -0,0 +1,62 @@
+use crate::vm::OpCode;
+
+// how many values an opcode takes off the stack, and how many it leaves
+fn stack_effect(op: &OpCode) -> (usize, usize) {
+  match op {
+    OpCode::GetField(_) | OpCode::PushImmediate(_) | OpCode::GetGlobal(_) | OpCode::GetLocal(_) |
+    OpCode::Range(_, _, _) => (0, 1),
+    OpCode::GetMember | OpCode::MapMember | OpCode::Descend | OpCode::MapDescend => (2, 1),
+    OpCode::Wildcard | OpCode::MapWildcard | OpCode::Filter(_) | OpCode::MapFilter(_) | OpCode::Negate | OpCode::Not => (1, 1),
+    OpCode::Slice(start, end) | OpCode::MapSlice(start, end) => (1 + *start as usize + *end as usize, 1),
+    OpCode::SetGlobal(_) | OpCode::SetLocal(_) | OpCode::Pop => (1, 0),
+    OpCode::SetElement(_, depth, _) => (depth + 1, 0),
+    OpCode::Delete(_, depth) => (*depth, 0),
+    OpCode::ForIn(_, _) | OpCode::ForEach(_, _) | OpCode::DestructureObject(_) | OpCode::DestructureArray(_) => (1, 0),
+    OpCode::Call(_, argc) | OpCode::CallLambda(_, argc, _) | OpCode::CallTracked(_, argc, _) => (*argc, 1),
+    OpCode::MakeArray(count) => (*count, 1),
+    OpCode::Equal | OpCode::Match | OpCode::NotMatch | OpCode::And | OpCode::In |
+    OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide | OpCode::NotEqual |
+    OpCode::Greater | OpCode::Less | OpCode::GreaterEqual | OpCode::LessEqual => (2, 1),
+    OpCode::Print(argc) | OpCode::Printf(argc) => (*argc, 0),
+    OpCode::PrintTo(argc) | OpCode::PrintfTo(argc) => (argc + 1, 0),
+    OpCode::Getline(_) => (1, 1),
+    OpCode::Exit(status) => (*status as usize, 0),
+    OpCode::Accumulate(aggregates) => (aggregates.len() + 1, 0),
+    OpCode::ReportGroups(_, _) => (0, 0),
+  }
+}
+
+fn values(n: usize) -> String {
+  if n == 1 { String::from("1 value") } else { format!("{} values", n) }
+}
+
+// checks code leaves the expected number of values on the stack, 1 for an
+// expression and 0 for statements, without ever taking one that isn't
+// there. the code nested in filters, functions and loops is checked too.
+// what names the code for the error
+pub fn verify(code: &[OpCode], expected: usize, what: &str) -> Result<(), String> {
+  let mut depth = 0;
+  for op in code.iter() {
+    match op {
+      OpCode::Filter(predicate) | OpCode::MapFilter(predicate) => verify(predicate, 1, "a filter")?,
+      OpCode::CallLambda(_, _, lambda) => verify(&lambda.body, 1, "a function")?,
+      OpCode::ForIn(_, body) | OpCode::ForEach(_, body) => verify(body, 0, "a for loop body")?,
+      OpCode::Range(_, start, end) => {
+        verify(start, 1, "the start of a range")?;
+        verify(end, 1, "the end of a range")?;
+      },
+      _ => (),
+    }
+
+    let (pops, pushes) = stack_effect(op);
+    if pops > depth {
+      return Err(format!("{} needs a value where there isn't one", what));
+    }
+    depth = depth - pops + pushes;
+  }
+
+  if depth != expected {
+    return Err(format!("{} leaves {} on the stack, expected {}", what, values(depth), expected));
+  }
+  return Ok(());
+}
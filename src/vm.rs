@@ -1,37 +1,210 @@
+use std::cmp::Ordering;
 use std::fmt;
-use std::collections::HashMap;
-use std::io;
-use std::cell::RefCell;
-use serde_json;
-use crate::compiler::{JqaRule, JqaRuleKind};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::cell::{Cell, OnceCell, RefCell};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use serde::{Serialize, Deserialize};
+use regex::{Regex, RegexBuilder};
+use crate::compiler::{JqaRule, JqaRuleKind, SelectorBranch};
+use crate::input::{self, Document};
+use crate::sample::{Sample, Sampler};
+use crate::aggregate::{Aggregate, Groups};
+use crate::checkpoint::{self, Checkpoint};
+use crate::collate::Collation;
+use crate::diff;
+use crate::output;
+use crate::schema::Schema;
+use crate::table::Table;
+use crate::printf::{escape_control, sprintf, NumberFormat};
 
-#[derive(Clone, Debug)]
+mod builtins;
+pub use builtins::{check_arity, parse_size, parse_with_unit, LAMBDA_FUNCTIONS, TRACKING_FUNCTIONS};
+use builtins::{take_path, RECORD_FUNCTIONS};
+
+// the rules being run, and the one being run now as its index, whether
+// it's in the pattern and the index of the opcode, so a runtime error can
+// say which expression it came from. they're kept per thread as the hook
+// that reports errors can't see the vm
+thread_local! {
+  static RULES: RefCell<Vec<JqaRule>> = const { RefCell::new(Vec::new()) };
+  static RUNNING: Cell<Option<(usize, bool, usize)>> = const { Cell::new(None) };
+}
+
+// the line, column and text of the expression being run, if any
+pub fn running_expression() -> Option<(usize, usize, String)> {
+  let (rule, pattern, op) = RUNNING.with(|running| running.get())?;
+  RULES.with(|rules| {
+    let rules = rules.borrow();
+    let (span, text) = rules.get(rule)?.expression_at(pattern, op)?;
+    Some((span.line, span.col, text))
+  })
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum OpCode {
   GetField(String),
   PushImmediate(Value),
   GetMember,
+  MapMember,
+  Descend,
+  MapDescend,
+  Wildcard,
+  MapWildcard,
+  // whether the start and end of the range were given
+  Slice(bool, bool),
+  MapSlice(bool, bool),
+  Filter(Vec<OpCode>),
+  MapFilter(Vec<OpCode>),
   GetGlobal(String),
   SetGlobal(String),
+  // a lambda's parameter, from the innermost call that has it
+  GetLocal(String),
+  SetLocal(String),
+  // sets an element of a global, taking the given number of keys and then
+  // the value. with an operator, the value is combined with the element's
+  // old one, for `a[k] += v`
+  SetElement(String, usize, Option<Box<OpCode>>),
+  // takes the given number of keys and removes what they lead to from a
+  // global, or from $ without one. a global without keys is emptied
+  Delete(Option<String>, usize),
+  // whether the key is in the object or array on top of the stack
+  In,
+  // runs the body once for each key of the object or index of the array on
+  // top of the stack, with the global set to it
+  ForIn(String, Vec<OpCode>),
+  // runs the body once for each element of the array or value of the
+  // object on top of the stack, with the global set to it, or $ without one
+  ForEach(Option<String>, Vec<OpCode>),
+  // sets globals from an object's keys, as (key, variable) pairs, or from
+  // an array's elements in order
+  DestructureObject(Vec<(String, String)>),
+  DestructureArray(Vec<String>),
+  // a builtin function and how many arguments it was given
+  Call(String, usize),
+  // the same for builtins like map() whose last argument is a function
+  CallLambda(String, usize, Lambda),
+  // the same for builtins like maxby() that remember what they've seen, with
+  // a number for the call site so each call keeps its own
+  CallTracked(String, usize, usize),
+  // a `start, end` range pattern, numbered so each keeps its own state.
+  // true from a record the start matches through the next one the end
+  // matches, which may be the same record
+  Range(usize, Vec<OpCode>, Vec<OpCode>),
+  // an array of the given number of values
+  MakeArray(usize),
+  Pop,
   Equal,
+  NotEqual,
+  // string ~ regex and !~
+  Match,
+  NotMatch,
   And,
   Add,
   Subtract,
   Multiply,
   Divide,
+  Negate,
+  Not,
   Greater,
+  Less,
+  GreaterEqual,
+  LessEqual,
   Print(usize),
+  Printf(usize),
+  // the same, written to the file on top of the stack
+  PrintTo(usize),
+  PrintfTo(usize),
+  // reads a line from the file on top of the stack into a variable, or $
+  Getline(Option<String>),
+  // stops reading records, running the END rules next, or stops the END
+  // rules. with a status, it's taken off the stack to be the exit code
+  Exit(bool),
+  // adds the record to its group for --group-by and --agg, taking the
+  // group key and a value for each aggregate
+  Accumulate(Vec<Aggregate>),
+  // prints the groups, and whether there's a key column
+  ReportGroups(Vec<Aggregate>, bool),
+}
+
+// `|x, y| expr`, passed to builtins like map()
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Lambda {
+  pub params: Vec<String>,
+  pub body: Vec<OpCode>,
+}
+
+// a regex kept as a value, like `re = /a+b/`. it's compiled the first time
+// something's matched against it and kept with the value, which every copy
+// shares. it's saved as its pattern
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub struct RegexValue(Rc<CompiledRegex>);
+
+#[derive(Debug)]
+struct CompiledRegex {
+  pattern: String,
+  // without and with IGNORECASE
+  compiled: [OnceCell<Regex>; 2],
+}
+
+impl RegexValue {
+  pub fn new(pattern: String) -> RegexValue {
+    RegexValue(Rc::new(CompiledRegex { pattern, compiled: Default::default() }))
+  }
+
+  pub fn pattern(&self) -> &str {
+    &self.0.pattern
+  }
+
+  fn compiled(&self, ignore_case: bool) -> &Regex {
+    self.0.compiled[ignore_case as usize].get_or_init(|| build_regex(&self.0.pattern, ignore_case))
+  }
+}
+
+impl PartialEq for RegexValue {
+  fn eq(&self, other: &RegexValue) -> bool {
+    self.0.pattern == other.0.pattern
+  }
+}
+
+impl From<String> for RegexValue {
+  fn from(pattern: String) -> RegexValue {
+    RegexValue::new(pattern)
+  }
 }
 
-#[derive(PartialEq, Clone, Debug)]
+impl From<RegexValue> for String {
+  fn from(regex: RegexValue) -> String {
+    regex.0.pattern.clone()
+  }
+}
+
+fn build_regex(pattern: &str, ignore_case: bool) -> Regex {
+  RegexBuilder::new(pattern)
+    .case_insensitive(ignore_case)
+    .build()
+    .unwrap_or_else(|err| panic!("invalid regex {}: {}", pattern, err))
+}
+
+#[derive(PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub enum Value {
   Str(String),
   Num(f64),
   Object(serde_json::Value),
   Array(serde_json::Value),
+  // json null, and members that aren't there
+  Null,
+  // `/regex/` where its value is kept, see RegexValue
+  Regex(RegexValue),
 }
 
 impl Value {
-  fn from(v: serde_json::Value) -> Value {
+  pub fn from(v: serde_json::Value) -> Value {
     if v.is_array() {
       return Value::Array(v);
     }
@@ -44,14 +217,18 @@ impl Value {
     if v.is_number() {
       return Value::Num(v.as_f64().unwrap());
     }
+    if v.is_null() {
+      return Value::Null;
+    }
 
-    return Value::Num(0.0);
+    // like awk's comparisons, true is 1 and false 0
+    return Value::Num(if v.as_bool().unwrap_or(false) { 1.0 } else { 0.0 });
   }
 
   fn from_opt(v: Option<&serde_json::Value>) -> Value {
     match v {
       Some(v) => Value::from(v.clone()),
-      None => Value::Num(0.0),
+      None => Value::Null,
     }
   }
 
@@ -59,32 +236,67 @@ impl Value {
     match (self, other) {
       (Value::Str(a), Value::Str(b)) => a.eq(&b),
       (Value::Num(a), Value::Num(b)) => a.eq(&b),
+      // missing values and unset variables have always been 0, and like
+      // awk's uninitialized values they're the empty string too
+      (Value::Null, Value::Null) => true,
+      (Value::Null, Value::Num(n)) => n == 0.0,
+      (Value::Num(n), Value::Null) => *n == 0.0,
+      (Value::Null, Value::Str(s)) => s.is_empty(),
+      (Value::Str(s), Value::Null) => s.is_empty(),
+      (Value::Regex(a), Value::Regex(b)) => *a == b,
       _ => false,
     }
   }
 
-  fn as_f64(&self) -> f64 {
+  pub fn as_f64(&self) -> f64 {
     match self {
       Value::Num(n) => *n,
-      Value::Str(s) => s.parse().unwrap_or(0.0),
+      Value::Str(s) => s.trim().parse().unwrap_or(0.0),
       _ => 0.0
     }
   }
 
-  fn truthy(self) -> bool {
+  pub fn truthy(self) -> bool {
     match self {
-      Value::Str(s) => s.len() > 0,
+      Value::Str(s) => !s.is_empty(),
       Value::Num(n) => n != 0.0,
-      _ => false,
+      // only empty ones are false, so find($, "x") can be a condition
+      Value::Array(_) | Value::Object(_) => self.field_count() > 0,
+      Value::Null => false,
+      Value::Regex(_) => true,
+    }
+  }
+
+  // the number of elements or keys, like awk's NF
+  fn field_count(&self) -> usize {
+    match self {
+      Value::Array(serde_json::Value::Array(a)) => a.len(),
+      Value::Object(serde_json::Value::Object(o)) => o.len(),
+      _ => 0,
     }
   }
 
-  fn display_type(self) -> &'static str {
+  pub fn to_json(&self) -> serde_json::Value {
+    match self {
+      Value::Str(s) => serde_json::Value::String(s.clone()),
+      // whole numbers are written without a fraction, 6 rather than 6.0
+      Value::Num(n) if n.fract() == 0.0 && n.abs() < i64::MAX as f64 => serde_json::Value::from(*n as i64),
+      Value::Num(n) => serde_json::Number::from_f64(*n)
+        .map_or(serde_json::Value::Null, serde_json::Value::Number),
+      Value::Array(v) | Value::Object(v) => v.clone(),
+      Value::Null => serde_json::Value::Null,
+      Value::Regex(re) => serde_json::Value::String(re.0.pattern.clone()),
+    }
+  }
+
+  fn display_type(&self) -> &'static str {
     match self {
       Value::Str(_) => "string",
       Value::Num(_) => "number",
       Value::Array(_) => "array",
       Value::Object(_) => "object",
+      Value::Null => "null",
+      Value::Regex(_) => "regex",
     }
   }
 }
@@ -95,25 +307,160 @@ impl fmt::Display for Value {
       Value::Str(s) => String::from(s),
       Value::Num(n) => format!("{}", n),
       Value::Array(v) | Value::Object(v) => format!("{}", v),
+      Value::Null => String::from("0"),
+      Value::Regex(re) => re.0.pattern.clone(),
     })
   }
 }
 
 
-fn for_each_in<F: FnMut(Value)>(v: Value, mut func: F) {
+// the text of a match and each of its groups, for MATCH
+fn match_groups(regex: &Regex, s: &str) -> Option<Vec<serde_json::Value>> {
+  regex.captures(s).map(|captures| {
+    captures.iter()
+      .map(|group| group.map_or(serde_json::Value::Null, |m| serde_json::Value::String(String::from(m.as_str()))))
+      .collect()
+  })
+}
+
+// looks up an object key or array index, None if it isn't there
+fn member_of(v: &serde_json::Value, member: &Value) -> Option<serde_json::Value> {
+  match (v, member) {
+    (serde_json::Value::Object(o), Value::Str(s)) => o.get(s).cloned(),
+    (serde_json::Value::Object(o), Value::Num(n)) => o.get(&n.to_string()).cloned(),
+    (serde_json::Value::Array(a), Value::Num(n)) => a.get(*n as usize).cloned(),
+    _ => None,
+  }
+}
+
+// a member of an object by name or an array by index, as GetMember would
+// find it. None for anything else, which is left to GetMember
+fn member_in_place(v: &Value, key: &Value) -> Option<Value> {
+  match (v, key) {
+    (Value::Object(serde_json::Value::Object(o)), Value::Str(key)) => Some(Value::from_opt(o.get(key))),
+    (Value::Array(serde_json::Value::Array(a)), Value::Num(n)) => Some(Value::from_opt(a.get(*n as usize))),
+    _ => None,
+  }
+}
+
+// collects every value stored under `key` at any depth in document order.
+// uses an explicit stack rather than recursion so deep input is safe
+fn descend(v: &serde_json::Value, key: &str, out: &mut Vec<serde_json::Value>) {
+  let mut stack = vec![v];
+  while let Some(v) = stack.pop() {
+    match v {
+      serde_json::Value::Object(o) => {
+        if let Some(found) = o.get(key) {
+          out.push(found.clone());
+        }
+        stack.extend(o.values().rev());
+      },
+      serde_json::Value::Array(a) => stack.extend(a.iter().rev()),
+      _ => (),
+    }
+  }
+}
+
+// whether code could look at the record, directly or through a builtin that
+// defaults to it
+fn reads_record(code: &[OpCode]) -> bool {
+  code.iter().any(|op| match op {
+    OpCode::GetField(_) | OpCode::Print(0) | OpCode::PrintTo(0) | OpCode::Delete(None, _) => true,
+    OpCode::Call(name, _) | OpCode::CallTracked(name, _, _) => RECORD_FUNCTIONS.contains(&name.as_str()),
+    OpCode::CallLambda(_, _, lambda) => reads_record(&lambda.body),
+    OpCode::Filter(code) | OpCode::MapFilter(code) | OpCode::ForIn(_, code) | OpCode::ForEach(_, code) => reads_record(code),
+    OpCode::Range(_, start, end) => reads_record(start) || reads_record(end),
+    _ => false,
+  })
+}
+
+// whether code could read the global name, directly or in nested code
+fn reads_global(code: &[OpCode], name: &str) -> bool {
+  code.iter().any(|op| match op {
+    OpCode::GetGlobal(global) => global == name,
+    OpCode::CallLambda(_, _, lambda) => reads_global(&lambda.body, name),
+    OpCode::Filter(code) | OpCode::MapFilter(code) | OpCode::ForIn(_, code) | OpCode::ForEach(_, code) => reads_global(code, name),
+    OpCode::Range(_, start, end) => reads_global(start, name) || reads_global(end, name),
+    _ => false,
+  })
+}
+
+// the place for key in v, for assigning to. arrays grow to fit and
+// anything else becomes an object
+fn element_mut<'a>(v: &'a mut serde_json::Value, key: &Value) -> &'a mut serde_json::Value {
+  let index = match (&*v, key) {
+    (serde_json::Value::Array(_), Value::Num(n)) if *n >= 0.0 => Some(*n as usize),
+    _ => None,
+  };
+  if let Some(i) = index {
+    let a = v.as_array_mut().unwrap();
+    if i >= a.len() {
+      a.resize(i + 1, serde_json::Value::Null);
+    }
+    return &mut a[i];
+  }
+  if !v.is_object() {
+    *v = serde_json::Value::Object(serde_json::Map::new());
+  }
+  v.as_object_mut().unwrap().entry(format!("{}", key)).or_insert(serde_json::Value::Null)
+}
+
+// what's at keys in v, as element_mut would find it without changing
+// anything
+fn element_at<'a>(v: &'a serde_json::Value, keys: &[Value]) -> Option<&'a serde_json::Value> {
+  let mut v = v;
+  for key in keys.iter() {
+    v = match (v, key) {
+      (serde_json::Value::Array(a), Value::Num(n)) if *n >= 0.0 => a.get(*n as usize)?,
+      (serde_json::Value::Object(o), key) => o.get(&format!("{}", key))?,
+      _ => return None,
+    };
+  }
+  return Some(v);
+}
+
+// every element of an array or value of an object
+fn children_of(v: &serde_json::Value, out: &mut Vec<serde_json::Value>) {
+  match v {
+    serde_json::Value::Object(o) => out.extend(o.values().cloned()),
+    serde_json::Value::Array(a) => out.extend(a.iter().cloned()),
+    _ => (),
+  }
+}
+
+// elements start..end of an array, clamped to its bounds
+fn slice_of(v: &serde_json::Value, start: Option<f64>, end: Option<f64>, out: &mut Vec<serde_json::Value>) {
+  if let serde_json::Value::Array(a) = v {
+    let end = end.map_or(a.len(), |n| (n.max(0.0) as usize).min(a.len()));
+    let start = start.map_or(0, |n| (n.max(0.0) as usize).min(end));
+    out.extend_from_slice(&a[start..end]);
+  }
+}
+
+// the json behind a value that fanned out into many, see Compiler::fanned_out
+fn fanned_items(v: Value) -> Vec<serde_json::Value> {
+  match v {
+    Value::Array(serde_json::Value::Array(a)) => a,
+    _ => panic!("expected a list of values, found {}", v.display_type()),
+  }
+}
+
+// calls func with the key (object key or array index), position and value
+// of each member
+fn for_each_in<F: FnMut(Value, usize, Value)>(v: Value, mut func: F) {
   match v {
     Value::Array(a) => {
       let arr = a.as_array().unwrap();
-      for item in arr {
+      for (i, item) in arr.iter().enumerate() {
         let val = Value::from(item.clone());
-        func(val);
+        func(Value::Num(i as f64), i, val);
       }
     },
     Value::Object(o) => {
       let obj = o.as_object().unwrap();
-      for (_k, v) in obj.iter() {
+      for (i, (k, v)) in obj.iter().enumerate() {
         let val = Value::from(v.clone());
-        func(val);
+        func(Value::Str(k.clone()), i, val);
       }
     },
     _ => panic!("JSON must be an object or an array, got {:?}", v),
@@ -121,10 +468,145 @@ fn for_each_in<F: FnMut(Value)>(v: Value, mut func: F) {
 }
 
 
+// strings compare alphabetically by the collation, anything else as numbers.
+// a missing value or unset variable is the empty string next to a string,
+// so `$.name > last { last = $.name }` keeps the greatest name from the
+// first record on
+fn greater(l: &Value, r: &Value, collation: Collation) -> bool {
+  match (l, r) {
+    (Value::Str(l), Value::Str(r)) => collation.compare(l, r) == Ordering::Greater,
+    (Value::Str(l), Value::Null) => collation.compare(l, "") == Ordering::Greater,
+    (Value::Null, Value::Str(r)) => collation.compare("", r) == Ordering::Greater,
+    (l, r) => l.as_f64() > r.as_f64(),
+  }
+}
+
+// raised with panic_any by error() to stop the program with a message and
+// exit code of its choosing
+pub struct UserError {
+  pub message: String,
+  pub code: i32,
+}
+
+// raised with panic_any when a record's rules run past --record-timeout, to
+// give up on the record
+pub struct RecordTimeout;
+
+// variables the vm sets itself
+pub const BUILTIN_VARIABLES: &[&str] = &[
+  "NR", "NFIELDS", "KEY", "INDEX", "SELECTOR", "ENVIRON", "FILENAME", "OFFSET", "FLINE", "DUPKEYS", "PREV", "VALID", "SCHEMA_ERRORS", "IGNORECASE",
+  "NULLSTR", "MATCH", "ORS", "ARGV", "ARGC", "SUBSEP", "STATS",
+];
+
+// where the document being processed came from
+struct Provenance {
+  filename: String,
+  offset: usize,
+  line: usize,
+  // keys that were in their object more than once, see --dup-keys
+  duplicates: Vec<String>,
+}
+
+// one value from the selection, along with everything the builtin variables
+// need to describe it
+struct Record {
+  selector: Rc<String>,
+  key: Value,
+  index: usize,
+  value: Value,
+  provenance: Rc<Provenance>,
+}
+
+// what END sees in STATS
+#[derive(Default)]
+struct Stats {
+  started: Option<Instant>,
+  // records selected from the input, those the rules ran on, and those
+  // where at least one rule matched
+  read: usize,
+  run: usize,
+  matched: usize,
+  // records the rules started on but gave up on
+  abandoned: usize,
+}
+
 pub struct Vm {
   fields: HashMap<String, Value>,
   variables: RefCell<HashMap<String, Value>>,
   stack: Vec<Value>,
+  provenance: Rc<Provenance>,
+  // the current document's place in a stream of them, see Document
+  stream_index: Option<usize>,
+  sampler: Option<Sampler<Record>>,
+  // the current record and the ones before it, newest first, see PREV
+  history: VecDeque<serde_json::Value>,
+  window: usize,
+  // whether the rules read PREV, as keeping it copies every record
+  keeps_history: bool,
+  groups: Groups,
+  // files loaded by lookup(), by path and then by the field they're indexed on
+  lookups: HashMap<(String, String), HashMap<String, serde_json::Value>>,
+  // the first argument of each lambda being applied, innermost last, which
+  // select() gives back like jq's .
+  subjects: Vec<Value>,
+  // the parameters of each lambda being applied, innermost last
+  frames: Vec<HashMap<String, Value>>,
+  // files parsed by readjson(), by path
+  documents: HashMap<String, serde_json::Value>,
+  // selectors compiled by path()
+  paths: HashMap<String, Vec<OpCode>>,
+  // schemas loaded by schema_validate(), and the one for --schema
+  schemas: HashMap<String, Rc<Schema>>,
+  schema: Option<Schema>,
+  // with --schema and no program, problems are printed as they're found
+  report_invalid: bool,
+  invalid: usize,
+  // regexes used with ~ and !~, by pattern and whether they ignore case
+  regexes: HashMap<(String, bool), Regex>,
+  // printed rows are collected here for --to table
+  table: Option<Table>,
+  seq: bool,
+  number_format: NumberFormat,
+  escape_control: bool,
+  collation: Collation,
+  // files opened by print > "file" and getline < "file"
+  outputs: HashMap<String, LineWriter<File>>,
+  inputs: HashMap<String, Box<dyn BufRead>>,
+  // how many times each rule's body has run, by position in the program
+  rule_hits: Vec<usize>,
+  // what first(), maxby() and friends have kept, by call site
+  tracked: HashMap<usize, (Value, Value)>,
+  // the range patterns that have matched their start but not their end
+  ranges: HashSet<usize>,
+  // how long each record's rules can take, and when the current record's
+  // time is up
+  record_timeout: Option<Duration>,
+  deadline: Option<Instant>,
+  // for --dry-run: patterns are tested but no rule bodies run, and records
+  // whose patterns fail are counted rather than stopping the run
+  dry_run: bool,
+  failed_records: usize,
+  stats: Stats,
+  // the gap between records for --max-rate, and when the next one is due
+  record_interval: Option<Duration>,
+  next_record: Option<Instant>,
+  // where to save the run's state, and after how many documents, for
+  // --checkpoint. documents_read counts them as they're read
+  checkpoint: Option<(String, usize)>,
+  documents_read: usize,
+  // whether the run carries on from a checkpoint, so BEGIN has been run
+  resumed: bool,
+  // how long each output file was at the checkpoint being resumed from
+  resumed_outputs: HashMap<String, u64>,
+  // set by exit, which stops whatever is running
+  exiting: bool,
+  exit_code: Option<i32>,
+  // selectors from --root-for, by the input file's path or name
+  roots_for: HashMap<String, Vec<SelectorBranch>>,
+  // the rule being run and whether it's in the pattern, and how deeply
+  // nested the code being run is, as only the top level is tracked
+  running: Option<(usize, bool)>,
+  depth: usize,
   dbg: bool,
 }
 
@@ -133,10 +615,60 @@ impl Vm {
   pub fn new(dbg: bool) -> Vm {
     let mut variables = HashMap::new();
     variables.insert(String::from("NR"), Value::Num(0.0));
+    // joins the parts of composite keys, like awk
+    variables.insert(String::from("SUBSEP"), Value::Str(String::from("\x1c")));
+
+    let environ: serde_json::Map<String, serde_json::Value> = std::env::vars()
+      .map(|(k, v)| (k, serde_json::Value::String(v)))
+      .collect();
+    variables.insert(String::from("ENVIRON"), Value::Object(serde_json::Value::Object(environ)));
     Vm {
       fields: HashMap::new(),
       variables: RefCell::new(variables),
       stack: Vec::new(),
+      provenance: Rc::new(Provenance { filename: String::new(), offset: 0, line: 1, duplicates: Vec::new() }),
+      stream_index: None,
+      sampler: None,
+      history: VecDeque::new(),
+      window: 1,
+      keeps_history: false,
+      groups: Groups::default(),
+      lookups: HashMap::new(),
+      subjects: Vec::new(),
+      frames: Vec::new(),
+      documents: HashMap::new(),
+      paths: HashMap::new(),
+      schemas: HashMap::new(),
+      schema: None,
+      report_invalid: false,
+      invalid: 0,
+      regexes: HashMap::new(),
+      table: None,
+      seq: false,
+      number_format: NumberFormat::Auto,
+      escape_control: false,
+      collation: Collation::Binary,
+      outputs: HashMap::new(),
+      inputs: HashMap::new(),
+      rule_hits: Vec::new(),
+      tracked: HashMap::new(),
+      ranges: HashSet::new(),
+      record_timeout: None,
+      deadline: None,
+      dry_run: false,
+      failed_records: 0,
+      stats: Stats::default(),
+      record_interval: None,
+      next_record: None,
+      checkpoint: None,
+      documents_read: 0,
+      resumed: false,
+      resumed_outputs: HashMap::new(),
+      exiting: false,
+      exit_code: None,
+      roots_for: HashMap::new(),
+      running: None,
+      depth: 0,
       dbg,
     }
   }
@@ -148,25 +680,205 @@ impl Vm {
     self.stack.pop().unwrap()
   }
 
+  // like gawk, setting IGNORECASE makes ==, ~, !~ and index() ignore case
+  // when comparing strings
+  fn ignore_case(&self) -> bool {
+    self.variables.borrow().get("IGNORECASE").is_some_and(|v| v.clone().truthy())
+  }
+
+  fn regex(&mut self, pattern: &str) -> &Regex {
+    let ignore_case = self.ignore_case();
+    self.regexes.entry((String::from(pattern), ignore_case)).or_insert_with(|| build_regex(pattern, ignore_case))
+  }
+
+  // how print shows null and missing values, set with NULLSTR or
+  // --null-as. without it they're 0 like any other missing value
+  fn null_string(&self) -> Value {
+    match self.variables.borrow().get("NULLSTR") {
+      Some(v) => Value::Str(format!("{}", v)),
+      None => Value::Null,
+    }
+  }
+
+  fn printable(&self, values: Vec<Value>) -> Vec<Value> {
+    values.into_iter().map(|v| if let Value::Null = v { self.null_string() } else { v }).collect()
+  }
+
+  // what ends each print, a newline unless ORS is set
+  fn output_separator(&self) -> String {
+    match self.variables.borrow().get("ORS") {
+      Some(v) => format!("{}", v),
+      None => String::from("\n"),
+    }
+  }
+
+  // values as print shows them, separated by spaces
+  fn join_values(&self, values: Vec<Value>) -> String {
+    let line: Vec<String> = self.printable(values).iter().map(|v| {
+      let text = self.number_format.text(v);
+      if self.escape_control { escape_control(&text) } else { text }
+    }).collect();
+    return line.join(" ");
+  }
+
+  // a line of output ended by ORS. with --seq each value is a json text of
+  // its own instead, framed as in rfc 7464
+  fn print_line(&self, values: Vec<Value>) -> String {
+    if self.seq {
+      return self.printable(values).iter().map(|v| format!("\x1e{}\n", self.number_format.json(&v.to_json()))).collect();
+    }
+    return self.join_values(values) + &self.output_separator();
+  }
+
+  // prints a line of values, or adds them to the table
+  fn emit(&mut self, values: Vec<Value>) {
+    if self.table.is_none() {
+      output::print(&self.print_line(values));
+      return;
+    }
+    let values = self.printable(values);
+    if let Some(table) = self.table.as_mut() {
+      table.add(values);
+    }
+  }
+
+  // output redirected with `> "file"`. like awk, a file is truncated the
+  // first time it's written to and stays open for the rest of the run.
+  // /dev/stdout and /dev/stderr work on every platform
+  fn write_to(&mut self, target: &str, text: &str) {
+    if target == "/dev/stderr" {
+      eprint!("{}", text);
+      return;
+    }
+    if target == "/dev/stdout" {
+      output::print(text);
+      return;
+    }
+    let resumed_at = self.resumed_outputs.get(target).copied();
+    let file = self.outputs.entry(String::from(target)).or_insert_with(|| {
+      // a resumed run carries on from where the file was at the
+      // checkpoint, dropping anything written for records after it
+      let file = match resumed_at {
+        Some(len) => OpenOptions::new().append(true).open(target).and_then(|file| file.set_len(len).map(|_| file)),
+        None => File::create(target),
+      };
+      LineWriter::new(file.unwrap_or_else(|err| panic!("error opening {}: {}", target, err)))
+    });
+    file.write_all(text.as_bytes()).unwrap_or_else(|err| panic!("error writing to {}: {}", target, err));
+  }
+
+  // the next line of a file for getline, without its line ending. like
+  // print's files, it stays open for the rest of the run. - is stdin
+  fn read_line(&mut self, source: &str) -> Result<Option<String>, std::io::Error> {
+    if !self.inputs.contains_key(source) {
+      let reader: Box<dyn BufRead> = match source {
+        "-" | "/dev/stdin" => Box::new(BufReader::new(std::io::stdin())),
+        _ => Box::new(BufReader::new(File::open(source)?)),
+      };
+      self.inputs.insert(String::from(source), reader);
+    }
+
+    let mut line = String::new();
+    if self.inputs.get_mut(source).unwrap().read_line(&mut line)? == 0 {
+      return Ok(None);
+    }
+    if line.ends_with('\n') {
+      line.pop();
+      if line.ends_with('\r') {
+        line.pop();
+      }
+    }
+    return Ok(Some(line));
+  }
+
+  // collects printed rows and prints them as an aligned table at the end
+  pub fn collect_table(&mut self) {
+    self.table = Some(Table::default());
+  }
+
+  // prints every value as its own json text, each preceded by an ascii
+  // record separator, for --seq
+  pub fn json_seq(&mut self) {
+    self.seq = true;
+  }
+
+  // how print, tables and --seq write numbers
+  pub fn number_format(&mut self, format: NumberFormat) {
+    self.number_format = format;
+  }
+
+  // escapes control characters in printed strings, like a newline in a log
+  // message, so each print stays on one line. json output already does
+  pub fn escape_control(&mut self) {
+    self.escape_control = true;
+  }
+
+  fn finish_output(&mut self) {
+    if let Some(table) = self.table.take() {
+      output::print(&table.render(self.number_format, self.escape_control));
+    }
+    self.flush_all().unwrap_or_else(|(target, err)| panic!("error writing to {}: {}", target, err));
+  }
+
+  // writes out everything buffered for stdout and the files print has
+  // opened, saying which one failed
+  fn flush_all(&mut self) -> Result<(), (String, std::io::Error)> {
+    output::flush().map_err(|err| (String::from("stdout"), err))?;
+    for (target, file) in self.outputs.iter_mut() {
+      file.flush().map_err(|err| (target.clone(), err))?;
+    }
+    return Ok(());
+  }
+
   fn dbg(&mut self, op_code: &OpCode) {
-    if self.dbg {
-      println!("> {:?}", op_code);
+    if !self.dbg {
+      return;
+    }
+    match running_expression() {
+      Some((line, col, text)) if self.depth == 1 => output::print(&format!("> {:?} at {}:{} {}\n", op_code, line, col, text)),
+      _ => output::print(&format!("> {:?}\n", op_code)),
     }
   }
 
   fn dbg_stack(&mut self) {
     if self.dbg {
-      println!("--> {:?}", self.stack);
+      output::print(&format!("--> {:?}\n", self.stack));
     }
   }
 
-  fn eval(&mut self, prog: Vec<OpCode>) {
-    for op_code in prog.iter() {
+  fn eval(&mut self, prog: &[OpCode]) {
+    self.depth += 1;
+    // ops already done along with the one before them
+    let mut skip = 0;
+    for (i, op_code) in prog.iter().enumerate() {
+      if skip > 0 {
+        skip -= 1;
+        continue;
+      }
+      if self.exiting {
+        break;
+      }
+      if self.deadline.is_some_and(|deadline| Instant::now() > deadline) {
+        panic::panic_any(RecordTimeout);
+      }
+      if let (1, Some((rule, pattern))) = (self.depth, self.running) {
+        RUNNING.with(|running| running.set(Some((rule, pattern, i))));
+      }
       self.dbg(op_code);
       self.dbg_stack();
       match op_code {
         OpCode::GetField(s) => {
-          if s.len() == 0 {
+          // `$.name` looks the member up where it is, rather than copying
+          // the whole record to take one member out of it
+          if let (Some(OpCode::PushImmediate(key)), Some(OpCode::GetMember)) = (prog.get(i + 1), prog.get(i + 2)) {
+            let field = self.fields.get(if s.is_empty() { "root" } else { s });
+            if let Some(member) = field.and_then(|field| member_in_place(field, key)) {
+              self.push(member);
+              skip = 2;
+              continue;
+            }
+          }
+          if s.is_empty() {
             let field = self.fields.get("root").unwrap().clone();
             self.push(field);
           } else {
@@ -188,11 +900,13 @@ impl Vm {
             Value::Array(a) => {
               let idx = match member {
                 Value::Num(n) => n,
+                // numeric strings, e.g. from -v or ENVIRON
+                Value::Str(ref s) if s.trim().parse::<f64>().is_ok() => member.as_f64(),
                 _ => panic!("cannot index an array with a {}", member.display_type()),
               };
 
               let arr = a.as_array().unwrap();
-              let val = arr.iter().nth(idx as usize);
+              let val = arr.get(idx as usize);
               self.push(Value::from_opt(val));
             },
             Value::Object(o) => {
@@ -203,16 +917,132 @@ impl Vm {
               };
 
               let obj = o.as_object().unwrap();
-              let val = obj.get(&key).unwrap();
-              self.push(Value::from(val.clone()));
+              let val = obj.get(&key);
+              self.push(Value::from_opt(val));
+            },
+            // so a.b.c is null when a.b isn't there
+            Value::Null => self.push(Value::Null),
+            // the character at an index
+            Value::Str(s) => {
+              let idx = match member {
+                Value::Num(n) => n,
+                _ => panic!("cannot index a string with a {}", member.display_type()),
+              };
+              let c = if idx < 0.0 { None } else { s.chars().nth(idx as usize) };
+              self.push(c.map_or(Value::Null, |c| Value::Str(c.to_string())));
             },
             _ => panic!("can only access members on objects or arrays, found {}", obj.display_type()),
           }
         },
-        OpCode::Equal => {
+        OpCode::MapMember => {
+          let member = self.pop();
+          let items = fanned_items(self.pop());
+          let found = items.iter()
+            .filter_map(|item| member_of(item, &member))
+            .collect();
+          self.push(Value::Array(serde_json::Value::Array(found)));
+        },
+        OpCode::Descend => {
+          let key = format!("{}", self.pop());
+          let mut found = Vec::new();
+          if let Value::Array(v) | Value::Object(v) = self.pop() {
+            descend(&v, &key, &mut found);
+          }
+          self.push(Value::Array(serde_json::Value::Array(found)));
+        },
+        OpCode::MapDescend => {
+          let key = format!("{}", self.pop());
+          let mut found = Vec::new();
+          for item in fanned_items(self.pop()).iter() {
+            descend(item, &key, &mut found);
+          }
+          self.push(Value::Array(serde_json::Value::Array(found)));
+        },
+        OpCode::Wildcard => {
+          let mut found = Vec::new();
+          if let Value::Array(v) | Value::Object(v) = self.pop() {
+            children_of(&v, &mut found);
+          }
+          self.push(Value::Array(serde_json::Value::Array(found)));
+        },
+        OpCode::MapWildcard => {
+          let mut found = Vec::new();
+          for item in fanned_items(self.pop()).iter() {
+            children_of(item, &mut found);
+          }
+          self.push(Value::Array(serde_json::Value::Array(found)));
+        },
+        OpCode::Slice(has_start, has_end) | OpCode::MapSlice(has_start, has_end) => {
+          let end = if *has_end { Some(self.pop().as_f64()) } else { None };
+          let start = if *has_start { Some(self.pop().as_f64()) } else { None };
+          let mut found = Vec::new();
+          let sliced = match (op_code, self.pop()) {
+            (OpCode::MapSlice(_, _), v) => {
+              for item in fanned_items(v).iter() {
+                slice_of(item, start, end, &mut found);
+              }
+              Value::Array(serde_json::Value::Array(found))
+            },
+            (_, Value::Array(v)) => {
+              slice_of(&v, start, end, &mut found);
+              Value::Array(serde_json::Value::Array(found))
+            },
+            // strings are sliced by character
+            (_, Value::Str(s)) => {
+              let chars = s.chars().map(|c| serde_json::Value::String(c.to_string())).collect();
+              slice_of(&serde_json::Value::Array(chars), start, end, &mut found);
+              Value::Str(found.iter().filter_map(|c| c.as_str()).collect())
+            },
+            _ => Value::Array(serde_json::Value::Array(found)),
+          };
+          self.push(sliced);
+        },
+        OpCode::Filter(predicate) | OpCode::MapFilter(predicate) => {
+          let mut candidates = Vec::new();
+          match (op_code, self.pop()) {
+            (OpCode::MapFilter(_), v) => {
+              for item in fanned_items(v).iter() {
+                children_of(item, &mut candidates);
+              }
+            },
+            (_, Value::Array(v)) | (_, Value::Object(v)) => children_of(&v, &mut candidates),
+            _ => (),
+          }
+
+          let root = self.fields.get("root").unwrap().clone();
+          let mut found = Vec::new();
+          for candidate in candidates.into_iter() {
+            self.fields.insert(String::from("root"), Value::from(candidate.clone()));
+            self.eval(predicate);
+            if self.pop().truthy() {
+              found.push(candidate);
+            }
+          }
+          self.fields.insert(String::from("root"), root);
+          self.push(Value::Array(serde_json::Value::Array(found)));
+        },
+        OpCode::Equal | OpCode::NotEqual => {
           let right = self.pop();
           let left = self.pop();
-          let result = left.compare(right);
+          let equal = match (&left, &right) {
+            (Value::Str(l), Value::Str(r)) if self.ignore_case() => l.to_lowercase() == r.to_lowercase(),
+            _ => left.compare(right),
+          };
+          let result = equal == matches!(op_code, OpCode::Equal);
+          self.push(Value::Num(if result { 1.0 } else { 0.0 }));
+        },
+        OpCode::Match | OpCode::NotMatch => {
+          let pattern = self.pop();
+          let s = format!("{}", self.pop());
+          let groups = match pattern {
+            Value::Regex(re) => match_groups(re.compiled(self.ignore_case()), &s),
+            pattern => match_groups(self.regex(&format!("{}", pattern)), &s),
+          };
+          let found = groups.is_some();
+          if let Some(groups) = groups {
+            self.set_variable("MATCH", Value::Array(serde_json::Value::Array(groups)));
+          }
+          let result = found == matches!(op_code, OpCode::Match);
           self.push(Value::Num(if result { 1.0 } else { 0.0 }));
         },
         OpCode::And => {
@@ -234,6 +1064,14 @@ impl Vm {
           let left = self.pop().as_f64();
           self.push(Value::Num(left - right));
         },
+        OpCode::Negate => {
+          let n = self.pop().as_f64();
+          self.push(Value::Num(-n));
+        },
+        OpCode::Not => {
+          let truthy = self.pop().truthy();
+          self.push(Value::Num(if truthy { 0.0 } else { 1.0 }));
+        },
         OpCode::Multiply => {
           let right = self.pop().as_f64();
           let left = self.pop().as_f64();
@@ -244,30 +1082,132 @@ impl Vm {
           let left = self.pop().as_f64();
           self.push(Value::Num(left / right));
         },
-        OpCode::Greater => {
+        OpCode::Greater | OpCode::Less | OpCode::GreaterEqual | OpCode::LessEqual => {
           let right = self.pop();
           let left = self.pop();
-
-          match (left, right) {
-            (Value::Str(l), Value::Str(r)) => {
-              self.push(Value::Num(if l > r { 1.0 } else { 0.0 }));
-            },
-            (l, r) => {
-              self.push(Value::Num(if l.as_f64() > r.as_f64() { 1.0 } else { 0.0 }));
-            }
-          }
+          let result = match op_code {
+            OpCode::Greater => greater(&left, &right, self.collation),
+            OpCode::Less => greater(&right, &left, self.collation),
+            OpCode::GreaterEqual => !greater(&right, &left, self.collation),
+            _ => !greater(&left, &right, self.collation),
+          };
+          self.push(Value::Num(if result { 1.0 } else { 0.0 }));
         },
         OpCode::Print(argc) => {
           if *argc == 0 {
-            println!("{}", self.fields.get("root").unwrap().clone());
+            let root = self.fields.get("root").unwrap().clone();
+            self.emit(vec![root]);
             break;
           }
 
-          let mut args = Vec::with_capacity(*argc);
-          for _ in 0..*argc {
-            args.insert(0, format!("{}", self.pop()));
+          let mut args = vec![Value::Num(0.0); *argc];
+          for arg in args.iter_mut().rev() {
+            *arg = self.pop();
+          }
+          self.emit(args);
+        },
+        OpCode::Printf(argc) => {
+          let mut args = vec![Value::Num(0.0); *argc];
+          for arg in args.iter_mut().rev() {
+            *arg = self.pop();
+          }
+          output::print(&sprintf(&format!("{}", args[0]), &args[1..]));
+        },
+        OpCode::PrintTo(argc) => {
+          let target = format!("{}", self.pop());
+          let mut args = vec![Value::Num(0.0); *argc];
+          for arg in args.iter_mut().rev() {
+            *arg = self.pop();
+          }
+          if args.is_empty() {
+            args.push(self.fields.get("root").unwrap().clone());
+          }
+          if target == "/dev/stdout" {
+            self.emit(args);
+          } else {
+            let line = self.print_line(args);
+            self.write_to(&target, &line);
+          }
+        },
+        OpCode::Exit(status) => {
+          if *status {
+            self.exit_code = Some(self.pop().as_f64() as i32);
+          } else if self.exit_code.is_none() {
+            self.exit_code = Some(0);
+          }
+          self.exiting = true;
+        },
+        OpCode::Getline(name) => {
+          let source = format!("{}", self.pop());
+          let result = match self.read_line(&source) {
+            Ok(Some(line)) => {
+              match name {
+                Some(name) => self.set_variable(name, Value::Str(line)),
+                None => {
+                  self.fields.insert(String::from("root"), Value::Str(line));
+                },
+              }
+              1.0
+            },
+            Ok(None) => 0.0,
+            Err(_) => -1.0,
+          };
+          self.push(Value::Num(result));
+        },
+        OpCode::PrintfTo(argc) => {
+          let target = format!("{}", self.pop());
+          let mut args = vec![Value::Num(0.0); *argc];
+          for arg in args.iter_mut().rev() {
+            *arg = self.pop();
+          }
+          self.write_to(&target, &sprintf(&format!("{}", args[0]), &args[1..]));
+        },
+        OpCode::Call(name, argc) => {
+          let mut args = vec![Value::Num(0.0); *argc];
+          for arg in args.iter_mut().rev() {
+            *arg = self.pop();
+          }
+          let result = self.call(name, args);
+          self.push(result);
+        },
+        OpCode::CallLambda(name, argc, lambda) => {
+          let mut args = vec![Value::Num(0.0); *argc];
+          for arg in args.iter_mut().rev() {
+            *arg = self.pop();
+          }
+          let result = self.call_lambda(name, args, lambda);
+          self.push(result);
+        },
+        OpCode::CallTracked(name, argc, site) => {
+          let mut args = vec![Value::Num(0.0); *argc];
+          for arg in args.iter_mut().rev() {
+            *arg = self.pop();
+          }
+          let result = self.call_tracked(name, *site, args);
+          self.push(result);
+        },
+        OpCode::MakeArray(count) => {
+          let mut items = vec![serde_json::Value::Null; *count];
+          for item in items.iter_mut().rev() {
+            *item = self.pop().to_json();
+          }
+          self.push(Value::Array(serde_json::Value::Array(items)));
+        },
+        OpCode::Pop => {
+          self.pop();
+        },
+        OpCode::Accumulate(aggregates) => {
+          let mut values = vec![0.0; aggregates.len()];
+          for value in values.iter_mut().rev() {
+            *value = self.pop().as_f64();
+          }
+          let key = format!("{}", self.pop());
+          self.groups.add(key, aggregates, &values);
+        },
+        OpCode::ReportGroups(aggregates, grouped) => {
+          for row in self.groups.report(aggregates, *grouped).into_iter() {
+            self.emit(row);
           }
-          println!("{}", args.join(" "));
         },
         OpCode::GetGlobal(name) => {
           let val: Option<Value>;
@@ -280,68 +1220,697 @@ impl Vm {
             }
           }
 
-          if val.is_none() {
-            self.push(Value::Num(0.0));
-          } else {
-            self.push(val.unwrap());
-          }
+          // unset variables are null, which reads as 0 and can be indexed
+          // like an empty array
+          self.push(val.unwrap_or(Value::Null));
         },
         OpCode::SetGlobal(name) => {
           let val = self.pop();
           let mut variables = self.variables.borrow_mut();
           variables.insert(name.clone(), val);
         },
+        OpCode::GetLocal(name) => {
+          let val = self.frames.iter().rev().find_map(|frame| frame.get(name)).cloned();
+          self.push(val.unwrap_or(Value::Null));
+        },
+        OpCode::SetLocal(name) => {
+          let val = self.pop();
+          if let Some(frame) = self.frames.iter_mut().rev().find(|frame| frame.contains_key(name)) {
+            frame.insert(name.clone(), val);
+          }
+        },
+        OpCode::SetElement(name, depth, op) => {
+          let mut val = self.pop();
+          let mut keys = vec![Value::Null; *depth];
+          for key in keys.iter_mut().rev() {
+            *key = self.pop();
+          }
+          if let Some(op) = op {
+            let old = match self.variables.borrow().get(name) {
+              Some(Value::Array(v) | Value::Object(v)) => element_at(v, &keys).cloned(),
+              _ => None,
+            };
+            self.push(Value::from_opt(old.as_ref()));
+            self.push(val);
+            self.eval(std::slice::from_ref(&**op));
+            val = self.pop();
+          }
+          // changed where it's stored, as copying the whole variable for
+          // every element would make filling it quadratic
+          let mut variables = self.variables.borrow_mut();
+          let var = variables.entry(name.clone()).or_insert(Value::Null);
+          if !matches!(var, Value::Array(_) | Value::Object(_)) {
+            *var = Value::Object(serde_json::Value::Object(serde_json::Map::new()));
+          }
+          if let Value::Array(root) | Value::Object(root) = var {
+            let mut slot = &mut *root;
+            for key in keys.iter() {
+              slot = element_mut(slot, key);
+            }
+            *slot = val.to_json();
+            // a key can turn an array into an object
+            *var = Value::from(std::mem::take(root));
+          }
+        },
+        OpCode::Delete(name, depth) => {
+          let mut keys = vec![String::new(); *depth];
+          for key in keys.iter_mut().rev() {
+            *key = format!("{}", self.pop());
+          }
+          let keys: Vec<&str> = keys.iter().map(|k| k.as_str()).collect();
+          match name {
+            Some(name) if keys.is_empty() => {
+              let emptied = match self.variables.borrow().get(name) {
+                Some(Value::Array(_)) => Some(Value::Array(serde_json::Value::Array(Vec::new()))),
+                Some(Value::Object(_)) => Some(Value::Object(serde_json::Value::Object(serde_json::Map::new()))),
+                _ => None,
+              };
+              match emptied {
+                Some(v) => self.set_variable(name, v),
+                None => {
+                  self.variables.borrow_mut().remove(name);
+                },
+              }
+            },
+            Some(name) => {
+              if let Some(Value::Array(v) | Value::Object(v)) = self.variables.borrow_mut().get_mut(name) {
+                take_path(v, &keys);
+              }
+            },
+            None => {
+              let mut root = self.fields.get("root").unwrap().to_json();
+              take_path(&mut root, &keys);
+              self.fields.insert(String::from("root"), Value::from(root));
+            },
+          }
+        },
+        OpCode::In => {
+          let container = self.pop().to_json();
+          let key = self.pop();
+          let found = match (&container, &key) {
+            (serde_json::Value::Array(a), Value::Num(n)) => *n >= 0.0 && (*n as usize) < a.len(),
+            (serde_json::Value::Object(o), key) => o.contains_key(&format!("{}", key)),
+            _ => false,
+          };
+          self.push(Value::Num(if found { 1.0 } else { 0.0 }));
+        },
+        OpCode::ForIn(name, body) => {
+          let keys: Vec<Value> = match self.pop() {
+            Value::Object(serde_json::Value::Object(o)) => o.keys().map(|k| Value::Str(k.clone())).collect(),
+            Value::Array(serde_json::Value::Array(a)) => (0..a.len()).map(|i| Value::Num(i as f64)).collect(),
+            _ => Vec::new(),
+          };
+          for key in keys.into_iter() {
+            self.set_variable(name, key);
+            self.eval(body);
+          }
+        },
+        OpCode::Range(range, start, end) => {
+          let mut matched = self.ranges.contains(range);
+          if !matched {
+            self.eval(start);
+            matched = self.pop().truthy();
+          }
+          if matched {
+            self.eval(end);
+            if self.pop().truthy() {
+              self.ranges.remove(range);
+            } else {
+              self.ranges.insert(*range);
+            }
+          }
+          self.push(Value::Num(if matched { 1.0 } else { 0.0 }));
+        },
+        OpCode::ForEach(name, body) => {
+          let mut items = Vec::new();
+          if let Value::Array(v) | Value::Object(v) = self.pop() {
+            children_of(&v, &mut items);
+          }
+          let root = self.fields.get("root").unwrap().clone();
+          for item in items.into_iter() {
+            match name {
+              Some(name) => self.set_variable(name, Value::from(item)),
+              None => {
+                self.fields.insert(String::from("root"), Value::from(item));
+              },
+            }
+            self.eval(body);
+          }
+          self.fields.insert(String::from("root"), root);
+        },
+        OpCode::DestructureObject(names) => {
+          let v = self.pop().to_json();
+          let mut variables = self.variables.borrow_mut();
+          for (key, name) in names.iter() {
+            let val = Value::from_opt(member_of(&v, &Value::Str(key.clone())).as_ref());
+            variables.insert(name.clone(), val);
+          }
+        },
+        OpCode::DestructureArray(names) => {
+          let v = self.pop().to_json();
+          let mut variables = self.variables.borrow_mut();
+          for (i, name) in names.iter().enumerate() {
+            let val = Value::from_opt(member_of(&v, &Value::Num(i as f64)).as_ref());
+            variables.insert(name.clone(), val);
+          }
+        },
         #[allow(unreachable_patterns)]
         _ => panic!("unknown opcode {:?}", op_code),
       }
       self.dbg_stack();
     }
+    self.depth -= 1;
   }
 
-  fn eval_rules(&mut self, rules: &Vec<JqaRule>, kind: JqaRuleKind, root: Value) {
+  // rules run in the order they were written, so a rule's pattern sees what
+  // the bodies of the rules before it assigned for the same record, as in
+  // `$.pop > max { max = $.pop } $.pop == max { print }`
+  fn eval_rules(&mut self, rules: &[JqaRule], kind: JqaRuleKind, root: Value) {
     self.fields.insert(String::from("root"), root);
-    for rule in rules.iter().filter(|&rule| rule.kind == kind) {
-      if rule.pattern.len() == 0 {
-        self.eval(rule.body.clone());
+    let mut matched = false;
+    for (i, rule) in rules.iter().enumerate().filter(|(_, rule)| rule.kind == kind) {
+      if self.exiting {
+        break;
+      }
+      if rule.pattern.is_empty() {
+        self.rule_hits[i] += 1;
+        matched = true;
+        if self.dry_run {
+          continue;
+        }
+        self.running = Some((i, false));
+        self.eval(&rule.body);
+        self.stop_running();
         continue;
       }
 
-      self.eval(rule.pattern.clone());
+      self.running = Some((i, true));
+      self.eval(&rule.pattern);
       match self.stack.pop() {
         Some(v) => {
           if v.truthy() {
-            self.eval(rule.body.clone());
+            self.rule_hits[i] += 1;
+            matched = true;
+            if !self.dry_run {
+              self.running = Some((i, false));
+              self.eval(&rule.body);
+            }
           }
         }
         _ => panic!("expected one value on the stack after pattern"),
       }
+      self.stop_running();
+    }
+    if matched && kind == JqaRuleKind::Match {
+      self.stats.matched += 1;
     }
   }
 
-  pub fn run<T>(&mut self, rdr:T, selector: Vec<OpCode>, rules: Vec<JqaRule>) where T: io::Read {
-    let v: serde_json::Value = serde_json::from_reader(rdr)
-      .expect("error parsing JSON");
-    
-    self.fields.insert(String::from("root"), Value::from(v));
-    self.eval(selector);
+  // ready to run the rules, keeping them for their source maps
+  fn load_rules(&mut self, rules: &[JqaRule]) {
+    self.rule_hits = vec![0; rules.len()];
+    self.keeps_history = rules.iter().any(|rule| reads_global(&rule.pattern, "PREV") || reads_global(&rule.body, "PREV"));
+    RULES.with(|r| *r.borrow_mut() = rules.to_vec());
+  }
 
-    match self.stack.pop() {
-      Some(v) => {
-        self.eval_rules(&rules, JqaRuleKind::Begin, v.clone());
-        let v_clone = v.clone();
-        for_each_in(v, |val| {
-          {
-            let mut variables = self.variables.borrow_mut();
-            let nr = variables.get("NR").unwrap().as_f64();
+  fn stop_running(&mut self) {
+    self.running = None;
+    RUNNING.with(|running| running.set(None));
+  }
 
-            variables.insert(String::from("NR"), Value::Num(nr + 1.0));
-          }
+  pub fn rule_hits(&self) -> &[usize] {
+    &self.rule_hits
+  }
+
+  fn set_variable(&self, name: &str, val: Value) {
+    self.variables.borrow_mut().insert(String::from(name), val);
+  }
+
+  // evaluates code that doesn't depend on the input or any variables,
+  // returning None if it does
+  pub fn eval_constant(code: &[OpCode]) -> Option<Value> {
+    let constant = code.iter().all(|op| matches!(op,
+      OpCode::PushImmediate(_) | OpCode::Equal | OpCode::And | OpCode::Add | OpCode::Subtract |
+      OpCode::Multiply | OpCode::Divide | OpCode::Negate | OpCode::Not | OpCode::Greater | OpCode::Less | OpCode::GreaterEqual |
+      OpCode::LessEqual | OpCode::NotEqual));
+    // how strings are ordered, and whether IGNORECASE makes them equal,
+    // isn't known until the program runs
+    let collated = code.iter().any(|op| matches!(op, OpCode::PushImmediate(Value::Str(_)))) &&
+      code.iter().any(|op| matches!(op, OpCode::Greater | OpCode::Less | OpCode::GreaterEqual | OpCode::LessEqual |
+        OpCode::Equal | OpCode::NotEqual));
+    if !constant || collated || code.is_empty() {
+      return None;
+    }
+
+    let mut vm = Vm::new(false);
+    vm.eval(code);
+    return vm.stack.pop();
+  }
+
+  // sets a global from the command line. like awk, anything that looks like
+  // a number is treated as one
+  pub fn assign(&mut self, name: &str, value: &str) {
+    let val = match value.trim().parse::<f64>() {
+      Ok(n) => Value::Num(n),
+      Err(_) => Value::Str(String::from(value)),
+    };
+    self.set_variable(name, val);
+  }
+
+  // sets a global to a parsed json value, for --argfile
+  pub fn assign_json(&mut self, name: &str, value: serde_json::Value) {
+    self.set_variable(name, Value::from(value));
+  }
+
+  // only lets a sample of the records through to the rules
+  pub fn sample(&mut self, sample: Sample, seed: Option<u64>) {
+    self.sampler = Some(Sampler::new(sample, seed));
+  }
+
+  // selects the root of one input file differently, for --root-for
+  pub fn root_for(&mut self, file: &str, selector: Vec<SelectorBranch>) {
+    self.roots_for.insert(String::from(file), selector);
+  }
+
+  // gives up on any record whose rules take longer than this. the time is
+  // checked between opcodes, so a single builtin call isn't interrupted
+  pub fn record_timeout(&mut self, timeout: Duration) {
+    self.record_timeout = Some(timeout);
+  }
+
+  // processes at most this many records a second. the input is read ahead
+  // of the program, but only by a bounded amount (see pipeline), so once
+  // that's full a faster writer is held up rather than buffered without
+  // limit
+  pub fn max_rate(&mut self, per_second: f64) {
+    self.record_interval = Some(Duration::from_secs_f64(1.0 / per_second));
+  }
+
+  fn throttle(&mut self) {
+    let interval = match self.record_interval {
+      Some(interval) => interval,
+      None => return,
+    };
+    let now = Instant::now();
+    let due = self.next_record.map_or(now, |next| next.max(now));
+    if due > now {
+      std::thread::sleep(due - now);
+    }
+    self.next_record = Some(due + interval);
+  }
+
+  // saves the state of the run every so many documents, before reading the
+  // next one, so it can be resumed
+  pub fn checkpoint(&mut self, path: &str, every: usize) {
+    self.checkpoint = Some((String::from(path), every.max(1)));
+  }
+
+  fn save_checkpoint(&mut self, next: &Document) {
+    self.documents_read += 1;
+    let path = match &self.checkpoint {
+      Some((path, every)) if self.documents_read.is_multiple_of(*every) => path.clone(),
+      _ => return,
+    };
+    // what's been printed for the records so far has to be out before the
+    // checkpoint says they're done
+    self.flush_all().unwrap_or_else(|(target, err)| panic!("error writing to {}: {}", target, err));
+    let outputs = self.outputs.iter()
+      .map(|(target, file)| {
+        let len = file.get_ref().metadata().unwrap_or_else(|err| panic!("error checking {}: {}", target, err)).len();
+        (target.clone(), len)
+      })
+      .collect();
+    let variables = self.variables.borrow().iter()
+      .filter(|(name, _)| name.as_str() != "ENVIRON")
+      .map(|(name, v)| (name.clone(), v.to_json()))
+      .collect();
+    let state = Checkpoint {
+      filename: next.filename.clone(),
+      offset: next.offset,
+      line: next.line,
+      variables,
+      ranges: self.ranges.iter().cloned().collect(),
+      tracked: self.tracked.iter().map(|(site, (key, v))| (*site, key.to_json(), v.to_json())).collect(),
+      groups: std::mem::take(&mut self.groups),
+      outputs,
+    };
+    checkpoint::store(&path, &state);
+    self.groups = state.groups;
+  }
+
+  // carries on from a checkpoint, without running BEGIN again. the input
+  // should start where the checkpoint left off
+  pub fn resume(&mut self, state: Checkpoint) {
+    for (name, v) in state.variables.into_iter() {
+      self.set_variable(&name, Value::from(v));
+    }
+    self.ranges = state.ranges.into_iter().collect();
+    self.tracked = state.tracked.into_iter().map(|(site, key, v)| (site, (Value::from(key), Value::from(v)))).collect();
+    self.groups = state.groups;
+    self.resumed_outputs = state.outputs.into_iter().collect();
+    self.resumed = true;
+  }
+
+  // how strings are ordered, for --collate
+  pub fn collate(&mut self, collation: Collation) {
+    self.collation = collation;
+  }
+
+  // how many earlier records PREV keeps
+  pub fn window(&mut self, n: usize) {
+    self.window = n;
+  }
+
+  // checks every record against a schema before the rules see it, setting
+  // VALID and SCHEMA_ERRORS. when reporting, each problem is printed too
+  pub fn validate(&mut self, schema: Schema, report: bool) {
+    self.schema = Some(schema);
+    self.report_invalid = report;
+  }
+
+  // only tests the patterns, counting the records each rule would match, to
+  // check a program against its input before a run that changes things
+  pub fn dry_run(&mut self) {
+    self.dry_run = true;
+  }
+
+  // how many records hit an error in a dry run
+  pub fn failed_records(&self) -> usize {
+    self.failed_records
+  }
+
+  // the status given to exit, if the program exited
+  pub fn exit_code(&self) -> Option<i32> {
+    self.exit_code
+  }
+
+  // how many records failed --schema
+  pub fn invalid_count(&self) -> usize {
+    self.invalid
+  }
+
+  fn set_schema_errors(&self, errors: Vec<String>) -> bool {
+    let valid = errors.is_empty();
+    let errors = errors.into_iter().map(serde_json::Value::String).collect();
+    self.set_variable("SCHEMA_ERRORS", Value::Array(serde_json::Value::Array(errors)));
+    self.set_variable("VALID", Value::Num(if valid { 1.0 } else { 0.0 }));
+    return valid;
+  }
+
+  fn set_provenance(&self, provenance: &Provenance) {
+    self.set_variable("FILENAME", Value::Str(provenance.filename.clone()));
+    self.set_variable("OFFSET", Value::Num(provenance.offset as f64));
+    self.set_variable("FLINE", Value::Num(provenance.line as f64));
+    let duplicates = provenance.duplicates.iter().cloned().map(serde_json::Value::String).collect();
+    self.set_variable("DUPKEYS", Value::Array(serde_json::Value::Array(duplicates)));
+  }
+
+  // evaluates each branch of the selector against a document, giving the
+  // value to iterate over for each one. records from the document share its
+  // FILENAME, OFFSET and FLINE
+  fn select(&mut self, doc: Document, selector: &[SelectorBranch]) -> Vec<(String, Value)> {
+    let name = Path::new(&doc.filename).file_name().and_then(|name| name.to_str());
+    let selector = self.roots_for.get(&doc.filename)
+      .or_else(|| name.and_then(|name| self.roots_for.get(name)))
+      .cloned()
+      .unwrap_or_else(|| selector.to_vec());
+    self.provenance = Rc::new(Provenance {
+      filename: doc.filename,
+      offset: doc.offset,
+      line: doc.line,
+      duplicates: doc.duplicates,
+    });
+    self.set_provenance(&self.provenance);
+    self.stream_index = doc.stream_index;
+    self.fields.insert(String::from("root"), Value::from(doc.value));
+
+    let mut roots = Vec::with_capacity(selector.len());
+    for branch in selector.iter() {
+      self.eval(&branch.code);
+      match self.stack.pop() {
+        Some(v) => roots.push((branch.source.clone(), v)),
+        _ => panic!("expected a value on the stack after the selector"),
+      }
+    }
+    return roots;
+  }
+
+  // BEGIN and END see the selected value, or every selected value when the
+  // selector is a union
+  fn selection_root(roots: &[(String, Value)]) -> Value {
+    if roots.len() == 1 {
+      return roots[0].1.clone();
+    }
+    Value::Array(serde_json::Value::Array(roots.iter().map(|(_, v)| v.to_json()).collect()))
+  }
+
+  // each document in the input has the selector applied separately. BEGIN
+  // sees the selection from the first document and END from the last, which
+  // for the usual single document input are the same thing
+  pub fn run<I>(&mut self, docs: I, selector: Vec<SelectorBranch>, rules: Vec<JqaRule>)
+    where I: Iterator<Item = Document> {
+    let mut docs = docs;
+    self.load_rules(&rules);
+    // like awk, a program with only BEGIN rules doesn't read any input, as
+    // long as they don't look at $ (which is the first document in BEGIN)
+    let begin_only = !rules.is_empty() && rules.iter().all(|rule| rule.kind == JqaRuleKind::Begin && !reads_record(&rule.body));
+    self.stats.started = Some(Instant::now());
+    let first = if begin_only { None } else { docs.next() };
+    let first = first.unwrap_or_else(|| Document {
+      value: serde_json::Value::Object(serde_json::Map::new()),
+      filename: String::new(),
+      offset: 0,
+      line: 1,
+      duplicates: Vec::new(),
+      stream_index: None,
+    });
+    let mut roots = self.select(first, &selector);
+    let mut root = Vm::selection_root(&roots);
+
+    self.set_variable("SELECTOR", Value::Str(roots[0].0.clone()));
+    if !self.resumed {
+      self.eval_rules(&rules, JqaRuleKind::Begin, root.clone());
+    }
+    if begin_only {
+      self.finish_output();
+      return;
+    }
+    while !self.exiting {
+      self.process_records(&rules, roots);
+      if self.exiting {
+        break;
+      }
+      match docs.next() {
+        Some(doc) => {
+          self.save_checkpoint(&doc);
+          roots = self.select(doc, &selector);
+          root = Vm::selection_root(&roots);
+        },
+        None => break,
+      }
+    }
+
+    let sampled = self.sampler.as_mut().map_or(Vec::new(), |s| s.finish());
+    for record in sampled.into_iter() {
+      if self.exiting {
+        break;
+      }
+      self.eval_record(&rules, record);
+    }
+    // like awk, exit still runs the END rules, unless it's in one
+    self.exiting = false;
+    self.set_stats();
+    self.eval_rules(&rules, JqaRuleKind::End, root);
+    self.finish_output();
+    // a finished run has nothing to resume
+    if let Some((path, _)) = &self.checkpoint {
+      let _ = std::fs::remove_file(path);
+    }
+  }
+
+  // a summary of the run for END to report however it likes: records read
+  // from the input, those where a rule matched, those skipped by --sample
+  // or given up on, bytes of input and milliseconds taken
+  fn set_stats(&mut self) {
+    let stats = &self.stats;
+    let elapsed = stats.started.map_or(0.0, |started| started.elapsed().as_secs_f64() * 1000.0);
+    let summary = serde_json::json!({
+      "records": stats.read,
+      "matched": stats.matched,
+      "skipped": stats.read - stats.run + stats.abandoned,
+      "bytes": input::bytes_read(),
+      "elapsed_ms": elapsed.round(),
+    });
+    self.set_variable("STATS", Value::from(summary));
+  }
+
+  // evaluates one expression with $ as the document's root, printing the
+  // result once
+  pub fn run_eval(&mut self, doc: Document, selector: Vec<SelectorBranch>, code: Vec<OpCode>) {
+    let root = Vm::selection_root(&self.select(doc, &selector));
+    self.fields.insert(String::from("root"), root);
+    self.eval(&code);
+    let result = self.pop();
+    output::print(&self.print_line(vec![result]));
+    self.finish_output();
+  }
+
+  // compares two documents. with no rules the differences between the two
+  // selections are printed, and like diff the exit code is 1 if there are
+  // any. otherwise the rules see a record for each key in
+  // either selection, with $A and $B bound to each side's value (0 where it's
+  // missing) and $ to whichever side has it, preferring b
+  pub fn run_diff(&mut self, a: Document, b: Document, selector: Vec<SelectorBranch>, rules: Vec<JqaRule>) {
+    let a_root = Vm::selection_root(&self.select(a, &selector)).to_json();
+    let b_root = Vm::selection_root(&self.select(b, &selector)).to_json();
 
-          self.eval_rules(&rules, JqaRuleKind::Match, val);
+    if rules.is_empty() {
+      let differences = diff::differences(&a_root, &b_root);
+      for line in differences.iter() {
+        output::print(&format!("{}\n", line));
+      }
+      if !differences.is_empty() {
+        self.exit_code = Some(1);
+      }
+      self.finish_output();
+      return;
+    }
+
+    self.load_rules(&rules);
+    self.fields.insert(String::from("A"), Value::from(a_root.clone()));
+    self.fields.insert(String::from("B"), Value::from(b_root.clone()));
+    self.eval_rules(&rules, JqaRuleKind::Begin, Value::from(b_root.clone()));
+
+    for (index, key) in diff::keys(&a_root, &b_root).iter().enumerate() {
+      if self.exiting {
+        break;
+      }
+      let a_val = key.get(&a_root);
+      let b_val = key.get(&b_root);
+      let val = Value::from_opt(b_val.or(a_val));
+      {
+        let mut variables = self.variables.borrow_mut();
+        let nr = variables.get("NR").unwrap().as_f64();
+
+        variables.insert(String::from("NR"), Value::Num(nr + 1.0));
+        variables.insert(String::from("KEY"), match key {
+          diff::Key::Name(name) => Value::Str(name.clone()),
+          diff::Key::Index(i) => Value::Num(*i as f64),
         });
-        self.eval_rules(&rules, JqaRuleKind::End, v_clone);
-      },
-      _ => panic!("expected a value on the stack after the selector"),
+        variables.insert(String::from("INDEX"), Value::Num(index as f64));
+        variables.insert(String::from("NFIELDS"), Value::Num(val.field_count() as f64));
+      }
+      self.fields.insert(String::from("A"), Value::from_opt(a_val));
+      self.fields.insert(String::from("B"), Value::from_opt(b_val));
+      self.eval_rules(&rules, JqaRuleKind::Match, val);
+    }
+
+    self.fields.insert(String::from("A"), Value::from(a_root));
+    self.fields.insert(String::from("B"), Value::from(b_root.clone()));
+    self.exiting = false;
+    self.eval_rules(&rules, JqaRuleKind::End, Value::from(b_root));
+    self.finish_output();
+  }
+
+  // the members of each selection are the records, except in a stream of
+  // documents where each selection is one record, whatever it is, with its
+  // document's position as KEY and INDEX
+  fn process_records(&mut self, rules: &[JqaRule], roots: Vec<(String, Value)>) {
+    for (source, v) in roots.into_iter() {
+      let selector = Rc::new(source);
+      match self.stream_index {
+        Some(index) => self.process_record(rules, &selector, Value::Num(index as f64), index, v),
+        None => for_each_in(v, |key, index, value| self.process_record(rules, &selector, key, index, value)),
+      }
+    }
+  }
+
+  fn process_record(&mut self, rules: &[JqaRule], selector: &Rc<String>, key: Value, index: usize, value: Value) {
+    if self.exiting {
+      return;
+    }
+    let record = Record {
+      selector: selector.clone(),
+      key,
+      index,
+      value,
+      provenance: self.provenance.clone(),
+    };
+    self.stats.read += 1;
+    let record = match self.sampler.as_mut() {
+      Some(sampler) => sampler.offer(record),
+      None => Some(record),
+    };
+    if let Some(record) = record {
+      self.throttle();
+      self.eval_record(rules, record);
+    }
+  }
+
+  fn eval_record(&mut self, rules: &[JqaRule], record: Record) {
+    self.stats.run += 1;
+    {
+      let mut variables = self.variables.borrow_mut();
+      let nr = variables.get("NR").unwrap().as_f64();
+
+      variables.insert(String::from("NR"), Value::Num(nr + 1.0));
+      variables.insert(String::from("SELECTOR"), Value::Str(record.selector.to_string()));
+      variables.insert(String::from("KEY"), record.key);
+      variables.insert(String::from("INDEX"), Value::Num(record.index as f64));
+      variables.insert(String::from("NFIELDS"), Value::Num(record.value.field_count() as f64));
+    }
+    self.set_provenance(&record.provenance);
+
+    // PREV[0] is this record, PREV[1] the one before and so on
+    if self.keeps_history {
+      self.history.push_front(record.value.to_json());
+      self.history.truncate(self.window + 1);
+      let prev = serde_json::Value::Array(self.history.iter().cloned().collect());
+      self.set_variable("PREV", Value::Array(prev));
+    }
+
+    if let Some(schema) = &self.schema {
+      let errors = schema.validate(&record.value.to_json());
+      if !errors.is_empty() {
+        self.invalid += 1;
+      }
+      if self.report_invalid {
+        let nr = self.variables.borrow().get("NR").unwrap().clone();
+        for err in errors.iter() {
+          output::print(&format!("record {}: {}\n", nr, err));
+        }
+      }
+      self.set_schema_errors(errors);
+    }
+
+    let value = record.value;
+    if self.record_timeout.is_none() && !self.dry_run {
+      return self.eval_rules(rules, JqaRuleKind::Match, value);
+    }
+    self.deadline = self.record_timeout.map(|timeout| Instant::now() + timeout);
+    let result = panic::catch_unwind(AssertUnwindSafe(|| self.eval_rules(rules, JqaRuleKind::Match, value)));
+    self.deadline = None;
+    let payload = match result {
+      Ok(()) => return,
+      Err(payload) => payload,
+    };
+    // a dry run goes on to the next record, the error's already been
+    // reported by the panic hook
+    if !payload.is::<RecordTimeout>() && !self.dry_run {
+      panic::resume_unwind(payload);
+    }
+    // whatever the record was in the middle of is abandoned
+    self.stack.clear();
+    self.frames.clear();
+    self.subjects.clear();
+    self.depth = 0;
+    self.stop_running();
+    self.stats.abandoned += 1;
+    if payload.is::<RecordTimeout>() {
+      let nr = self.variables.borrow().get("NR").unwrap().clone();
+      eprintln!("warning: skipped record {}, its rules took longer than {:?}", nr, self.record_timeout.unwrap());
+    } else {
+      self.failed_records += 1;
     }
   }
 }
\ No newline at end of file
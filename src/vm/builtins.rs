@@ -0,0 +1,904 @@
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::rc::Rc;
+use crate::aggregate;
+use crate::compiler::Compiler;
+use crate::lexer::Lexer;
+use crate::output;
+use crate::printf::sprintf;
+use crate::schema::Schema;
+use super::{children_of, descend, greater, Lambda, UserError, Value, Vm};
+
+// every builtin with the fewest and most arguments it takes, not counting a
+// function passed to it. None is no limit
+pub const BUILTIN_FUNCTIONS: &[(&str, usize, Option<usize>)] = &[
+  ("lookup", 2, Some(3)), ("pointer", 2, Some(2)), ("path", 2, Some(2)), ("schema_validate", 2, Some(2)),
+  ("merge", 2, Some(2)), ("index", 2, Some(2)), ("error", 1, Some(2)), ("warn", 0, None),
+  ("map", 1, Some(1)), ("filter", 1, Some(1)), ("reduce", 2, Some(2)),
+  ("readjson", 1, Some(1)), ("writejson", 2, Some(3)), ("http_get", 1, Some(2)), ("http_get_json", 1, Some(2)),
+  ("select", 1, Some(2)), ("first", 1, Some(1)), ("last", 1, Some(1)), ("maxby", 1, Some(2)), ("minby", 1, Some(2)),
+  ("parsenum", 1, Some(2)), ("median", 1, Some(1)), ("percentile", 2, Some(2)), ("stddev", 1, Some(1)),
+  ("union", 2, Some(2)), ("intersect", 2, Some(2)), ("difference", 2, Some(2)),
+  ("find", 2, Some(2)), ("paths", 2, Some(2)), ("paths_matching", 2, Some(2)),
+  ("del", 2, None), ("omit", 2, None), ("pick", 2, None),
+  ("rename", 3, Some(3)), ("move", 3, Some(3)), ("split", 1, Some(2)), ("join", 1, Some(2)),
+  ("fflush", 0, Some(1)), ("sprintf", 1, None), ("cmp", 2, Some(2)), ("eq", 2, Some(2)),
+  ("tolower", 1, Some(1)), ("toupper", 1, Some(1)),
+];
+
+// checks a call has the right number of arguments for the builtin, with a
+// message like "split expects 1-2 arguments, got 3" if not
+pub fn check_arity(name: &str, count: usize) -> Result<(), String> {
+  let (least, most) = match BUILTIN_FUNCTIONS.iter().find(|(builtin, _, _)| *builtin == name) {
+    Some((_, least, most)) => (*least, *most),
+    None => return Err(format!("unknown function {}", name)),
+  };
+  if count >= least && most.is_none_or(|most| count <= most) {
+    return Ok(());
+  }
+  let expected = match most {
+    None => format!("at least {}", least),
+    Some(most) if most == least => format!("{}", least),
+    Some(most) => format!("{}-{}", least, most),
+  };
+  let plural = if most == Some(1) { "argument" } else { "arguments" };
+  return Err(format!("{} expects {} {}, got {}", name, expected, plural, count));
+}
+
+// the arguments, counting from 0, that a string or numeric builtin needs to
+// be a string or a number. those convert into each other like awk, so it's
+// only arrays and objects that are refused
+const SCALAR_ARGUMENTS: &[(&str, &[usize])] = &[
+  ("pointer", &[1]), ("index", &[0, 1]), ("error", &[0, 1]), ("parsenum", &[0, 1]), ("percentile", &[1]),
+  ("readjson", &[0]), ("writejson", &[0]), ("http_get", &[0, 1]), ("http_get_json", &[0, 1]), ("lookup", &[0, 1]),
+  ("path", &[1]), ("schema_validate", &[1]), ("rename", &[1, 2]), ("move", &[1, 2]), ("split", &[0, 1]),
+  ("join", &[1]), ("paths_matching", &[1]), ("fflush", &[0]), ("sprintf", &[0]), ("tolower", &[0]), ("toupper", &[0]),
+];
+
+// checks the arguments given to a builtin are the types it needs, with a
+// message like "tolower expects a string or number for argument 1, got
+// array" if not
+fn check_types(name: &str, args: &[Value]) {
+  let positions = SCALAR_ARGUMENTS.iter().find(|(builtin, _)| *builtin == name).map_or(&[][..], |(_, positions)| *positions);
+  for &i in positions.iter() {
+    if let Some(arg @ (Value::Array(_) | Value::Object(_))) = args.get(i) {
+      panic!("{} expects a string or number for argument {}, got {}", name, i + 1, arg.display_type());
+    }
+  }
+}
+
+// the builtins that take a function as their last argument
+pub const LAMBDA_FUNCTIONS: &[&str] = &["map", "filter", "reduce"];
+
+// the builtins that fall back to the record without an argument for it
+pub const RECORD_FUNCTIONS: &[&str] = &["warn", "select", "maxby", "minby"];
+
+// the builtins that keep a value between calls
+pub const TRACKING_FUNCTIONS: &[&str] = &["first", "last", "maxby", "minby"];
+
+fn load_json(path: &str, what: &str) -> serde_json::Value {
+  let file = File::open(path).unwrap_or_else(|err| panic!("error opening {} {}: {}", what, path, err));
+  serde_json::from_reader(BufReader::new(file))
+    .unwrap_or_else(|err| panic!("error parsing {} {}: {}", what, path, err))
+}
+
+// a json file loaded once for lookup(), keyed by the given field of each of
+// its elements, or by its own keys or indexes without one
+fn load_lookup(path: &str, field: Option<&str>) -> HashMap<String, serde_json::Value> {
+  let doc = load_json(path, "lookup file");
+
+  let mut table = HashMap::new();
+  match field {
+    Some(field) => {
+      let mut rows = Vec::new();
+      children_of(&doc, &mut rows);
+      for row in rows.into_iter() {
+        if let Some(key) = row.get(field) {
+          // the first row with a key wins
+          table.entry(format!("{}", Value::from(key.clone()))).or_insert(row);
+        }
+      }
+    },
+    None => match doc {
+      serde_json::Value::Object(o) => table.extend(o),
+      serde_json::Value::Array(a) => {
+        table.extend(a.into_iter().enumerate().map(|(i, v)| (i.to_string(), v)));
+      },
+      _ => panic!("lookup file {} must hold an object or an array", path),
+    },
+  }
+  return table;
+}
+
+// the elements of an array or values of an object, for map() and friends
+fn elements(name: &str, v: &Value) -> Vec<serde_json::Value> {
+  let mut items = Vec::new();
+  match v {
+    Value::Array(v) | Value::Object(v) => children_of(v, &mut items),
+    _ => panic!("{} takes an array or object, got {}", name, v.display_type()),
+  }
+  return items;
+}
+
+// pointer(v, "/a/b/2") follows a json pointer
+fn pointer(args: Vec<Value>) -> Value {
+  match args.as_slice() {
+    [v, pointer] => Value::from_opt(v.to_json().pointer(&format!("{}", pointer))),
+    _ => panic!("pointer takes 2 arguments, got {}", args.len()),
+  }
+}
+
+// applies a json merge patch (rfc 7386): objects are merged key by key, a
+// null removes the key, and anything else replaces the target outright
+fn merge_patch(target: serde_json::Value, patch: &serde_json::Value) -> serde_json::Value {
+  let patch = match patch {
+    serde_json::Value::Object(patch) => patch,
+    _ => return patch.clone(),
+  };
+
+  let mut target = match target {
+    serde_json::Value::Object(target) => target,
+    _ => serde_json::Map::new(),
+  };
+  for (key, value) in patch.iter() {
+    if value.is_null() {
+      target.remove(key);
+    } else {
+      let existing = target.remove(key).unwrap_or(serde_json::Value::Null);
+      target.insert(key.clone(), merge_patch(existing, value));
+    }
+  }
+  return serde_json::Value::Object(target);
+}
+
+// merge(a, b) overlays b onto a
+fn merge(args: Vec<Value>) -> Value {
+  match args.as_slice() {
+    [a, b] => Value::from(merge_patch(a.to_json(), &b.to_json())),
+    _ => panic!("merge takes 2 arguments, got {}", args.len()),
+  }
+}
+
+// the multiplier for a unit after a number: durations in seconds, byte
+// sizes either si (k, M, G, T, P) or binary (Ki, Mi, ...) with an optional
+// B, and percentages
+fn unit_scale(unit: &str) -> Option<f64> {
+  let duration = match unit {
+    "" => 1.0,
+    "%" => 0.01,
+    "ns" => 1e-9,
+    "us" | "µs" => 1e-6,
+    "ms" => 1e-3,
+    "s" | "sec" => 1.0,
+    "m" | "min" => 60.0,
+    "h" => 3600.0,
+    "d" => 86400.0,
+    "w" => 604800.0,
+    _ => 0.0,
+  };
+  if duration != 0.0 {
+    return Some(duration);
+  }
+
+  // a bare m is minutes, so sizes need a capital M unless there's a B
+  if unit == "m" {
+    return None;
+  }
+  return size_scale(unit);
+}
+
+// the multiplier for a byte size unit, where any m is mega. si (k, M, G,
+// T, P) or binary (Ki, Mi, ...) prefixes with an optional B, or a bare B
+fn size_scale(unit: &str) -> Option<f64> {
+  let prefix = unit.strip_suffix(['B', 'b']).unwrap_or(unit).to_lowercase();
+  let (prefix, base) = match prefix.strip_suffix('i') {
+    Some(prefix) => (prefix, 1024.0_f64),
+    None => (prefix.as_str(), 1000.0),
+  };
+  let power = match prefix {
+    "" => 0,
+    "k" => 1,
+    "m" => 2,
+    "g" => 3,
+    "t" => 4,
+    "p" => 5,
+    _ => return None,
+  };
+  return Some(base.powi(power));
+}
+
+// a number followed by an optional unit, like 100ms or 1.5GiB, in seconds,
+// bytes and so on
+pub fn parse_with_unit(s: &str) -> Option<f64> {
+  let (n, unit) = split_number(s)?;
+  return unit_scale(unit).map(|scale| n * scale);
+}
+
+// a number of bytes like 512k or 1.5GiB for the size flags, which don't
+// take durations so 1m is a megabyte
+pub fn parse_size(s: &str) -> Option<f64> {
+  let (n, unit) = split_number(s)?;
+  return size_scale(unit).map(|scale| n * scale);
+}
+
+// the number at the start of s and the trimmed unit after it. the number
+// runs up to the first character that can't be in one, allowing _ and ,
+// between digits
+fn split_number(s: &str) -> Option<(f64, &str)> {
+  let end = s.char_indices()
+    .find(|(i, c)| {
+      let exponent = matches!(c, 'e' | 'E') && s[i + 1..].starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+');
+      let sign = matches!(c, '-' | '+') && (*i == 0 || s[..*i].ends_with(['e', 'E']));
+      !(c.is_ascii_digit() || matches!(c, '.' | '_' | ',') || exponent || sign)
+    })
+    .map_or(s.len(), |(i, _)| i);
+  let number: String = s[..end].chars().filter(|c| !matches!(c, '_' | ',')).collect();
+  number.parse::<f64>().ok().map(|n| (n, s[end..].trim()))
+}
+
+// an integer in the given radix, allowing the usual 0x, 0o or 0b prefix
+fn parse_radix(s: &str, radix: u32) -> Option<f64> {
+  let (negative, digits) = match s.strip_prefix('-') {
+    Some(digits) => (true, digits),
+    None => (false, s.strip_prefix('+').unwrap_or(s)),
+  };
+  let prefix = match radix {
+    16 => "0x",
+    8 => "0o",
+    2 => "0b",
+    _ => "",
+  };
+  let digits = if prefix.is_empty() { None } else { digits.get(..2) }
+    .filter(|p| p.eq_ignore_ascii_case(prefix))
+    .map_or(digits, |_| &digits[2..]);
+  let n = i64::from_str_radix(&digits.replace('_', ""), radix).ok()? as f64;
+  return Some(if negative { -n } else { n });
+}
+
+// parsenum("1.5GiB"), parsenum("250ms") or parsenum("0x1f", 16) read
+// numbers written for people. anything that can't be read is null
+fn parsenum(args: Vec<Value>) -> Value {
+  let (s, radix) = match args.as_slice() {
+    [s] => (format!("{}", s), None),
+    [s, radix] => (format!("{}", s), Some(radix.as_f64() as u32)),
+    _ => panic!("parsenum takes 1 or 2 arguments, got {}", args.len()),
+  };
+  let s = s.trim();
+
+  let n = match radix {
+    Some(radix) if !(2..=36).contains(&radix) => panic!("parsenum radix must be between 2 and 36, got {}", radix),
+    Some(radix) => parse_radix(s, radix),
+    None => {
+      let lower = s.trim_start_matches(['-', '+']).to_lowercase();
+      if lower.starts_with("0x") {
+        parse_radix(s, 16)
+      } else if lower.starts_with("0o") {
+        parse_radix(s, 8)
+      } else if lower.starts_with("0b") {
+        parse_radix(s, 2)
+      } else {
+        parse_with_unit(s)
+      }
+    },
+  };
+  return n.map_or(Value::Null, Value::Num);
+}
+
+// the numbers in an array, or an object's values, for the statistics
+fn numbers(name: &str, v: &Value) -> Vec<f64> {
+  elements(name, v).into_iter().map(|item| Value::from(item).as_f64()).collect()
+}
+
+// median(a), percentile(a, p) and stddev(a), the sample standard deviation,
+// over an array or the values of an object. they're null when it's empty
+fn statistic(name: &str, args: Vec<Value>) -> Value {
+  let n = match (name, args.as_slice()) {
+    ("median", [v]) => aggregate::percentile(numbers(name, v), 50.0),
+    ("percentile", [v, p]) => {
+      let p = p.as_f64();
+      if !(0.0..=100.0).contains(&p) {
+        panic!("percentile must be between 0 and 100, got {}", p);
+      }
+      aggregate::percentile(numbers(name, v), p)
+    },
+    ("stddev", [v]) => aggregate::stddev(&numbers(name, v)),
+    ("percentile", _) => panic!("percentile takes 2 arguments, got {}", args.len()),
+    _ => panic!("{} takes 1 argument, got {}", name, args.len()),
+  };
+  return n.map_or(Value::Null, Value::Num);
+}
+
+// union(a, b), intersect(a, b) and difference(a, b) treat arrays as sets,
+// giving each element once in the order it first appears
+fn set_operation(name: &str, args: Vec<Value>) -> Value {
+  let (a, b) = match args.as_slice() {
+    [a, b] => (elements(name, a), elements(name, b)),
+    _ => panic!("{} takes 2 arguments, got {}", name, args.len()),
+  };
+  let in_b: HashSet<String> = b.iter().map(|v| v.to_string()).collect();
+  let candidates: Vec<serde_json::Value> = match name {
+    "union" => a.into_iter().chain(b).collect(),
+    "intersect" => a.into_iter().filter(|v| in_b.contains(&v.to_string())).collect(),
+    _ => a.into_iter().filter(|v| !in_b.contains(&v.to_string())).collect(),
+  };
+
+  let mut seen = HashSet::new();
+  let result = candidates.into_iter().filter(|v| seen.insert(v.to_string())).collect();
+  return Value::Array(serde_json::Value::Array(result));
+}
+
+// find(v, key) is every value stored under key at any depth in v, like
+// v..key but usable on any value and always giving an array
+fn find(args: Vec<Value>) -> Value {
+  let (v, key) = match args.as_slice() {
+    [v, key] => (v.to_json(), format!("{}", key)),
+    _ => panic!("find takes 2 arguments, got {}", args.len()),
+  };
+  let mut found = Vec::new();
+  descend(&v, &key, &mut found);
+  return Value::Array(serde_json::Value::Array(found));
+}
+
+// json pointers to everything in v the test picks out, in document order
+fn pointers(v: &serde_json::Value, mut test: impl FnMut(&serde_json::Value) -> bool) -> Value {
+  let mut found = Vec::new();
+  let mut stack = vec![(String::new(), v)];
+  while let Some((pointer, v)) = stack.pop() {
+    if test(v) {
+      found.push(serde_json::Value::String(pointer.clone()));
+    }
+    match v {
+      serde_json::Value::Object(o) => {
+        let escape = |k: &str| k.replace('~', "~0").replace('/', "~1");
+        stack.extend(o.iter().rev().map(|(k, v)| (format!("{}/{}", pointer, escape(k)), v)));
+      },
+      serde_json::Value::Array(a) => {
+        stack.extend(a.iter().enumerate().rev().map(|(i, v)| (format!("{}/{}", pointer, i), v)));
+      },
+      _ => (),
+    }
+  }
+  return Value::Array(serde_json::Value::Array(found));
+}
+
+// paths(v, x) gives a json pointer to everywhere x appears in v
+fn paths(args: Vec<Value>) -> Value {
+  match args.as_slice() {
+    [v, x] => {
+      let x = x.to_json();
+      pointers(&v.to_json(), |v| *v == x)
+    },
+    _ => panic!("paths takes 2 arguments, got {}", args.len()),
+  }
+}
+
+// del(obj, "key"), omit(obj, keys) and pick(obj, keys) give a copy of obj
+// without the keys, or with only them. keys can be a string, an array of
+// them, or several arguments
+fn select_keys(name: &str, args: Vec<Value>) -> Value {
+  let (obj, keys) = match args.split_first() {
+    Some((obj, keys)) if !keys.is_empty() => (obj, keys),
+    _ => panic!("{} takes an object and at least one key, got {} arguments", name, args.len()),
+  };
+  let mut wanted = HashSet::new();
+  for key in keys.iter() {
+    match key {
+      Value::Array(serde_json::Value::Array(a)) => wanted.extend(a.iter().map(|k| format!("{}", Value::from(k.clone())))),
+      _ => {
+        wanted.insert(format!("{}", key));
+      },
+    }
+  }
+
+  let obj = match obj {
+    Value::Object(serde_json::Value::Object(o)) => o,
+    _ => panic!("{} takes an object, got {}", name, obj.display_type()),
+  };
+  let keep = name == "pick";
+  let obj = obj.iter()
+    .filter(|(k, _)| wanted.contains(*k) == keep)
+    .map(|(k, v)| (k.clone(), v.clone()))
+    .collect();
+  return Value::Object(serde_json::Value::Object(obj));
+}
+
+// takes the value at a dotted path like a.b.0 out of v
+pub fn take_path(v: &mut serde_json::Value, path: &[&str]) -> Option<serde_json::Value> {
+  let (last, parents) = path.split_last()?;
+  let mut v = v;
+  for key in parents.iter() {
+    v = match v {
+      serde_json::Value::Object(o) => o.get_mut(*key)?,
+      serde_json::Value::Array(a) => a.get_mut(key.parse::<usize>().ok()?)?,
+      _ => return None,
+    };
+  }
+  match v {
+    serde_json::Value::Object(o) => o.remove(*last),
+    serde_json::Value::Array(a) => {
+      let i = last.parse::<usize>().ok().filter(|i| *i < a.len())?;
+      Some(a.remove(i))
+    },
+    _ => None,
+  }
+}
+
+// puts a value at a dotted path in v, making objects along the way
+fn put_path(v: &mut serde_json::Value, path: &[&str], value: serde_json::Value) {
+  let mut v = v;
+  for key in path.iter() {
+    if let serde_json::Value::Array(a) = v {
+      match key.parse::<usize>() {
+        Ok(i) if i < a.len() => {
+          v = &mut a[i];
+          continue;
+        },
+        _ => panic!("move cannot put a value at {} in an array", key),
+      }
+    }
+    if !v.is_object() {
+      *v = serde_json::Value::Object(serde_json::Map::new());
+    }
+    v = v.as_object_mut().unwrap().entry(String::from(*key)).or_insert(serde_json::Value::Null);
+  }
+  *v = value;
+}
+
+// rename(obj, "old", "new") renames a key, and move(obj, "a.b", "c") moves
+// whatever is at one dotted path to another. both give a copy, unchanged if
+// there's nothing to move
+fn reshape(name: &str, args: Vec<Value>) -> Value {
+  let (mut v, from, to) = match args.as_slice() {
+    [v, from, to] => (v.to_json(), format!("{}", from), format!("{}", to)),
+    _ => panic!("{} takes 3 arguments, got {}", name, args.len()),
+  };
+  let (from, to): (Vec<&str>, Vec<&str>) = match name {
+    "rename" if !v.is_object() => panic!("rename takes an object, got {}", Value::from(v).display_type()),
+    "rename" => (vec![&from], vec![&to]),
+    _ => (from.split('.').collect(), to.split('.').collect()),
+  };
+  if let Some(value) = take_path(&mut v, &from) {
+    put_path(&mut v, &to, value);
+  }
+  return Value::from(v);
+}
+
+// sprintf(fmt, ...) gives what printf would print
+fn format_string(args: Vec<Value>) -> Value {
+  match args.split_first() {
+    Some((fmt, args)) => Value::Str(sprintf(&format!("{}", fmt), args)),
+    None => panic!("sprintf takes at least 1 argument, got 0"),
+  }
+}
+
+// tolower(s) and toupper(s), for any letters unicode has cases for
+fn change_case(name: &str, args: Vec<Value>) -> Value {
+  let s = match args.as_slice() {
+    [s] => format!("{}", s),
+    _ => panic!("{} takes 1 argument, got {}", name, args.len()),
+  };
+  return Value::Str(if name == "tolower" { s.to_lowercase() } else { s.to_uppercase() });
+}
+
+// where a value comes in the order cmp() puts different types in
+fn type_rank(v: &serde_json::Value) -> u8 {
+  match v {
+    serde_json::Value::Null => 0,
+    serde_json::Value::Bool(_) => 1,
+    serde_json::Value::Number(_) => 2,
+    serde_json::Value::String(_) => 3,
+    serde_json::Value::Array(_) => 4,
+    serde_json::Value::Object(_) => 5,
+  }
+}
+
+// nan comes before every other number
+fn compare_numbers(a: f64, b: f64) -> Ordering {
+  a.partial_cmp(&b).unwrap_or_else(|| b.is_nan().cmp(&a.is_nan()))
+}
+
+// jq's order: null < false < true < numbers < strings < arrays < objects.
+// strings compare by code point, arrays element by element, and objects by
+// their sorted keys and then the values of those keys in turn
+fn total_order(a: &serde_json::Value, b: &serde_json::Value) -> Ordering {
+  match (a, b) {
+    (serde_json::Value::Bool(a), serde_json::Value::Bool(b)) => a.cmp(b),
+    (serde_json::Value::Number(a), serde_json::Value::Number(b)) => compare_numbers(a.as_f64().unwrap_or(0.0), b.as_f64().unwrap_or(0.0)),
+    (serde_json::Value::String(a), serde_json::Value::String(b)) => a.cmp(b),
+    (serde_json::Value::Array(a), serde_json::Value::Array(b)) => {
+      for (a, b) in a.iter().zip(b.iter()) {
+        let order = total_order(a, b);
+        if order != Ordering::Equal {
+          return order;
+        }
+      }
+      a.len().cmp(&b.len())
+    },
+    (serde_json::Value::Object(a), serde_json::Value::Object(b)) => {
+      let mut a_keys: Vec<&String> = a.keys().collect();
+      let mut b_keys: Vec<&String> = b.keys().collect();
+      a_keys.sort();
+      b_keys.sort();
+      a_keys.cmp(&b_keys).then_with(|| {
+        a_keys.iter()
+          .map(|key| total_order(&a[*key], &b[*key]))
+          .find(|order| *order != Ordering::Equal)
+          .unwrap_or(Ordering::Equal)
+      })
+    },
+    _ => type_rank(a).cmp(&type_rank(b)),
+  }
+}
+
+// cmp(a, b) is -1, 0 or 1 as a comes before, with or after b in a total
+// order over every value, whatever their types, and eq(a, b) is whether
+// they're the same. unlike == and <, nothing is coerced: "1" isn't 1.
+// true and false read from the input are 1 and 0 by the time they're values
+// of their own, but keep their place inside arrays and objects
+fn compare(name: &str, args: Vec<Value>) -> Value {
+  let order = match args.as_slice() {
+    [Value::Num(a), Value::Num(b)] => compare_numbers(*a, *b),
+    [a, b] => total_order(&a.to_json(), &b.to_json()),
+    _ => panic!("{} takes 2 arguments, got {}", name, args.len()),
+  };
+  if name == "eq" {
+    return Value::Num(if order == Ordering::Equal { 1.0 } else { 0.0 });
+  }
+  return Value::Num(match order {
+    Ordering::Less => -1.0,
+    Ordering::Equal => 0.0,
+    Ordering::Greater => 1.0,
+  });
+}
+
+// error(message[, code]) stops the program, exiting with code or 1
+fn error(args: Vec<Value>) -> Value {
+  let (message, code) = match args.as_slice() {
+    [message] => (format!("{}", message), 1),
+    [message, code] => (format!("{}", message), code.as_f64() as i32),
+    _ => panic!("error takes 1 or 2 arguments, got {}", args.len()),
+  };
+  std::panic::panic_any(UserError { message, code });
+}
+
+// http_get(url[, timeout]) fetches a url and gives the body, and
+// http_get_json parses it. the timeout is in seconds, 10 by default. they're
+// only available when built with the http feature
+#[cfg(feature = "http")]
+fn http_get(name: &str, args: Vec<Value>) -> Value {
+  let (url, timeout) = match args.as_slice() {
+    [url] => (format!("{}", url), 10.0),
+    [url, timeout] => (format!("{}", url), timeout.as_f64()),
+    _ => panic!("{} takes 1 or 2 arguments, got {}", name, args.len()),
+  };
+  let agent = ureq::AgentBuilder::new()
+    .timeout(std::time::Duration::from_secs_f64(timeout))
+    .build();
+  let response = agent.get(&url).call()
+    .unwrap_or_else(|err| panic!("error fetching {}: {}", url, err));
+  let body = response.into_string()
+    .unwrap_or_else(|err| panic!("error reading {}: {}", url, err));
+
+  if name == "http_get_json" {
+    let json = serde_json::from_str(&body).unwrap_or_else(|err| panic!("error parsing {}: {}", url, err));
+    return Value::from(json);
+  }
+  return Value::Str(body);
+}
+
+#[cfg(not(feature = "http"))]
+fn http_get(name: &str, _args: Vec<Value>) -> Value {
+  panic!("{} needs jqawk built with the http feature", name);
+}
+
+impl Vm {
+  // like print, but to stderr so it stays out of the data being piped on
+  fn warn(&mut self, mut args: Vec<Value>) -> Value {
+    if args.is_empty() {
+      args.push(self.fields.get("root").unwrap().clone());
+    }
+    eprintln!("{}", self.join_values(args));
+    return Value::Num(0.0);
+  }
+
+  // fflush() writes out everything print has buffered, fflush(target) just
+  // what's gone to stdout or a file opened with `> "file"`. like awk it
+  // gives 0, or -1 when the target isn't open or can't be written to
+  fn fflush(&mut self, args: Vec<Value>) -> Value {
+    let result = match args.first().map(|target| format!("{}", target)).as_deref() {
+      None => self.flush_all().map_err(|_| ()),
+      Some("/dev/stdout") => output::flush().map_err(|_| ()),
+      // stderr isn't buffered
+      Some("/dev/stderr") => Ok(()),
+      Some(target) => match self.outputs.get_mut(target) {
+        Some(file) => file.flush().map_err(|_| ()),
+        None => Err(()),
+      },
+    };
+    return Value::Num(if result.is_ok() { 0.0 } else { -1.0 });
+  }
+
+  pub(super) fn call(&mut self, name: &str, args: Vec<Value>) -> Value {
+    check_types(name, &args);
+    match name {
+      "lookup" => self.lookup(args),
+      "pointer" => pointer(args),
+      "path" => self.path(args),
+      "schema_validate" => self.schema_validate(args),
+      "merge" => merge(args),
+      "index" => self.index(args),
+      "error" => error(args),
+      "parsenum" => parsenum(args),
+      "median" | "percentile" | "stddev" => statistic(name, args),
+      "union" | "intersect" | "difference" => set_operation(name, args),
+      "find" => find(args),
+      "paths" => paths(args),
+      "del" | "omit" | "pick" => select_keys(name, args),
+      "rename" | "move" => reshape(name, args),
+      "split" => self.split(args),
+      "join" => self.join(args),
+      "paths_matching" => self.paths_matching(args),
+      "warn" => self.warn(args),
+      "fflush" => self.fflush(args),
+      "sprintf" => format_string(args),
+      "cmp" | "eq" => compare(name, args),
+      "tolower" | "toupper" => change_case(name, args),
+      "readjson" => self.readjson(args),
+      "writejson" => self.writejson(args),
+      "http_get" | "http_get_json" => http_get(name, args),
+      "select" => self.select_value(args),
+      _ => panic!("unknown function {}", name),
+    }
+  }
+
+  // first(v) and last(v) give the first and latest v seen by that call.
+  // maxby(key[, v]) and minby(key[, v]) give the v, or the record, that came
+  // with the largest or smallest key so far, the first of any ties
+  pub(super) fn call_tracked(&mut self, name: &str, site: usize, args: Vec<Value>) -> Value {
+    let (key, v) = match (name, args.as_slice()) {
+      ("first", [v]) | ("last", [v]) => (Value::Null, v.clone()),
+      ("maxby", [key]) | ("minby", [key]) => {
+        let subject = self.subjects.last().cloned();
+        (key.clone(), subject.unwrap_or_else(|| self.fields.get("root").unwrap().clone()))
+      },
+      ("maxby", [key, v]) | ("minby", [key, v]) => (key.clone(), v.clone()),
+      ("first", _) | ("last", _) => panic!("{} takes 1 argument, got {}", name, args.len()),
+      _ => panic!("{} takes 1 or 2 arguments, got {}", name, args.len()),
+    };
+
+    let replace = match self.tracked.get(&site) {
+      None => true,
+      Some((best, _)) => match name {
+        "first" => false,
+        "last" => true,
+        "maxby" => greater(&key, best, self.collation),
+        _ => greater(best, &key, self.collation),
+      },
+    };
+    if replace {
+      self.tracked.insert(site, (key, v));
+    }
+    return self.tracked.get(&site).unwrap().1.clone();
+  }
+
+  pub(super) fn call_lambda(&mut self, name: &str, args: Vec<Value>, lambda: &Lambda) -> Value {
+    match (name, args.as_slice()) {
+      ("map", [v]) => self.map(v, lambda),
+      ("filter", [v]) => self.filter(v, lambda),
+      ("reduce", [v, init]) => {
+        let mut acc = init.clone();
+        for item in elements("reduce", v).into_iter() {
+          acc = self.apply(lambda, vec![acc, Value::from(item)]);
+        }
+        acc
+      },
+      ("map", _) | ("filter", _) => panic!("{} takes 1 argument and a function, got {}", name, args.len()),
+      ("reduce", _) => panic!("reduce takes 2 arguments and a function, got {}", args.len()),
+      _ => panic!("unknown function {}", name),
+    }
+  }
+
+  // calls a lambda with a frame of its own for its parameters, which shadow
+  // any globals of the same name. missing arguments are null, the same as
+  // an unset global
+  fn apply(&mut self, lambda: &Lambda, args: Vec<Value>) -> Value {
+    self.subjects.push(args.first().cloned().unwrap_or(Value::Null));
+    let mut args = args.into_iter();
+    let frame = lambda.params.iter()
+      .map(|param| (param.clone(), args.next().unwrap_or(Value::Null)))
+      .collect();
+    self.frames.push(frame);
+
+    self.eval(&lambda.body);
+    let result = self.pop();
+    self.frames.pop();
+    self.subjects.pop();
+    return result;
+  }
+
+  // map(v, |x| ...) applies the function to each element. objects keep
+  // their keys
+  fn map(&mut self, v: &Value, lambda: &Lambda) -> Value {
+    if let Value::Object(serde_json::Value::Object(o)) = v {
+      let mapped = o.iter()
+        .map(|(key, item)| (key.clone(), self.apply(lambda, vec![Value::from(item.clone())]).to_json()))
+        .collect();
+      return Value::Object(serde_json::Value::Object(mapped));
+    }
+    let mapped = elements("map", v).into_iter()
+      .map(|item| self.apply(lambda, vec![Value::from(item)]).to_json())
+      .collect();
+    return Value::Array(serde_json::Value::Array(mapped));
+  }
+
+  // filter(v, |x| ...) keeps the elements the function is true for
+  fn filter(&mut self, v: &Value, lambda: &Lambda) -> Value {
+    if let Value::Object(serde_json::Value::Object(o)) = v {
+      let kept = o.iter()
+        .filter(|(_, item)| self.apply(lambda, vec![Value::from((*item).clone())]).truthy())
+        .map(|(key, item)| (key.clone(), item.clone()))
+        .collect();
+      return Value::Object(serde_json::Value::Object(kept));
+    }
+    let kept = elements("filter", v).into_iter()
+      .filter(|item| self.apply(lambda, vec![Value::from(item.clone())]).truthy())
+      .collect();
+    return Value::Array(serde_json::Value::Array(kept));
+  }
+
+  // lookup(file, key) finds a member of the file's top level object or
+  // array, lookup(file, field, value) the first element whose field matches
+  fn lookup(&mut self, args: Vec<Value>) -> Value {
+    let (path, field, key) = match args.as_slice() {
+      [path, key] => (format!("{}", path), None, key),
+      [path, field, key] => (format!("{}", path), Some(format!("{}", field)), key),
+      _ => panic!("lookup takes 2 or 3 arguments, got {}", args.len()),
+    };
+
+    let table = self.lookups.entry((path.clone(), field.clone().unwrap_or_default()))
+      .or_insert_with(|| load_lookup(&path, field.as_deref()));
+    return Value::from_opt(table.get(&format!("{}", key)));
+  }
+
+  // select(v, cond) gives v if cond is true and null if not. select(cond)
+  // does the same for the element a lambda was called with, or $ outside
+  // of one. map() keeps the nulls, so map(xs, |x| select(x > 0)) has null
+  // in place of each element that isn't positive, while filter(xs, |x| x > 0)
+  // leaves them out
+  fn select_value(&mut self, args: Vec<Value>) -> Value {
+    let (v, cond) = match args.as_slice() {
+      [cond] => {
+        let subject = self.subjects.last().cloned();
+        (subject.unwrap_or_else(|| self.fields.get("root").unwrap().clone()), cond.clone())
+      },
+      [v, cond] => (v.clone(), cond.clone()),
+      _ => panic!("select takes 1 or 2 arguments, got {}", args.len()),
+    };
+    if cond.truthy() { v } else { Value::Null }
+  }
+
+  fn subsep(&self) -> String {
+    self.variables.borrow().get("SUBSEP").map_or(String::new(), |v| format!("{}", v))
+  }
+
+  // split(s[, sep]) gives the parts of s between each sep, SUBSEP by
+  // default. like awk, a single character is taken literally and anything
+  // longer is a regex, while an empty sep splits s into characters
+  fn split(&mut self, args: Vec<Value>) -> Value {
+    let (s, sep) = match args.as_slice() {
+      [s] => (format!("{}", s), self.subsep()),
+      [s, sep] => (format!("{}", s), format!("{}", sep)),
+      _ => panic!("split takes 1 or 2 arguments, got {}", args.len()),
+    };
+    let parts: Vec<String> = match sep.chars().count() {
+      _ if s.is_empty() => Vec::new(),
+      0 => s.chars().map(String::from).collect(),
+      1 => s.split(sep.as_str()).map(String::from).collect(),
+      _ => self.regex(&sep).split(&s).map(String::from).collect(),
+    };
+    return Value::Array(serde_json::Value::Array(parts.into_iter().map(serde_json::Value::String).collect()));
+  }
+
+  // join(a[, sep]) puts the elements of a together with sep between them,
+  // SUBSEP by default, e.g. to make a composite key
+  fn join(&mut self, args: Vec<Value>) -> Value {
+    let (v, sep) = match args.as_slice() {
+      [v] => (v, self.subsep()),
+      [v, sep] => (v, format!("{}", sep)),
+      _ => panic!("join takes 1 or 2 arguments, got {}", args.len()),
+    };
+    let parts: Vec<String> = elements("join", v).into_iter().map(|item| format!("{}", Value::from(item))).collect();
+    return Value::Str(parts.join(&sep));
+  }
+
+  // paths_matching(v, regex) gives a json pointer to every string, number
+  // or boolean in v that matches
+  fn paths_matching(&mut self, args: Vec<Value>) -> Value {
+    let (v, pattern) = match args.as_slice() {
+      [v, pattern] => (v.to_json(), format!("{}", pattern)),
+      _ => panic!("paths_matching takes 2 arguments, got {}", args.len()),
+    };
+    let regex = self.regex(&pattern);
+    return pointers(&v, |v| match v {
+      serde_json::Value::String(s) => regex.is_match(s),
+      serde_json::Value::Number(_) | serde_json::Value::Bool(_) => regex.is_match(&v.to_string()),
+      _ => false,
+    });
+  }
+
+  // readjson(path) parses a json file the first time it's asked for
+  fn readjson(&mut self, args: Vec<Value>) -> Value {
+    let path = match args.as_slice() {
+      [path] => format!("{}", path),
+      _ => panic!("readjson takes 1 argument, got {}", args.len()),
+    };
+    let doc = self.documents.entry(path.clone()).or_insert_with(|| load_json(&path, "file"));
+    return Value::from(doc.clone());
+  }
+
+  // writejson(path, v[, pretty]) writes v to a file. like print > "file",
+  // the file is emptied by the first write and later values follow it
+  fn writejson(&mut self, args: Vec<Value>) -> Value {
+    let (path, v, pretty) = match args.as_slice() {
+      [path, v] => (format!("{}", path), v, false),
+      [path, v, pretty] => (format!("{}", path), v, pretty.clone().truthy()),
+      _ => panic!("writejson takes 2 or 3 arguments, got {}", args.len()),
+    };
+    let json = if pretty {
+      serde_json::to_string_pretty(&v.to_json()).unwrap()
+    } else {
+      serde_json::to_string(&v.to_json()).unwrap()
+    };
+    self.write_to(&path, &format!("{}\n", json));
+    return Value::Num(0.0);
+  }
+
+  // path(v, "$.a.b[2]") evaluates a selector given as a string, with $ as v
+  fn path(&mut self, args: Vec<Value>) -> Value {
+    let (v, path) = match args.as_slice() {
+      [v, path] => (v.clone(), format!("{}", path)),
+      _ => panic!("path takes 2 arguments, got {}", args.len()),
+    };
+
+    let code = self.paths.entry(path.clone()).or_insert_with(|| {
+      Compiler::new(Lexer::new(&path)).compile_expression().unwrap_or_else(|errors| {
+        panic!("invalid path '{}': {}", path, errors[0].message)
+      })
+    }).clone();
+
+    let root = self.fields.insert(String::from("root"), v);
+    self.eval(&code);
+    if let Some(root) = root {
+      self.fields.insert(String::from("root"), root);
+    }
+    return self.pop();
+  }
+
+  // schema_validate(v, file) checks v against a json schema, leaving the
+  // problems found in SCHEMA_ERRORS
+  fn schema_validate(&mut self, args: Vec<Value>) -> Value {
+    let (v, path) = match args.as_slice() {
+      [v, path] => (v.to_json(), format!("{}", path)),
+      _ => panic!("schema_validate takes 2 arguments, got {}", args.len()),
+    };
+
+    let schema = self.schemas.entry(path.clone()).or_insert_with(|| Rc::new(Schema::load(&path))).clone();
+    let valid = self.set_schema_errors(schema.validate(&v));
+    return Value::Num(if valid { 1.0 } else { 0.0 });
+  }
+
+  // index(s, t) is where t first appears in s, counting from 1, or 0
+  fn index(&mut self, args: Vec<Value>) -> Value {
+    let (mut s, mut t) = match args.as_slice() {
+      [s, t] => (format!("{}", s), format!("{}", t)),
+      _ => panic!("index takes 2 arguments, got {}", args.len()),
+    };
+    if self.ignore_case() {
+      s = s.to_lowercase();
+      t = t.to_lowercase();
+    }
+    return Value::Num(s.find(&t).map_or(0.0, |i| (s[..i].chars().count() + 1) as f64));
+  }
+}
@@ -1,13 +1,13 @@
+#![allow(clippy::needless_return)]
+
 use std::io::Write;
 use std::env;
+use std::fs;
 use std::path::PathBuf;
 use std::process::{Command, Stdio};
 
 fn jqawk_exe() -> PathBuf {
-  env::current_exe().unwrap()
-    .parent()
-    .expect("exe dir")
-    .join("jqawk")
+  PathBuf::from(env!("CARGO_BIN_EXE_jqawk"))
 }
 
 fn run(args: &[&str]) -> String {
@@ -44,6 +44,40 @@ fn run_stdin(args: &[&str], stdin: &str) -> String {
   return String::from_utf8_lossy(&output.stdout).to_string();
 }
 
+fn run_err(args: &[&str]) -> String {
+  let output = Command::new(jqawk_exe())
+    .args(args)
+    .output()
+    .expect("Failed to execute jqawk");
+
+  if output.status.success() {
+    panic!("jqawk succeeded: {}", String::from_utf8_lossy(&output.stdout));
+  }
+
+  return String::from_utf8_lossy(&output.stderr).to_string();
+}
+
+fn run_stdin_err(args: &[&str], stdin: &str) -> String {
+  let mut child = Command::new(jqawk_exe())
+    .args(args)
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .expect("error spawning jqawk");
+
+  child.stdin.as_mut().unwrap().write_all(stdin.as_bytes())
+    .expect("could not write to child stdin");
+
+  let output = child.wait_with_output().expect("error reading child stdout");
+
+  if output.status.success() {
+    panic!("jqawk succeeded: {}", String::from_utf8_lossy(&output.stdout));
+  }
+
+  return String::from_utf8_lossy(&output.stderr).to_string();
+}
+
 #[test]
 fn begin_and_end() {
   let program = "\
@@ -116,6 +150,1529 @@ BEGIN {
   assert_eq!(output, "6\n1\n8\n2\n");
 }
 
+#[test]
+fn compile_cache() {
+  let dir = env::temp_dir().join(format!("jqawk-cache-{}", std::process::id()));
+  let dir_str = dir.to_str().unwrap();
+  let program = "$.age > 50 { print $.name }";
+
+  let first = run(&["--compile-cache", dir_str, program, "test.json"]);
+  assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+  let second = run(&["--compile-cache", dir_str, program, "test.json"]);
+  fs::remove_dir_all(&dir).unwrap();
+
+  assert_eq!(first, "medium tony\nwell done tony\n");
+  assert_eq!(second, first);
+}
+
+#[test]
+fn syntax_errors() {
+  let program = "\
+{ print 1 +; print 2 }
+{ print \"oops
+  print @ }";
+  let output = run_err(&[program, "test.json"]);
+  let expected = "\
+error on line 1, column 12: unexpected prefix ;
+error on line 2, column 9: unterminated string
+error on line 3, column 9: unexpected character '@'
+";
+  assert_eq!(output, expected);
+}
+
+#[test]
+fn max_depth() {
+  let output = run_stdin(&["--max-depth", "3", "{ print $[0] }"], "[[[1]], [\"[[[[\"]]");
+  assert_eq!(output, "[1]\n[[[[\n");
+
+  // an error rather than a crash
+  let output = run_stdin_err(&["--max-depth", "3", "{ print }"], "[\n[[[1]]]]");
+  assert_eq!(output, "error: input is nested more than 3 levels deep on line 2\n");
+
+  // the limit can go up to what's safe to parse, copy and print
+  let deepest = format!("[[{}1{}]]", "{\"a\": [".repeat(999), "]}".repeat(999));
+  let program = "{ x = $; y[1] = $; print eq(merge(x, $), $) > \"/dev/null\"; print cmp(x, $) }";
+  let output = Command::new(jqawk_exe())
+    .args(["--max-depth", "2000", program])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .and_then(|mut child| {
+      child.stdin.take().unwrap().write_all(deepest.as_bytes())?;
+      child.wait_with_output()
+    })
+    .unwrap();
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "0\n");
+  assert_eq!(String::from_utf8_lossy(&output.stderr), "");
+
+  let too_deep = format!("[{}]", deepest);
+  let output = run_stdin_err(&["--max-depth", "2000", "{ print }"], &too_deep);
+  assert_eq!(output, "error: input is nested more than 2000 levels deep on line 1\n");
+  let output = run_err(&["--max-depth", "2001", "{ print }", "test.json"]);
+  assert_eq!(output, "--max-depth can be at most 2000, as nested values are handled recursively\n");
+}
+
+#[test]
+fn recursive_descent() {
+  let input = "{ \"price\": 1, \"items\": [{ \"price\": 2 }, { \"box\": { \"price\": 3 } }] }";
+  let output = run_stdin(&["-r", "$..price", "{ print }"], input);
+  assert_eq!(output, "1\n2\n3\n");
+
+  let output = run_stdin(&["BEGIN { print $.items..price }"], input);
+  assert_eq!(output, "[2,3]\n");
+
+  let output = run_stdin(&["BEGIN { print $..box.price }"], input);
+  assert_eq!(output, "[3]\n");
+}
+
+#[test]
+fn wildcards() {
+  let input = "{ \"regions\": [
+    { \"name\": \"north\", \"cities\": [\"a\", \"b\"] },
+    { \"name\": \"south\", \"cities\": [\"c\"] }
+  ] }";
+  let output = run_stdin(&["-r", "$.regions[*].cities", "{ print }"], input);
+  assert_eq!(output, "[\"a\",\"b\"]\n[\"c\"]\n");
+
+  let output = run_stdin(&["BEGIN { print $.*[*].name }"], input);
+  assert_eq!(output, "[\"north\",\"south\"]\n");
+
+  let output = run_stdin(&["BEGIN { print $.regions[*].cities[*] }"], input);
+  assert_eq!(output, "[\"a\",\"b\",\"c\"]\n");
+}
+
+#[test]
+fn slices() {
+  let input = "[0, 1, 2, 3, 4, 5]";
+  assert_eq!(run_stdin(&["-r", "$[2:4]", "{ print }"], input), "2\n3\n");
+  assert_eq!(run_stdin(&["-r", "$[:2]", "{ print }"], input), "0\n1\n");
+  assert_eq!(run_stdin(&["-r", "$[4:]", "{ print }"], input), "4\n5\n");
+  assert_eq!(run_stdin(&["-r", "$[3:100]", "END { print NR }"], input), "3\n");
+
+  let input = "[{ \"n\": \"a\" }, { \"n\": \"b\" }, { \"n\": \"c\" }]";
+  assert_eq!(run_stdin(&["BEGIN { print $[1:].n }"], input), "[\"b\",\"c\"]\n");
+}
+
+#[test]
+fn selector_union() {
+  let input = "{ \"errors\": [\"e1\", \"e2\"], \"warnings\": [\"w1\"] }";
+  let output = run_stdin(&["-r", "$.errors, $.warnings", "{ print NR, SELECTOR, $ }"], input);
+  assert_eq!(output, "1 $.errors e1\n2 $.errors e2\n3 $.warnings w1\n");
+
+  let output = run_stdin(&["-r", "$.errors,$.warnings", "END { print $ }"], input);
+  assert_eq!(output, "[[\"e1\",\"e2\"],[\"w1\"]]\n");
+}
+
+#[test]
+fn selector_filters() {
+  let input = "{ \"items\": [
+    { \"type\": \"order\", \"id\": 1 },
+    { \"type\": \"refund\", \"id\": 2 },
+    { \"type\": \"order\", \"id\": 3 }
+  ] }";
+  let output = run_stdin(&["-r", "$.items[? $.type == \"order\"]", "{ print $.id }"], input);
+  assert_eq!(output, "1\n3\n");
+
+  let output = run_stdin(&["BEGIN { print $.items[? $.id > 1].type }"], input);
+  assert_eq!(output, "[\"refund\",\"order\"]\n");
+}
+
+#[test]
+fn record_keys() {
+  let input = "{ \"alice\": { \"total\": 3 }, \"bob\": { \"total\": 5 } }";
+  let output = run_stdin(&["{ print KEY, INDEX, $.total }"], input);
+  assert_eq!(output, "alice 0 3\nbob 1 5\n");
+
+  let output = run_stdin(&["{ print KEY, INDEX }"], "[\"a\", \"b\"]");
+  assert_eq!(output, "0 0\n1 1\n");
+}
+
+#[test]
+fn document_stream() {
+  let input = "\
+{ \"event\": \"a\", \"payload\": [1, 2] }
+{ \"event\": \"b\", \"payload\": [3] }
+";
+  // each document's selection is a record, rather than its members
+  let output = run_stdin(&["-r", "$.payload", "{ print NR, KEY, $ } END { print $ }"], input);
+  assert_eq!(output, "1 0 [1,2]\n2 1 [3]\n[3]\n");
+
+  let input = "{\"payload\": {\"x\": 1}}\n{\"payload\": {\"x\": 2}}\n";
+  assert_eq!(run_stdin(&["-r", "$.payload", "{ print NR, $.x }"], input), "1 1\n2 2\n");
+  assert_eq!(run_stdin(&["{ print NR, $.payload.x }"], input), "1 1\n2 2\n");
+
+  let input = "{\"n\": 1}\n{\"n\": \"two\"}\n";
+  assert_eq!(run_stdin(&["-r", "$.n", "{ print NR, $ }"], input), "1 1\n2 two\n");
+  assert_eq!(run_stdin(&["{ n += $ } END { print n, NR }"], "1\n2\n3\n"), "6 3\n");
+
+  let output = run_stdin(&["END { print NR }"], "");
+  assert_eq!(output, "0\n");
+}
+
+#[test]
+fn selector_variables() {
+  let input = "{ \"shards\": [[\"a\"], [\"b\", \"c\"]] }";
+  let output = run_stdin(&["-v", "shard=1", "-r", "$.shards[shard]", "{ print }"], input);
+  assert_eq!(output, "b\nc\n");
+
+  let output = Command::new(jqawk_exe())
+    .args(["-r", "$.shards[ENVIRON[\"SHARD\"]]", "{ print }"])
+    .env("SHARD", "0")
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .spawn()
+    .and_then(|mut child| {
+      child.stdin.as_mut().unwrap().write_all(input.as_bytes())?;
+      child.wait_with_output()
+    })
+    .expect("error running jqawk");
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "a\n");
+}
+
+#[test]
+fn test_runner() {
+  let spec = "\
+=== selects names
+--- args
+-r
+$.items
+--- program
+{ print $.name }
+--- input
+{ \"items\": [{ \"name\": \"a\" }, { \"name\": \"b\" }] }
+--- output
+a
+b
+
+=== adds
+--- program
+BEGIN { print 1 + 1 }
+--- output
+2
+";
+  let path = env::temp_dir().join(format!("jqawk-spec-{}.jqawk-test", std::process::id()));
+  fs::write(&path, spec).unwrap();
+  let output = run(&["--test", path.to_str().unwrap()]);
+  assert_eq!(output, "PASS selects names\nPASS adds\n2 passed, 0 failed\n");
+
+  fs::write(&path, spec.replace("2\n", "3\n")).unwrap();
+  let output = Command::new(jqawk_exe())
+    .args(["--test", path.to_str().unwrap()])
+    .output()
+    .expect("error running jqawk");
+  fs::remove_file(&path).unwrap();
+  assert!(!output.status.success());
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "\
+PASS selects names
+FAIL adds
+  expected:
+    3
+  got:
+    2
+1 passed, 1 failed
+");
+}
+
+#[test]
+fn test_runner_program_file() {
+  let dir = env::temp_dir().join(format!("jqawk-spec-dir-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  fs::write(dir.join("names.jqawk"), "{ print $.name }\n").unwrap();
+  let spec = "\
+=== runs a program file next to the spec
+--- args
+-f
+names.jqawk
+--- input
+[{ \"name\": \"a\" }, { \"name\": \"b\" }]
+--- output
+a
+b
+
+=== output without a newline
+--- args
+-j
+--- program
+BEGIN { print \"x\" }
+--- output no-newline
+x
+";
+  let path = dir.join("names.jqawk-test");
+  fs::write(&path, spec).unwrap();
+  let output = run(&["--test", path.to_str().unwrap()]);
+  assert_eq!(output, "PASS runs a program file next to the spec\nPASS output without a newline\n2 passed, 0 failed\n");
+
+  // the newline is part of the expected output otherwise
+  fs::write(&path, spec.replace(" no-newline", "")).unwrap();
+  let output = Command::new(jqawk_exe())
+    .args(["--test", path.to_str().unwrap()])
+    .output()
+    .expect("error running jqawk");
+  fs::remove_dir_all(&dir).unwrap();
+  assert!(!output.status.success());
+  assert!(String::from_utf8_lossy(&output.stdout).contains("FAIL output without a newline"));
+}
+
+#[test]
+fn lint() {
+  let program = "\
+BEGIN { print y }
+x = 1 { print }
+1 > 2 { print }
+{ n = n + 1; unused = 2 }
+END { print n, NR }";
+  let output = run_err(&["--lint", program]);
+  let expected = "\
+warning on line 1: variable 'y' is read before it is assigned
+warning on line 2: assignment in pattern, did you mean ==?
+warning on line 3: pattern is always false
+warning on line 4: variable 'unused' is assigned but never read
+";
+  assert_eq!(output, expected);
+
+  let program = "\
+/^a|b$/ && $.s ~ /(?m)a\\n^b/ { print }
+/a^b/ { print }
+$.s !~ \"x$y\" { print }
+{ re = /[a&&b]/; print $.s ~ re }";
+  let output = run_err(&["--lint", program]);
+  let expected = "\
+warning on line 2: regex /a^b/ can never match
+warning on line 3: regex /x$y/ can never match
+warning on line 4: regex /[a&&b]/ can never match
+";
+  assert_eq!(output, expected);
+
+  assert_eq!(run(&["--lint", "-v", "y=1", "BEGIN { print y }"]), "");
+}
+
+#[test]
+fn fmt() {
+  let program = "BEGIN{total=0}
+
+
+$.age>50&&$.name==\"x\"{ print $.name,$[0] ;total=total+$.age}
+{print $.items[?$.type==\"a\"].*;}   END   {print total;}";
+  let expected = "\
+BEGIN { total = 0 }
+
+$.age > 50 && $.name == \"x\" {
+  print $.name, $[0];
+  total = total + $.age;
+}
+{ print $.items[? $.type == \"a\"].* }
+END { print total }
+";
+  let output = run(&["--fmt", program]);
+  assert_eq!(output, expected);
+  assert_eq!(run(&["--fmt", &output]), expected);
+}
+
+#[test]
+fn json_diagnostics() {
+  let output = run_err(&["--diagnostics", "json", "{ print @ }", "test.json"]);
+  assert_eq!(output, "{\"file\":null,\"line\":1,\"column\":9,\"message\":\"unexpected character '@'\",\"severity\":\"error\"}\n");
+
+  let output = run_stdin_err(&["--diagnostics", "json", "{ print $.age.x }"], "[{ \"age\": 1 }]");
+  assert_eq!(output, "{\"file\":null,\"line\":1,\"column\":9,\"message\":\"can only access members on objects or arrays, found number\",\"severity\":\"error\",\"expression\":\"$.age.x\"}\n");
+}
+
+#[test]
+fn explain() {
+  let program = "\
+$.age > 50 { print $.name }
+$.agee > 50 { print \"typo\" }
+END { print NR }";
+  let output = Command::new(jqawk_exe())
+    .args(["--explain", program, "test.json"])
+    .output()
+    .expect("error running jqawk");
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "medium tony\nwell done tony\n3\n");
+  assert_eq!(String::from_utf8_lossy(&output.stderr), "      2  $.age > 50 { print $.name }
+!     0  $.agee > 50 { print \"typo\" }
+      1  END { print NR }
+");
+}
+
+#[test]
+fn nfields() {
+  let input = "[[1, 2, 3], { \"a\": 1, \"b\": 2 }, \"str\"]";
+  assert_eq!(run_stdin(&["{ print NFIELDS }"], input), "3\n2\n0\n");
+}
+
+#[test]
+fn record_provenance() {
+  let input = "[1]\n[2]\n\n  [\n3]\n";
+  assert_eq!(
+    run_stdin(&["{ print $[0], OFFSET, FLINE, FILENAME == \"\" }"], input),
+    "1 0 1 1\n2 4 2 1\n3 11 4 1\n",
+  );
+
+  let dir = std::env::temp_dir().join(format!("jqawk-provenance-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let path = dir.join("input.json");
+  std::fs::write(&path, "{ \"a\": 1 }").unwrap();
+  assert_eq!(run(&["{ print FILENAME, FLINE }", path.to_str().unwrap()]), format!("{} 1\n", path.display()));
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn sampling() {
+  let input = "[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]";
+  assert_eq!(run_stdin(&["--sample", "1", "{ print }"], input), "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n");
+  assert_eq!(run_stdin(&["--sample", "0", "{ print } END { print NR }"], input), "0\n");
+  assert_eq!(run_stdin(&["--sample-n", "20", "END { print NR }"], input), "10\n");
+
+  let sampled = run_stdin(&["--sample-n", "3", "--seed", "7", "{ print }"], input);
+  let picked: Vec<i32> = sampled.lines().map(|l| l.parse().unwrap()).collect();
+  assert_eq!(picked.len(), 3);
+  assert!(picked.windows(2).all(|w| w[0] < w[1]));
+  assert_eq!(run_stdin(&["--sample-n", "3", "--seed", "7", "{ print }"], input), sampled);
+
+  let rate = run_stdin(&["--sample", "0.5", "--seed", "7", "END { print NR }"], input);
+  assert_eq!(run_stdin(&["--sample", "0.5", "--seed", "7", "END { print NR }"], input), rate);
+}
+
+#[test]
+fn previous_records() {
+  let input = "[{ \"t\": 1 }, { \"t\": 4 }, { \"t\": 10 }]";
+  assert_eq!(run_stdin(&["NR > 1 { print $.t - PREV[1].t }"], input), "3\n6\n");
+  assert_eq!(
+    run_stdin(&["--window", "2", "{ print PREV[0], PREV[1], PREV[2] }"], "[1, 2, 3, 4]"),
+    "1 0 0\n2 1 0\n3 2 1\n4 3 2\n",
+  );
+}
+
+#[test]
+fn aggregation() {
+  let input = "[
+    { \"region\": \"north\", \"sales\": 5 },
+    { \"region\": \"south\", \"sales\": 2 },
+    { \"region\": \"north\", \"sales\": 1 }
+  ]";
+  let output = run_stdin(&["--group-by", "$.region", "--agg", "sum($.sales), count(), avg($.sales), max($.sales)"], input);
+  assert_eq!(output, "north 6 2 3 5\nsouth 2 1 2 2\n");
+  assert_eq!(run_stdin(&["--group-by", "$.region"], input), "north 2\nsouth 1\n");
+  assert_eq!(run_stdin(&["--agg", "sum($.sales), min($.sales)"], input), "8 1\n");
+  assert_eq!(run_stdin(&["--agg", "count()"], "[]"), "0\n");
+
+  let output = run_stdin_err(&["--agg", "total($.sales)"], input);
+  assert_eq!(output, "error on line 1, column 1: unknown aggregate total\n");
+}
+
+#[test]
+fn lookup_join() {
+  let dir = env::temp_dir().join(format!("jqawk-lookup-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let users = dir.join("users.json");
+  fs::write(&users, "[{ \"id\": 1, \"name\": \"ann\" }, { \"id\": 2, \"name\": \"bo\" }]").unwrap();
+  let users = users.to_str().unwrap();
+
+  let input = "[{ \"user\": 2, \"amount\": 5 }, { \"user\": 1, \"amount\": 3 }, { \"user\": 9, \"amount\": 1 }]";
+  let program = format!("{{ u = lookup(\"{}\", \"id\", $.user); print $.amount, u }}", users);
+  let output = run_stdin(&[&program], input);
+  assert_eq!(output, "5 {\"id\":2,\"name\":\"bo\"}\n3 {\"id\":1,\"name\":\"ann\"}\n1 0\n");
+
+  let program = format!("BEGIN {{ print lookup(\"{}\", 0).name }}", users);
+  assert_eq!(run_stdin(&[&program], "{}"), "ann\n");
+  fs::remove_dir_all(&dir).unwrap();
+
+  let output = run_err(&["BEGIN { nope(1) }"]);
+  assert_eq!(output, "error on line 1, column 9: unknown function nope\n");
+}
+
+#[test]
+fn diff_mode() {
+  let dir = env::temp_dir().join(format!("jqawk-diff-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let a = dir.join("a.json");
+  let b = dir.join("b.json");
+  fs::write(&a, "{ \"db\": { \"host\": \"a\", \"port\": 5432 }, \"flags\": [1, 2], \"old\": true }").unwrap();
+  fs::write(&b, "{ \"db\": { \"host\": \"b\", \"port\": 5432 }, \"flags\": [1], \"new\": \"x\" }").unwrap();
+  let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+
+  // like diff, differences are an exit code of 1
+  let output = Command::new(jqawk_exe()).args(["diff", a, b]).output().unwrap();
+  assert_eq!(output.status.code(), Some(1));
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "~ $.db.host: \"a\" -> \"b\"\n- $.flags[1]: 2\n- $.old: true\n+ $.new: \"x\"\n");
+  assert_eq!(run(&["diff", a, a]), "");
+  assert_eq!(run(&["diff", "-r", "$.db.port", a, b]), "");
+
+  let output = run(&["diff", "-r", "$.db", a, b, "$A == $B { print KEY, \"unchanged\" }"]);
+  assert_eq!(output, "port unchanged\n");
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dynamic_paths() {
+  let input = "{ \"a\": { \"b\": [1, 2, { \"c\": 3 }] }, \"ptr\": \"/a/b/2/c\", \"sel\": \"$.a.b[1]\" }";
+  let output = run_stdin(&["BEGIN { print pointer($, \"/a/b/0\"), pointer($, $.ptr), pointer($, \"/x\") }"], input);
+  assert_eq!(output, "1 3 0\n");
+
+  let output = run_stdin(&["BEGIN { print path($, $.sel), path($.a, \"$.b[2].c\") }"], input);
+  assert_eq!(output, "2 3\n");
+}
+
+#[test]
+fn schema_validation() {
+  let dir = env::temp_dir().join(format!("jqawk-schema-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let schema = dir.join("schema.json");
+  fs::write(&schema, "{
+    \"type\": \"object\",
+    \"required\": [\"name\"],
+    \"properties\": { \"name\": { \"type\": \"string\" }, \"age\": { \"type\": \"integer\", \"minimum\": 0 } },
+    \"additionalProperties\": false
+  }").unwrap();
+  let records = dir.join("records.json");
+  fs::write(&records, "[{ \"name\": \"a\", \"age\": 3 }, { \"name\": 1, \"age\": -1 }, { \"extra\": true }]").unwrap();
+  let (schema, records) = (schema.to_str().unwrap(), records.to_str().unwrap());
+
+  let output = run_err(&["--schema", schema, records]);
+  assert!(output.is_empty());
+  let output = Command::new(jqawk_exe()).args(["--schema", schema, records]).output().unwrap();
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "\
+record 2: $.age: -1 is less than 0
+record 2: $.name: expected string, got number
+record 3: $: missing required property name
+record 3: $.extra: unexpected property
+");
+
+  let output = run(&["--schema", schema, "VALID { print $.name }", records]);
+  assert_eq!(output, "a\n");
+
+  let program = format!("{{ print schema_validate($, \"{}\"), SCHEMA_ERRORS }}", schema);
+  let output = run(&["-r", "$[:2]", &program, records]);
+  assert_eq!(output, "1 []\n0 [\"$.age: -1 is less than 0\",\"$.name: expected string, got number\"]\n");
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn merge_patch() {
+  let input = "{
+    \"defaults\": { \"a\": 1, \"b\": { \"x\": 1, \"y\": 2 }, \"c\": [1] },
+    \"patch\": { \"a\": null, \"b\": { \"y\": 3 }, \"c\": [2], \"d\": \"new\" }
+  }";
+  let output = run_stdin(&["BEGIN { print merge($.defaults, $.patch) }"], input);
+  assert_eq!(output, "{\"b\":{\"x\":1,\"y\":3},\"c\":[2],\"d\":\"new\"}\n");
+  assert_eq!(run_stdin(&["BEGIN { print merge($.defaults, 5) }"], input), "5\n");
+}
+
+#[test]
+fn table_output() {
+  let input = "[{ \"name\": \"alice\", \"age\": 30 }, { \"name\": \"bob\", \"age\": 7, \"city\": \"paris\" }]";
+  let output = run_stdin(&["--to", "table", "{ print }"], input);
+  assert_eq!(output, "\
+age  name   city
+---  -----  -----
+ 30  alice
+  7  bob    paris
+");
+
+  let output = run_stdin(&["--to", "table", "{ print $.name, $.age }"], input);
+  assert_eq!(output, "alice  30\nbob     7\n");
+}
+
+#[test]
+fn defines() {
+  let program = "\
+define ASIA = $.continent == \"Asia\"
+define BIG = $.population > 100
+ASIA { print $.country }
+BIG && ASIA { print \"big\", $.country }";
+  let input = "[
+    { \"continent\": \"Asia\", \"country\": \"Japan\", \"population\": 125 },
+    { \"continent\": \"Europe\", \"country\": \"Sweden\", \"population\": 10 },
+    { \"continent\": \"Asia\", \"country\": \"Laos\", \"population\": 7 }
+  ]";
+  assert_eq!(run_stdin(&[program], input), "Japan\nbig Japan\nLaos\n");
+
+  let output = run_err(&["define A = 1\ndefine A = 2\nBEGIN { A = 3 }"]);
+  assert_eq!(output, "\
+error on line 2, column 8: A is already defined
+error on line 3, column 9: cannot assign to A, it is defined
+");
+}
+
+#[test]
+fn constant_folding() {
+  let output = run_stdin(&["$ == 60 * 60 * 24 { print 1 + 2 * 3, 10 - 4 / 2 } 2 > 1 { print \"always\" }"], "[86400]");
+  assert_eq!(output, "7 8\nalways\n");
+
+  let output = run_stdin(&["-r", "$[? $.n > 2 * 2]", "{ print $.n }"], "[{ \"n\": 3 }, { \"n\": 5 }]");
+  assert_eq!(output, "5\n");
+
+  let output = run_err(&["--lint", "1 > 2 * 3 { print }"]);
+  assert_eq!(output, "warning on line 1: pattern is always false\n");
+}
+
+#[test]
+fn printf_json() {
+  let input = "[{ \"a\": 1, \"b\": [1, 2] }]";
+  let output = run_stdin(&["{ printf \"record %d: %j\\n\", NR, $ }"], input);
+  assert_eq!(output, "record 1: {\"a\":1,\"b\":[1,2]}\n");
+
+  let output = run_stdin(&["{ printf \"%J\\n\", $.b }"], input);
+  assert_eq!(output, "[\n  1,\n  2\n]\n");
+
+  let output = run_stdin(&["BEGIN { printf \"%x %e %g %c %5.2s|%%\\n\", 255, 1234, 1234567, 65, \"abc\" }"], "[]");
+  assert_eq!(output, "ff 1.234000e+03 1.23457e+06 A    ab|%\n");
+}
+
+#[test]
+fn sprintf() {
+  let input = "[{ \"host\": \"a\", \"port\": 80 }, { \"host\": \"b\", \"port\": 8080 }]";
+  let output = run_stdin(&["{ key = sprintf(\"%s:%05d\", $.host, $.port); print key, sprintf(\"%j\", $) }"], input);
+  assert_eq!(output, "a:00080 {\"host\":\"a\",\"port\":80}\nb:08080 {\"host\":\"b\",\"port\":8080}\n");
+}
+
+#[test]
+fn ignore_case() {
+  let input = "[\"Apple\", \"banana\", \"APRICOT\"]";
+  let output = run_stdin(&["$ ~ \"^Ap\" { print \"match\", $ } $ !~ \"an\" { print \"no an\", $ }"], input);
+  assert_eq!(output, "match Apple\nno an Apple\nno an APRICOT\n");
+
+  let program = "BEGIN { IGNORECASE = 1 } $ ~ \"^ap\" { print \"match\", $ } $ == \"apple\" { print \"equal\" } { print index($, \"P\") }";
+  let output = run_stdin(&[program], input);
+  assert_eq!(output, "match Apple\nequal\n2\n0\nmatch APRICOT\n2\n");
+
+  let output = run_stdin(&["{ print index($, \"P\") }"], input);
+  assert_eq!(output, "0\n0\n2\n");
+  assert_eq!(run(&["--lint", "BEGIN { IGNORECASE = 1 }"]), "");
+
+  // literals aren't compared until IGNORECASE is known
+  let output = run(&["BEGIN { print \"a\" == \"A\", \"a\" != \"A\"; IGNORECASE = 1; print \"a\" == \"A\", \"a\" != \"A\" }"]);
+  assert_eq!(output, "0 1\n1 0\n");
+}
+
+#[test]
+fn user_error() {
+  let mut child = Command::new(jqawk_exe())
+    .args(["{ print $ } $ == 2 { error(\"unexpected record\", 3) }"])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .expect("error spawning jqawk");
+  child.stdin.as_mut().unwrap().write_all(b"[1, 2, 3]").unwrap();
+  let output = child.wait_with_output().expect("error reading child stdout");
+  assert_eq!(output.status.code(), Some(3));
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n2\n");
+  assert_eq!(String::from_utf8_lossy(&output.stderr), "error: unexpected record\n");
+
+  let stderr = run_stdin_err(&["--diagnostics", "json", "{ error(\"bad\") }"], "[1]");
+  assert_eq!(stderr, "{\"file\":null,\"line\":null,\"column\":null,\"message\":\"bad\",\"severity\":\"error\"}\n");
+}
+
+#[test]
+fn warnings_to_stderr() {
+  let mut child = Command::new(jqawk_exe())
+    .args(["$.n > 1 { warn(\"big\", $.n); warn() } { print $.n }"])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .expect("error spawning jqawk");
+  child.stdin.as_mut().unwrap().write_all(b"[{\"n\": 1}, {\"n\": 2}]").unwrap();
+  let output = child.wait_with_output().expect("error reading child stdout");
+  assert!(output.status.success());
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n2\n");
+  assert_eq!(String::from_utf8_lossy(&output.stderr), "big 2\n{\"n\":2}\n");
+}
+
+#[test]
+fn lambdas() {
+  let input = "[{\"items\": [{\"price\": 2, \"qty\": 3}, {\"price\": 5, \"qty\": 0}]}]";
+  let program = "{ print map($.items, |x| x.price * x.qty) }
+{ print filter($.items, |x| x.qty > 0) }
+{ print reduce(map($.items, |x| x.price), 0, |acc, x| acc + x) }
+{ print map($.items[0], |v| v * 10) }";
+  let output = run_stdin(&[program], input);
+  assert_eq!(output, "[6,0]\n[{\"price\":2,\"qty\":3}]\n7\n{\"price\":20,\"qty\":30}\n");
+
+  let err = run_err(&["{ print map($.items) }"]);
+  assert!(err.contains("map needs a function"));
+  assert_eq!(run(&["--fmt", "{ print map($,|x|x*2) }"]), "{ print map($, |x| x * 2) }\n");
+}
+
+#[test]
+fn pipes() {
+  let input = "[{\"items\": [{\"price\": 2, \"qty\": 3}, {\"price\": 5, \"qty\": 0}, {\"price\": 1, \"qty\": 4}]}]";
+  let program = "{ print $.items |> filter(|x| x.qty > 0) |> map(|x| x.price * x.qty) |> reduce(0, |a, x| a + x) }";
+  assert_eq!(run_stdin(&[program], input), "10\n");
+  let output = run_stdin(&["{ print $.items |> map(|x| x.qty) |> reduce(0, |a, x| a + x) == 7 }"], input);
+  assert_eq!(output, "1\n");
+  assert_eq!(run(&["--fmt", "{ print $.a|>map(|x|x) }"]), "{ print $.a |> map(|x| x) }\n");
+}
+
+#[test]
+fn destructuring() {
+  let input = "[{\"name\": \"a\", \"age\": 3, \"pair\": [1, 2]}, {\"name\": \"b\", \"pair\": [9]}]";
+  let program = "{ let {name, age: years} = $; [first, second] = $.pair; print name, years, first, second }";
+  assert_eq!(run_stdin(&[program], input), "a 3 1 2\nb 0 9 0\n");
+  assert_eq!(run(&["--fmt", "{ let {name,age:years}=$ }"]), "{ let {name, age: years} = $ }\n");
+  let output = run_err(&["--lint", "{ let {name, age} = $; print name }"]);
+  assert_eq!(output, "warning on line 1: variable 'age' is assigned but never read\n");
+}
+
+#[test]
+fn raw_input() {
+  assert_eq!(run_stdin(&["-R", "{ print NR, $ }"], "a\nb\n"), "1 a\n2 b\n");
+  let input = "\nfrom: x\nhi\n\n\nfrom: y\nbye\n";
+  let output = run_stdin(&["-R", "--record-sep", "", "{ print NR, $ }"], input);
+  assert_eq!(output, "1 from: x\nhi\n2 from: y\nbye\n");
+  assert_eq!(run_stdin(&["-R", "--record-sep", ";", "{ print NR, $ }"], "a;;b;"), "1 a\n2 \n3 b\n");
+  assert_eq!(run_stdin(&["-R", "--record-sep=-+", "{ print $ }"], "a--b---c"), "a\nb\nc\n");
+}
+
+#[test]
+fn argfile() {
+  let path = env::temp_dir().join(format!("jqawk-argfile-{}.json", std::process::id()));
+  fs::write(&path, "{\"thresholds\": {\"max\": 5}}").unwrap();
+  let path = path.to_str().unwrap();
+  let output = run_stdin(&["--argfile", "cfg", path, "$ > cfg.thresholds.max { print $ }"], "[3, 7, 9]");
+  assert_eq!(output, "7\n9\n");
+  assert_eq!(run(&["--argfile", "cfg", path, "--lint", "BEGIN { print cfg.thresholds }"]), "");
+  fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn stdin_between_files() {
+  let a = env::temp_dir().join(format!("jqawk-a-{}.json", std::process::id()));
+  let b = env::temp_dir().join(format!("jqawk-b-{}.json", std::process::id()));
+  fs::write(&a, "[1]").unwrap();
+  fs::write(&b, "[3]").unwrap();
+  let (a, b) = (a.to_str().unwrap(), b.to_str().unwrap());
+  let output = run_stdin(&["{ print FILENAME == \"-\", $ }", a, "-", b], "[2]");
+  assert_eq!(output, "0 1\n1 2\n0 3\n");
+  assert_eq!(run(&["-f", "/dev/null", a, b]), "");
+  fs::remove_file(a).unwrap();
+  fs::remove_file(b).unwrap();
+}
+
+#[test]
+fn output_redirection() {
+  let path = env::temp_dir().join(format!("jqawk-big-{}.txt", std::process::id()));
+  let program = format!("$ > 1 {{ print > \"{}\" }} {{ eprint \"saw\", $; printf \"%d,\", $ > \"/dev/stdout\" }}", path.display());
+  let mut child = Command::new(jqawk_exe())
+    .args([program.as_str()])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .expect("error spawning jqawk");
+  child.stdin.as_mut().unwrap().write_all(b"[1, 2, 3]").unwrap();
+  let output = child.wait_with_output().expect("error reading child stdout");
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "1,2,3,");
+  assert_eq!(String::from_utf8_lossy(&output.stderr), "saw 1\nsaw 2\nsaw 3\n");
+  assert_eq!(fs::read_to_string(&path).unwrap(), "2\n3\n");
+  fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn getline() {
+  let path = env::temp_dir().join(format!("jqawk-ids-{}.txt", std::process::id()));
+  fs::write(&path, "x\r\ny\n").unwrap();
+  let program = format!("{{ r = getline id < \"{0}\"; print $, r, id }} END {{ getline < \"{0}\"; print (getline < \"/no/such/file\") + 5 }}", path.display());
+  let output = run_stdin(&[program.as_str()], "[1, 2, 3]");
+  assert_eq!(output, "1 1 x\n2 1 y\n3 0 y\n4\n");
+  fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn readjson() {
+  let path = env::temp_dir().join(format!("jqawk-readjson-{}.json", std::process::id()));
+  fs::write(&path, "{\"a\": {\"limit\": 2}}").unwrap();
+  let program = format!("$.n > readjson(\"{0}\")[$.k].limit {{ print $.n }}", path.display());
+  let output = run_stdin(&[program.as_str()], "[{\"k\": \"a\", \"n\": 1}, {\"k\": \"a\", \"n\": 3}]");
+  assert_eq!(output, "3\n");
+  fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn writejson() {
+  let a = env::temp_dir().join(format!("jqawk-write-a-{}.json", std::process::id()));
+  let b = env::temp_dir().join(format!("jqawk-write-b-{}.json", std::process::id()));
+  fs::write(&a, "old").unwrap();
+  let input = format!("[{{\"file\": \"{0}\", \"v\": 1}}, {{\"file\": \"{1}\", \"v\": {{\"x\": 2}}}}, {{\"file\": \"{0}\", \"v\": [3]}}]",
+    a.display(), b.display());
+  assert_eq!(run_stdin(&["{ writejson($.file, $.v, NR == 2) }"], &input), "");
+  assert_eq!(fs::read_to_string(&a).unwrap(), "1\n[3]\n");
+  assert_eq!(fs::read_to_string(&b).unwrap(), "{\n  \"x\": 2\n}\n");
+  fs::remove_file(&a).unwrap();
+  fs::remove_file(&b).unwrap();
+}
+
+#[test]
+#[cfg(feature = "http")]
+fn http_get() {
+  use std::io::Read;
+  use std::net::TcpListener;
+
+  let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+  let url = format!("http://{}/user", listener.local_addr().unwrap());
+  let server = std::thread::spawn(move || {
+    for _ in 0..2 {
+      let (mut stream, _) = listener.accept().unwrap();
+      let mut request = [0; 1024];
+      let _ = stream.read(&mut request).unwrap();
+      let body = "{\"name\": \"ada\"}";
+      write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+    }
+  });
+
+  let program = format!("BEGIN {{ print http_get(\"{0}\"); print http_get_json(\"{0}\", 5).name }}", url);
+  assert_eq!(run(&[program.as_str()]), "{\"name\": \"ada\"}\nada\n");
+  server.join().unwrap();
+}
+
+#[test]
+#[cfg(not(feature = "http"))]
+fn http_get() {
+  let err = run_err(&["BEGIN { print http_get(\"http://localhost/\") }"]);
+  assert!(err.contains("http_get needs jqawk built with the http feature"));
+}
+
+#[test]
+fn null_rendering() {
+  let input = "[{\"a\": null, \"b\": {\"c\": 1}}, {\"b\": {}}]";
+  let program = "{ print $.a, $.b.c, $.x.y }";
+  assert_eq!(run_stdin(&[program], input), "0 1 0\n0 0 0\n");
+  assert_eq!(run_stdin(&["--null-as", "-", program], input), "- 1 -\n- - -\n");
+  let output = run_stdin(&["BEGIN { NULLSTR = \"NA\" } { print $.a == 0, $.a; printf \"%j\\n\", $.a }"], input);
+  assert_eq!(output, "1 NA\nnull\n1 NA\nnull\n");
+}
+
+#[test]
+fn string_subscripts() {
+  let output = run_stdin(&["{ print $[0], $[1], $[2:4], $[:2], $[3:], $[9] == 0 }"], "[\"héllo\"]");
+  assert_eq!(output, "h é ll hé lo 1\n");
+}
+
+#[test]
+fn capture_groups() {
+  let input = "[{\"msg\": \"login user=ann ip=10.0.0.1\"}, {\"msg\": \"logout\"}]";
+  let program = "$.msg ~ \"user=(\\w+) ip=([\\d.]+)( x)?\" { print MATCH[1], MATCH[2], MATCH[3] == 0, MATCH[0] }";
+  assert_eq!(run_stdin(&[program], input), "ann 10.0.0.1 1 user=ann ip=10.0.0.1\n");
+}
+
+#[test]
+fn select() {
+  let input = "[{\"items\": [{\"qty\": 3}, {\"qty\": 0}]}]";
+  let program = "{ print map($.items, |x| select(x.qty > 0)); print $.items[1] |> select(1), select(NR > 1) == 0 }";
+  assert_eq!(run_stdin(&[program], input), "[{\"qty\":3},null]\n{\"qty\":0} 1\n");
+  assert_eq!(run_stdin(&["{ print select($ > 2) }"], "[1, 5]"), "0\n5\n");
+}
+
+#[test]
+fn input_formats() {
+  let csv = "name,age\nann,31\n\"bo, jr\",4\n";
+  assert_eq!(run_stdin(&["$.age > 10 { print $.name, $.age + 1 }"], csv), "ann 32\n");
+  assert_eq!(run_stdin(&["--from", "csv", "{ print $.name }"], csv), "ann\nbo, jr\n");
+
+  let yaml = "name: ann\ntags: [a, b]\n---\nname: bo\ntags: []\n";
+  assert_eq!(run_stdin(&["-r", "$.tags", "{ print NR, $ }"], yaml), "1 [\"a\",\"b\"]\n2 []\n");
+  assert_eq!(run_stdin(&["{ print $ }"], "- 1\n- two\n"), "1\ntwo\n");
+  assert_eq!(run_stdin(&["{ print $ }"], "{\"a\": 1}\n{\"a\": 2}\n"), "{\"a\":1}\n{\"a\":2}\n");
+  assert_eq!(run_stdin(&["--from", "raw", "{ print NR, $ }"], "a,b\nc\n"), "1 a,b\n2 c\n");
+
+  let path = env::temp_dir().join(format!("jqawk-formats-{}.csv", std::process::id()));
+  fs::write(&path, "x,y\n1,2\n").unwrap();
+  assert_eq!(run(&["{ print $.x + $.y }", path.to_str().unwrap()]), "3\n");
+  fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn output_separation() {
+  let input = r#"[{"a": "x y", "b": [1]}, {"a": "z", "b": 2}]"#;
+  assert_eq!(run_stdin(&["-j", "{ print $.a }"], input), "x yz");
+  assert_eq!(run_stdin(&["BEGIN { ORS = \";\" } { print $.a }"], input), "x y;z;");
+  assert_eq!(
+    run_stdin(&["--seq", "{ print $.a, $.b }"], input),
+    "\x1e\"x y\"\n\x1e[1]\n\x1e\"z\"\n\x1e2\n",
+  );
+}
+
+#[test]
+fn tracking() {
+  let input = r#"[{"name": "a", "pop": 5}, {"name": "b", "pop": 9}, {"name": "c", "pop": 2}, {"name": "d", "pop": 9}]"#;
+  assert_eq!(
+    run_stdin(&["{ big = maxby($.pop, $.name); small = minby($.pop) } END { print big, small.name }"], input),
+    "b c\n",
+  );
+  assert_eq!(run_stdin(&["{ print first($.name), last($.name) }"], input), "a a\na b\na c\na d\n");
+}
+
+#[test]
+fn parsenum() {
+  let input = r#"[{"size": "1.5GiB", "took": "250ms"}, {"size": "2 KB", "took": "1.5h"}, {"size": "1,234", "took": "?"}]"#;
+  assert_eq!(
+    run_stdin(&["--null-as", "null", "{ print parsenum($.size), parsenum($.took) }"], input),
+    "1610612736 0.25\n2000 5400\n1234 null\n",
+  );
+  assert_eq!(
+    run_stdin(&["END { print parsenum(\"0x1f\"), parsenum(\"1f\", 16), parsenum(\"-0b101\"), parsenum(\"12%\") }"], "[]"),
+    "31 31 -5 0.12\n",
+  );
+}
+
+#[test]
+fn statistics() {
+  let input = r#"{"a": [3, 1, 4, 1, 5, 9, 2, 6], "b": {"x": 10, "y": 20}}"#;
+  assert_eq!(
+    run_stdin(&["{ printf \"%s %s %.2f %s %.3f\\n\", KEY, median($), percentile($, 95), percentile($, 0), stddev($) }"], input),
+    "a 3.5 7.95 1 2.748\nb 15 19.50 10 7.071\n",
+  );
+  assert_eq!(run_stdin(&["--null-as", "-", "{ print median($), stddev($) }"], "[[]]"), "- -\n");
+
+  let input = r#"[{"r": "a", "ms": 1}, {"r": "a", "ms": 3}, {"r": "b", "ms": 10}, {"r": "a", "ms": 8}]"#;
+  assert_eq!(
+    run_stdin(&["--group-by", "$.r", "--agg", "median($.ms), p90($.ms), count()"], input),
+    "a 3 7 3\nb 10 10 1\n",
+  );
+}
+
+#[test]
+fn set_operations() {
+  let input = r#"[{"a": [1, 2, 2, "x", {"k": 1}], "b": [2, 3, {"k": 1}, 3]}]"#;
+  assert_eq!(
+    run_stdin(&["{ print union($.a, $.b), intersect($.a, $.b), difference($.a, $.b), difference($.b, $.a) }"], input),
+    "[1,2,\"x\",{\"k\":1},3] [2,{\"k\":1}] [1,\"x\"] [3]\n",
+  );
+}
+
+#[test]
+fn find() {
+  let input = r#"[{"user": {"email": "a@x", "alt": [{"email": "b@y"}]}}, {"name": "n"}]"#;
+  assert_eq!(run_stdin(&["{ print find($, \"email\") }"], input), "[\"a@x\",\"b@y\"]\n[]\n");
+  assert_eq!(run_stdin(&["find($, \"email\") { print NR }"], input), "1\n");
+}
+
+#[test]
+fn paths() {
+  let input = r#"[{"a": {"x/y": [1, "bob@x.com", {"m~": 1}]}, "b": 1}]"#;
+  assert_eq!(
+    run_stdin(&["{ print paths($, 1), paths_matching($, \"@\") }"], input),
+    "[\"/a/x~1y/0\",\"/a/x~1y/2/m~0\",\"/b\"] [\"/a/x~1y/1\"]\n",
+  );
+  assert_eq!(run_stdin(&["{ print pointer($, paths_matching($, \"@\")[0]) }"], input), "bob@x.com\n");
+}
+
+#[test]
+fn key_removal() {
+  let input = r#"[{"name": "a", "password": "p", "ssn": 1, "n": {"k": 1}}]"#;
+  assert_eq!(
+    run_stdin(&["{ print del($, \"ssn\"), omit($, [\"password\", \"ssn\"]), pick($, [\"name\"]) }"], input),
+    "{\"n\":{\"k\":1},\"name\":\"a\",\"password\":\"p\"} {\"n\":{\"k\":1},\"name\":\"a\"} {\"name\":\"a\"}\n",
+  );
+  assert_eq!(run_stdin(&["{ print [1, $.name, [2]][2] }"], input), "[2]\n");
+  assert_eq!(run(&["--fmt", "{ x = omit($,[\"a\"])[0] }"]), "{ x = omit($, [\"a\"])[0] }\n");
+}
+
+#[test]
+fn reshaping() {
+  let input = r#"[{"a": {"b": 1, "c": [1, 2]}, "old": "x"}]"#;
+  assert_eq!(
+    run_stdin(&["{ print rename($, \"old\", \"new\"), move($, \"a.b\", \"z.y\") }"], input),
+    "{\"a\":{\"b\":1,\"c\":[1,2]},\"new\":\"x\"} {\"a\":{\"c\":[1,2]},\"old\":\"x\",\"z\":{\"y\":1}}\n",
+  );
+  assert_eq!(run_stdin(&["{ print move($, \"a.c.1\", \"two\").two, move($, \"nope\", \"x\").old }"], input), "2 x\n");
+}
+
+#[test]
+fn argv() {
+  assert_eq!(run_stdin(&["{ print ARGC, ARGV }", "--", "a", "12"], "[1]"), "2 [\"a\",\"12\"]\n");
+  assert_eq!(run_stdin(&["{ print ARGC, ARGV }"], "[1]"), "0 []\n");
+  assert_eq!(run_stdin(&["--jsonargs", "{ print ARGV[0].id + ARGV[1] }", "--", "{\"id\": 3}", "5"], "[1]"), "8\n");
+}
+
+#[test]
+fn composite_keys() {
+  let input = r#"[{"r": "eu", "y": 2020}, {"r": "us", "y": 2021}]"#;
+  assert_eq!(run_stdin(&["{ k = join([$.r, $.y]); parts = split(k); print parts[1], parts[0] }"], input), "2020 eu\n2021 us\n");
+  assert_eq!(run_stdin(&["BEGIN { SUBSEP = \":\" } { print join([$.r, $.y]) }"], input), "eu:2020\nus:2021\n");
+  assert_eq!(run_stdin(&["{ print split(\"a, b,c\", \", *\"), split(\"ab\", \"\") }"], "[1]"), "[\"a\",\"b\",\"c\"] [\"a\",\"b\"]\n");
+}
+
+#[test]
+fn lambda_scope() {
+  assert_eq!(
+    run_stdin(&["{ x = 5; print map([1, 2], |x| x * 10), x, map([1, 2], |x| map([10, 20], |y| x + y)) }"], "[1]"),
+    "[10,20] 5 [[11,21],[12,22]]\n",
+  );
+  // parameters don't outlive the call
+  assert_eq!(run_stdin(&["{ print reduce([1, 2, 3], 0, |acc, n| acc + n), n, acc }"], "[1]"), "6 0 0\n");
+  // a parameter without an argument is null, like an unset global
+  assert_eq!(
+    run(&["--null-as", "NA", "BEGIN { print reduce([1], 0, |acc, x, extra| extra), unset, map([1], |x, y| y), map([1], |x, y| y + 1) }"]),
+    "NA NA [null] [1]\n",
+  );
+}
+
+#[test]
+fn associative_arrays() {
+  let input = r#"[{"c": "Asia", "p": 10}, {"c": "Asia", "p": 5}, {"c": "EU", "p": 3}]"#;
+  assert_eq!(
+    run_stdin(&["{ pop[$.c] += $.p; n += 1 } END { print pop[\"Asia\"], n; for (k in pop) print k, pop[k] }"], input),
+    "15 3\nAsia 15\nEU 3\n",
+  );
+  assert_eq!(
+    run_stdin(&["{ seen[$.c].count += 1 } END { print seen, \"EU\" in seen, \"US\" in seen, missing[\"x\"] }"], input),
+    "{\"Asia\":{\"count\":2},\"EU\":{\"count\":1}} 1 0 0\n",
+  );
+  assert_eq!(run_stdin(&["END { a = [1]; a[2] = 5; a[0] *= 10; for (i in a) { print i } print a }"], input), "0\n1\n2\n[10,null,5]\n");
+  assert_eq!(
+    run(&["--fmt", "END { for(k in a){ print k }; for (k in a) { print k; n+=1 } print n }"]),
+    "END {\n  for (k in a) { print k }\n  for (k in a) {\n    print k;\n    n += 1;\n  }\n  print n;\n}\n",
+  );
+}
+
+#[test]
+fn begin_only() {
+  // the input is never opened
+  assert_eq!(run(&["BEGIN { print 1 + 1 }", "/nonexistent/input.json"]), "2\n");
+  // unless BEGIN looks at $
+  assert_eq!(run_stdin(&["BEGIN { print $.a }"], r#"{"a": 3}"#), "3\n");
+  let output = run_err(&["BEGIN { print $.a }", "/nonexistent/input.json"]);
+  assert!(output.contains("error opening input file /nonexistent/input.json"), "{}", output);
+}
+
+#[test]
+fn interactive() {
+  // only changes anything when stdin is a terminal
+  assert_eq!(run_stdin(&["-i", "{ print $.a * 10 }"], "{\"a\": 1}\n{\"a\": 2}\n"), "10\n20\n");
+}
+
+#[test]
+fn increments() {
+  let input = r#"[{"c": "Asia"}, {"c": "Asia"}, {"c": "EU"}]"#;
+  assert_eq!(run_stdin(&["{ n++; count[$.c]++; --left[$.c] } END { print n, count, left }"], input),
+    "3 {\"Asia\":2,\"EU\":1} {\"Asia\":-2,\"EU\":-1}\n");
+  // postfix gives the value from before, prefix the one after
+  assert_eq!(run(&["BEGIN { x = 5; print x++, x, ++x, x--, --x }"]), "5 6 7 7 5\n");
+  let output = run_err(&["BEGIN { print a[1]++ }"]);
+  assert!(output.contains("unexpected token ++"), "{}", output);
+  assert_eq!(run(&["--fmt", "{n++;count[$.c]++;print ++m}"]), "{\n  n++;\n  count[$.c]++;\n  print ++m;\n}\n");
+}
+
+#[test]
+fn builtin_arity() {
+  let output = run_err(&["BEGIN {\n  x = 1;\n  print lookup(x)\n}"]);
+  assert!(output.contains("error on line 3, column 9: lookup expects 2-3 arguments, got 1"), "{}", output);
+  let output = run_err(&["BEGIN { print readjson(1, 2) }"]);
+  assert!(output.contains("readjson expects 1 argument, got 2"), "{}", output);
+  let output = run_err(&["BEGIN { print del(1) }"]);
+  assert!(output.contains("del expects at least 2 arguments, got 1"), "{}", output);
+  // a piped value counts, a function doesn't
+  let output = run_err(&["BEGIN { print 1 |> merge(2, 3) }"]);
+  assert!(output.contains("merge expects 2 arguments, got 3"), "{}", output);
+  assert_eq!(run(&["BEGIN { print [1, 2] |> map(|x| x * 2) }"]), "[2,4]\n");
+}
+
+#[test]
+fn builtin_argument_types() {
+  let output = run_err(&["BEGIN { print tolower([\"A\"]) }"]);
+  assert!(output.contains("tolower expects a string or number for argument 1, got array"), "{}", output);
+  let output = run_stdin_err(&["{ print split(\"a.b\", $) }"], "[{\"a\": 1}]");
+  assert!(output.contains("split expects a string or number for argument 2, got object"), "{}", output);
+  let output = run_err(&["BEGIN { print percentile([1, 2], [50]) }"]);
+  assert!(output.contains("percentile expects a string or number for argument 2, got array"), "{}", output);
+  // strings and numbers stand in for each other, like awk
+  assert_eq!(run(&["BEGIN { print toupper(12), percentile([1, 2, 3], \"50\") }"]), "12 2\n");
+}
+
+#[test]
+fn stack_verification() {
+  // all of these used to leave the stack wrong, and now pass the check
+  // made after compiling
+  assert_eq!(run_stdin(&["x = 1"], "[7]"), "7\n");
+  assert_eq!(run_stdin(&["{ print (y = 2), y; x }"], "[7]"), "2 2\n");
+  assert_eq!(run_stdin(&["{ print map($, |v| v = 2), (n++), n }"], "[[1, 3]]"), "[2,2] 0 1\n");
+  assert_eq!(run_stdin(&["--group-by", "k = 1", "--agg", "count()"], "[7, 8]"), "1 2\n");
+}
+
+#[test]
+fn comparisons() {
+  let input = r#"[{"age": 25}, {"age": 30}, {"age": 40}]"#;
+  assert_eq!(run_stdin(&["$.age < 30 { print $.age }"], input), "25\n");
+  assert_eq!(run_stdin(&["$.age <= 30 { print $.age }"], input), "25\n30\n");
+  assert_eq!(run_stdin(&["$.age >= 30 { print $.age }"], input), "30\n40\n");
+  assert_eq!(run_stdin(&["$.age != 30 { print $.age }"], input), "25\n40\n");
+  // strings compare lexically
+  assert_eq!(run(&["BEGIN { print \"a\" < \"b\", \"b\" <= \"a\", \"x\" != \"x\", 2 >= 10 }"]), "1 0 0 0\n");
+}
+
+#[test]
+fn runtime_error_locations() {
+  let program = "BEGIN { n = 1 }\n$.age > 0 {\n  x = 2;\n  print map(x, |v| v + 1)\n}";
+  let output = run_stdin_err(&[program], r#"[{"age": 1}]"#);
+  assert!(output.contains("in `map(x, |v| v + 1)` on line 4, column 9"), "{}", output);
+  let output = run_stdin_err(&["$.age.x > 1"], r#"[{"age": 1}]"#);
+  assert!(output.contains("in `$.age.x` on line 1, column 1"), "{}", output);
+}
+
+#[test]
+fn foreach() {
+  let input = r#"[{"id": 1, "items": [{"n": "a", "q": 2}, {"n": "b", "q": 3}]}, {"id": 2, "items": [{"n": "c", "q": 1}]}]"#;
+  // $ is each element in turn, and the record again afterwards
+  assert_eq!(run_stdin(&["{ foreach $.items { print $.n, $.q }; print $.id }"], input), "a 2\nb 3\n1\nc 1\n2\n");
+  assert_eq!(run_stdin(&["{ foreach $.items as item { total += item.q } } END { print total }"], input), "6\n");
+  assert_eq!(run_stdin(&["{ foreach $.items print $.n }"], input), "a\nb\nc\n");
+}
+
+#[test]
+fn regex_patterns() {
+  let input = r#"[{"c": "Asia", "p": 1}, {"c": "EU", "p": 2}, {"c": "asia/x", "p": 3}]"#;
+  // matched against the record as text
+  assert_eq!(run_stdin(&["/Asia/ { print $.p }"], input), "1\n");
+  assert_eq!(run_stdin(&["/a\\/x/ && $.p > 2"], input), "{\"c\":\"asia/x\",\"p\":3}\n");
+  assert_eq!(run_stdin(&["$.c !~ /^A/ { print $.p / 2 }"], input), "1\n1.5\n");
+  assert_eq!(run(&["--fmt", "/Asia/{print $.p/2}"]), "/Asia/ { print $.p / 2 }\n");
+  let output = run_err(&["/Asia"]);
+  assert!(output.contains("unterminated regex"), "{}", output);
+}
+
+#[test]
+fn root_for() {
+  let dir = env::temp_dir().join(format!("jqawk-root-for-{}", std::process::id()));
+  std::fs::create_dir_all(&dir).unwrap();
+  let events = dir.join("events.json");
+  let users = dir.join("users.json");
+  std::fs::write(&events, r#"{"events": [{"e": "login"}, {"e": "logout"}]}"#).unwrap();
+  std::fs::write(&users, r#"{"users": [{"name": "ann"}]}"#).unwrap();
+
+  // by file name or by path, with the other files still using --root
+  let args = [
+    "--root", "$.users", "--root-for", "events.json=$.events",
+    "{ print $.e, $.name }", events.to_str().unwrap(), users.to_str().unwrap(),
+  ];
+  assert_eq!(run(&args), "login 0\nlogout 0\n0 ann\n");
+  let root_for = format!("{}=$.events[0]", events.display());
+  assert_eq!(run(&["--root-for", &root_for, "{ print $ }", events.to_str().unwrap()]), "login\n");
+  std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn number_format() {
+  let input = "[{ \"price\": 3, \"rate\": 0.126 }, { \"price\": 1500, \"rate\": 2 }]";
+  let output = run_stdin(&["--number-format", "fixed:2", "{ print $.price, $.rate }"], input);
+  assert_eq!(output, "3.00 0.13\n1500.00 2.00\n");
+
+  let output = run_stdin(&["--number-format", "sci", "{ print $.price }"], input);
+  assert_eq!(output, "3e+00\n1.5e+03\n");
+
+  let output = run_stdin(&["--number-format", "sci:2", "{ print $.price }"], input);
+  assert_eq!(output, "3.00e+00\n1.50e+03\n");
+
+  // numbers inside json are written the same way, strings are left alone
+  let output = run_stdin(&["--number-format", "fixed:1", "{ print }"], "[{ \"a\": [1, \"2\"], \"b\": null }]");
+  assert_eq!(output, "{\"a\":[1.0,\"2\"],\"b\":null}\n");
+
+  let output = run_stdin(&["--number-format", "fixed:1", "--seq", "{ print $.price }"], input);
+  assert_eq!(output, "\x1e3.0\n\x1e1500.0\n");
+
+  let output = run_stdin(&["--number-format", "fixed:2", "--to", "table", "{ print $.price }"], input);
+  assert_eq!(output, "   3.00\n1500.00\n");
+
+  // printf has its own conversions
+  let output = run_stdin(&["--number-format", "fixed:2", "{ printf \"%d\\n\", $.price }"], input);
+  assert_eq!(output, "3\n1500\n");
+
+  assert_eq!(run_stdin(&["{ print $.rate }"], input), "0.126\n2\n");
+
+  let stderr = run_stdin_err(&["--number-format", "fixed", "{ print }"], input);
+  assert!(stderr.contains("invalid --number-format 'fixed'"));
+}
+
+#[test]
+fn range_patterns() {
+  let input = "[1, 2, 3, 4, 5, 6, 7, 8]";
+  // a range starts again after it ends, and each keeps its own state
+  let program = "$ == 1, $ == 2 { print \"a\", $ } $ == 3, $ == 100 { print \"b\", $ }";
+  assert_eq!(run_stdin(&[program], "[1, 2, 3, 1, 2, 3]"), "a 1\na 2\nb 3\na 1\nb 1\na 2\nb 2\nb 3\n");
+
+  // the end is checked against the record that started the range too
+  assert_eq!(run_stdin(&["$ > 2, $ < 10"], input), "3\n4\n5\n6\n7\n8\n");
+  assert_eq!(run_stdin(&["$ == 2, $ < 5"], input), "2\n");
+
+  let stderr = run_stdin_err(&["$ == 1, { print }"], input);
+  assert!(stderr.contains("unexpected prefix {"));
+}
+
+#[test]
+fn eval_mode() {
+  let input = "{ \"items\": [{ \"price\": 2 }, { \"price\": 5 }], \"tax\": 0.5 }";
+  assert_eq!(run_stdin(&["eval", "median($.items[*].price) * $.tax"], input), "1.75\n");
+  assert_eq!(run_stdin(&["eval", "-r", "$.items", "$[1].price"], input), "5\n");
+  assert_eq!(run_stdin(&["eval", "$.items[1]"], input), "{\"price\":5}\n");
+
+  let dir = env::temp_dir().join(format!("jqawk-eval-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let path = dir.join("doc.json");
+  fs::write(&path, input).unwrap();
+  assert_eq!(run(&["eval", "$.tax + 1", path.to_str().unwrap()]), "1.5\n");
+  fs::remove_dir_all(&dir).unwrap();
+
+  let stderr = run_stdin_err(&["eval", "$.tax +"], input);
+  assert!(stderr.contains("error on line 1"));
+}
+
+#[test]
+fn exit_statement() {
+  let input = "[1, 2, 3, 4, 5]";
+  // END rules still run, and the rest of the rule doesn't
+  let program = "NR >= 3 { exit } { print $ } END { print \"done\", NR }";
+  assert_eq!(run_stdin(&[program], input), "1\n2\ndone 3\n");
+  assert_eq!(run_stdin(&["BEGIN { print \"begin\"; exit } { print $ } END { print NR }"], input), "begin\n0\n");
+  assert_eq!(run_stdin(&["{ foreach [1, 2] { exit }; print \"no\" } END { exit; print \"no\" }"], input), "");
+
+  let mut child = Command::new(jqawk_exe())
+    .args(["$ == 2 { exit 1 + 2 } { print $ } END { exit }"])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .expect("error spawning jqawk");
+  child.stdin.as_mut().unwrap().write_all(input.as_bytes()).unwrap();
+  let output = child.wait_with_output().expect("error reading child stdout");
+  // a bare exit keeps the status from before
+  assert_eq!(output.status.code(), Some(3));
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "1\n");
+}
+
+#[test]
+fn record_timeout() {
+  // the first record loops nine million times, the others don't
+  let input = format!("[[{}], [1, 2], [3]]", vec!["0"; 3000].join(", "));
+  let program = "{ n = 0; foreach $ as x { foreach $ as y { n++ } }; print NR, n }";
+  let mut child = Command::new(jqawk_exe())
+    .args(["--record-timeout", "50ms", program])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .expect("error spawning jqawk");
+  child.stdin.as_mut().unwrap().write_all(input.as_bytes()).unwrap();
+  let output = child.wait_with_output().expect("error reading child stdout");
+  assert!(output.status.success());
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "2 4\n3 1\n");
+  assert_eq!(String::from_utf8_lossy(&output.stderr), "warning: skipped record 1, its rules took longer than 50ms\n");
+
+  let stderr = run_stdin_err(&["--record-timeout", "soon", "{ print }"], "[1]");
+  assert!(stderr.contains("invalid --record-timeout 'soon'"));
+}
+
+#[test]
+fn delete_statement() {
+  let input = "[{ \"a\": 1, \"b\": { \"c\": 2, \"d\": [1, 2, 3] } }]";
+  assert_eq!(run_stdin(&["{ delete $.a; delete $.b.d[1]; delete $[\"b\"][\"c\"]; print }"], input), "{\"b\":{\"d\":[1,3]}}\n");
+
+  let program = "{ seen[\"x\"] = 1; seen[\"y\"] = 2; delete seen[\"x\"]; print seen; delete seen; print seen, \"x\" in seen }";
+  assert_eq!(run_stdin(&[program], input), "{\"y\":2}\n{} 0\n");
+  // deleting what isn't there does nothing
+  assert_eq!(run_stdin(&["{ delete $.z; delete $.b.d[7]; delete a[1]; print $.a, a }"], input), "1 0\n");
+
+  let stderr = run_stdin_err(&["{ delete $ }"], input);
+  assert!(stderr.contains("cannot delete the record, only its members"));
+}
+
+#[test]
+fn escape_control() {
+  let input = "[{ \"m\": \"one\\ntwo\\u0007\\tthree\", \"n\": 1 }]";
+  assert_eq!(run_stdin(&["--escape-control", "{ print $.m, $.n }"], input), "one\\ntwo\\u0007\\tthree 1\n");
+  assert_eq!(run_stdin(&["{ print $.m }"], input), "one\ntwo\u{7}\tthree\n");
+  // json is escaped already
+  assert_eq!(run_stdin(&["--escape-control", "{ print }"], input), "{\"m\":\"one\\ntwo\\u0007\\tthree\",\"n\":1}\n");
+  assert_eq!(run_stdin(&["--escape-control", "--to", "table", "{ print $.n, $.m }"], input), "1  one\\ntwo\\u0007\\tthree\n");
+}
+
+#[test]
+fn collation() {
+  let input = "[\"item10\", \"Item2\", \"apple\", \"Banana\"]";
+  let program = "$ < \"b\" { print $ } { m = maxby($) } END { print m }";
+  assert_eq!(run_stdin(&[program], input), "Item2\napple\nBanana\nitem10\n");
+  assert_eq!(run_stdin(&["--collate", "nocase", program], input), "apple\nItem2\n");
+  let program = "{ print maxby($), minby($) }";
+  assert_eq!(run_stdin(&["--collate", "natural", program], input), "item10 item10\nitem10 Item2\nitem10 apple\nitem10 apple\n");
+
+  // constants are compared when the program runs
+  assert_eq!(run(&["--collate", "nocase", "BEGIN { x = \"B\" > \"a\"; y = \"B\" < \"a\"; print x, y }"]), "1 0\n");
+  assert_eq!(run(&["--collate", "natural", "BEGIN { print \"x9\" < \"x10\", \"x09\" < \"x9\" }"]), "1 1\n");
+}
+
+#[test]
+fn duplicate_keys() {
+  let input = "[{ \"a\": 1, \"b\": { \"c\": 1, \"c\": 2 }, \"a\": 3 }]\n[{ \"b\": {}, \"x\": [{ \"y\": 1 }] }]";
+  let program = "{ print $[0].a, $[0].b.c, DUPKEYS }";
+  assert_eq!(run_stdin(&[program], input), "3 2 [\"0.b.c\",\"0.a\"]\n0 0 []\n");
+  assert_eq!(run_stdin(&["--dup-keys", "first", program], input), "1 1 [\"0.b.c\",\"0.a\"]\n0 0 []\n");
+
+  let stderr = run_stdin_err(&["--dup-keys", "error", "{ print }"], input);
+  assert!(stderr.contains("error parsing JSON: duplicate key 0.b.c at line 1 column"));
+  assert_eq!(run_stdin(&["--dup-keys", "error", "{ print }"], "[{ \"a\": { \"a\": 1 } }]"), "{\"a\":{\"a\":1}}\n");
+}
+
+#[test]
+fn checkpoint_resume() {
+  let dir = env::temp_dir().join(format!("jqawk-checkpoint-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let input = dir.join("input.json");
+  let checkpoint = dir.join("state.json");
+  let out = dir.join("out.txt");
+  fs::write(&input, "1\n2\n3\n4\n").unwrap();
+  let program = format!(
+    "BEGIN {{ print \"begin\" }} {{ sum += $; print $ > \"{}\" }} fail && $ == 3 {{ error(\"stop\") }} END {{ print sum, NR }}",
+    out.to_str().unwrap(),
+  );
+  let run_with = |fail: &str| {
+    return Command::new(env!("CARGO_BIN_EXE_jqawk"))
+      .args(["--checkpoint", checkpoint.to_str().unwrap(), "--checkpoint-every", "1", "--resume"])
+      .args(["-v", fail, &program, input.to_str().unwrap()])
+      .output()
+      .unwrap();
+  };
+
+  let failed = run_with("fail=1");
+  assert_eq!(failed.status.code(), Some(1));
+  assert_eq!(String::from_utf8_lossy(&failed.stdout), "begin\n");
+  assert!(checkpoint.exists());
+  assert_eq!(fs::read_to_string(&out).unwrap(), "1\n2\n3\n");
+
+  // carries on from the third record, without BEGIN, and from where the
+  // output file was when the third record was reached
+  let resumed = run_with("fail=0");
+  assert_eq!(resumed.status.code(), Some(0));
+  assert_eq!(String::from_utf8_lossy(&resumed.stdout), "10 4\n");
+  assert!(!checkpoint.exists());
+  assert_eq!(fs::read_to_string(&out).unwrap(), "1\n2\n3\n4\n");
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn number_literals() {
+  let program = "{ print 3.14, .5, 1e6, 2.5E-3, 1e+2, 1_000_000, $.a * 1.5, 10 - .5 }";
+  assert_eq!(run_stdin(&[program], "[{\"a\": 2}]"), "3.14 0.5 1000000 0.0025 100 1000000 3 9.5\n");
+}
+
+#[test]
+fn max_rate() {
+  let start = std::time::Instant::now();
+  assert_eq!(run_stdin(&["--max-rate", "20", "{ print }"], "1\n2\n3\n4\n5\n"), "1\n2\n3\n4\n5\n");
+  // the first record goes straight away, the other four are 50ms apart
+  assert!(start.elapsed() >= std::time::Duration::from_millis(200));
+}
+
+#[test]
+fn program_snippets() {
+  let dir = env::temp_dir().join(format!("jqawk-snippets-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let library = dir.join("lib.jqawk");
+  fs::write(&library, "define BIG = $ > 1\n").unwrap();
+  let library = library.to_str().unwrap();
+
+  assert_eq!(run_stdin(&["-f", library, "-e", "BIG { print }", "-e", "END { print NR }"], "[1, 2, 3]"), "2\n3\n3\n");
+  // without -f, the first argument is an input
+  assert_eq!(run_stdin(&["-e", "{ print }", "-"], "[1, 2]"), "1\n2\n");
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn unary_minus() {
+  let program = "{ x = -1; y = -$.d * 2; print x, y, ($.d > -5), 3 - -2, -(1 + 2), -x }";
+  assert_eq!(run_stdin(&[program], "[{\"d\": 3}]"), "-1 -6 1 5 -3 1\n");
+  assert_eq!(run(&["--fmt", "BEGIN { x=-1; y=3 - -x }"]), "BEGIN {\n  x = -1;\n  y = 3 - -x;\n}\n");
+}
+
+#[test]
+fn conditional_sections() {
+  let program = "@define VERBOSE
+@if ENVIRON[\"JQAWK_TEST_DEBUG\"]
+{ print \"debug\", $ }
+@else
+{ print }
+@endif
+@if !VERBOSE
+END { print \"quiet\" }
+@endif
+@if VERBOSE
+END { print \"verbose\", NR }
+@endif";
+  let run_with = |debug: &str| {
+    let output = Command::new(jqawk_exe())
+      .args([program, "-"])
+      .env("JQAWK_TEST_DEBUG", debug)
+      .stdin(Stdio::piped())
+      .stdout(Stdio::piped())
+      .spawn()
+      .and_then(|mut child| {
+        child.stdin.take().unwrap().write_all(b"[1, 2]")?;
+        child.wait_with_output()
+      })
+      .unwrap();
+    return String::from_utf8(output.stdout).unwrap();
+  };
+  assert_eq!(run_with(""), "1\n2\nverbose 2\n");
+  assert_eq!(run_with("1"), "debug 1\ndebug 2\nverbose 2\n");
+  assert_eq!(run(&["--fmt", program]), format!("{}\n", program));
+
+  let output = run_err(&["@if X\n{ print }\n@else\n@else\n@foo"]);
+  assert!(output.contains("error on line 4, column 1: @else after @else"));
+  assert!(output.contains("error on line 5, column 1: unknown directive @foo"));
+  assert!(output.contains("error on line 1, column 1: @if without @endif"));
+}
+
+#[test]
+fn logical_not() {
+  let input = "[{\"active\": true, \"tags\": [\"a\"]}, {\"active\": false, \"tags\": []}, {\"tags\": \"\"}]";
+  assert_eq!(run_stdin(&["!($.active) { print NR, !$.tags, !!$.tags }"], input), "2 1 0\n3 1 0\n");
+  assert_eq!(run_stdin(&["!/foo/ { print }"], "[\"foo\", \"bar\"]"), "bar\n");
+  assert_eq!(run(&["--fmt", "BEGIN{x=! y}"]), "BEGIN { x = !y }\n");
+}
+
+#[test]
+fn max_record_size() {
+  let input = format!("[1]\n[\"{}\"]\n{{\"a\": [2]}}\n[3]", "x".repeat(100));
+  let output = Command::new(jqawk_exe())
+    .args(["--max-record-size", "40", "--oversized", "skip", "{ print NR, $ }"])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .and_then(|mut child| {
+      child.stdin.take().unwrap().write_all(input.as_bytes())?;
+      child.wait_with_output()
+    })
+    .unwrap();
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "1 [1]\n2 {\"a\":[2]}\n3 [3]\n");
+  assert_eq!(String::from_utf8_lossy(&output.stderr), "warning: skipped the document on line 2, it's bigger than 40 bytes\n");
+
+  let output = run_stdin_err(&["--max-record-size", "40", "{ print }"], &input);
+  assert!(output.contains("the document on line 2 is bigger than the --max-record-size of 40 bytes"));
+
+  // sizes don't take durations, so a bare m is a megabyte rather than a minute
+  assert_eq!(run_stdin(&["--max-record-size", "1m", "{ print NR }"], &input), "1\n2\n3\n4\n");
+  let output = run_err(&["--max-record-size", "1min", "{ print }", "test.json"]);
+  assert!(output.contains("invalid --max-record-size '1min'"));
+
+  // long lines are fine without a limit
+  let long = format!("[\"{}\"]", "x".repeat(1 << 20));
+  assert_eq!(run_stdin(&["{ print NR }"], &long), "1\n");
+}
+
+#[test]
+fn gzip_input() {
+  let dir = env::temp_dir().join(format!("jqawk-gzip-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let path = dir.join("input.json.gz");
+  let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+  encoder.write_all(b"1\n2\n3\n").unwrap();
+  fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+  assert_eq!(run(&["{ n += $ } END { print n, NR }", path.to_str().unwrap()]), "6 3\n");
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn fflush() {
+  let dir = env::temp_dir().join(format!("jqawk-fflush-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let file = dir.join("out.txt");
+  let file = file.to_str().unwrap();
+  let program = format!(
+    "{{ print $ > \"{0}\"; print fflush(\"{0}\"), fflush(\"/dev/stdout\"), fflush(\"/dev/stderr\"), fflush(\"nope\"), fflush() }}",
+    file,
+  );
+  let output = run_stdin(&[&program], "[1, 2]");
+  assert_eq!(output, "0 0 0 -1 0\n0 0 0 -1 0\n");
+  assert_eq!(fs::read_to_string(file).unwrap(), "1\n2\n");
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn dry_run() {
+  let dir = env::temp_dir().join(format!("jqawk-dry-run-{}", std::process::id()));
+  fs::create_dir_all(&dir).unwrap();
+  let file = dir.join("out.txt");
+  let program = format!("BEGIN {{ print \"begin\" }} $.size > 1 {{ print > \"{}\" }} END {{ print NR }}", file.to_str().unwrap());
+  let output = Command::new(jqawk_exe())
+    .args(["--dry-run", &program])
+    .stdin(Stdio::piped())
+    .stdout(Stdio::piped())
+    .stderr(Stdio::piped())
+    .spawn()
+    .and_then(|mut child| {
+      child.stdin.take().unwrap().write_all(b"[{\"size\": 1}, {\"size\": 2}, {\"size\": 3}]")?;
+      child.wait_with_output()
+    })
+    .unwrap();
+  assert!(output.status.success());
+  assert_eq!(String::from_utf8_lossy(&output.stdout), "");
+  let stderr = String::from_utf8_lossy(&output.stderr);
+  assert!(stderr.contains("     2  $.size > 1"), "{}", stderr);
+  assert!(!file.exists());
+
+  // a record whose pattern fails is reported and the rest still checked
+  let stderr = run_stdin_err(&["--dry-run", "$.a.b > 1 { print }"], "[{\"a\": {\"b\": 2}}, {\"a\": 1}, {\"a\": {\"b\": 3}}]");
+  assert!(stderr.contains("can only access members"), "{}", stderr);
+  assert!(stderr.contains("     2  $.a.b > 1"), "{}", stderr);
+  assert!(stderr.contains("1 record hit an error"), "{}", stderr);
+  fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn run_stats() {
+  let program = "$.size > 1 { n++ } END { print STATS.records, STATS.matched, STATS.skipped, STATS.bytes, STATS.elapsed_ms >= 0 }";
+  let input = "[{\"size\": 1}, {\"size\": 2}, {\"size\": 3}]";
+  assert_eq!(run_stdin(&[program], input), format!("3 2 0 {} 1\n", input.len()));
+
+  let output = run_stdin(&["--sample-n", "1", "--seed", "1", "END { print STATS.records, STATS.skipped }"], input);
+  assert_eq!(output, "3 2\n");
+}
+
+#[test]
+fn cmp_eq() {
+  let input = "[[null, 0], [1, \"1\"], [\"10\", \"9\"], [\"a\", [1]], [[1, 2], [1]], [{\"a\": 1}, {\"a\": 2}], [{\"b\": 1}, {\"a\": 2, \"b\": 1}], [[true], [1]], [{\"a\": [1]}, {\"a\": [1]}]]";
+  let output = run_stdin(&["{ print cmp($[0], $[1]), eq($[0], $[1]) }"], input);
+  assert_eq!(output, "-1 0\n-1 0\n-1 0\n-1 0\n1 0\n-1 0\n1 0\n-1 0\n0 1\n");
+}
+
+#[test]
+fn regex_values() {
+  let input = "[\"aab\", \"b\", \"ab\", \"Ab\"]";
+  let program = "BEGIN { re = /^a+(b)$/ } $ ~ re { print $, MATCH[1] } END { print re }";
+  assert_eq!(run_stdin(&[program], input), "aab b\nab b\n^a+(b)$\n");
+
+  // stored in arrays and passed to builtins, and still a match on its own
+  let program = "BEGIN { res[\"a\"] = /^a/ } { print $ ~ res[\"a\"], filter([$], |s| s ~ res[\"a\"]), /b/ }";
+  assert_eq!(run_stdin(&[program], "[\"ab\", \"c\"]"), "1 [\"ab\"] 1\n0 [] 0\n");
+
+  let program = "BEGIN { re = /a/; IGNORECASE = 1 } $ ~ re { print }";
+  assert_eq!(run_stdin(&[program], input), "aab\nab\nAb\n");
+}
+
+#[test]
+fn change_case() {
+  let input = "[{ \"country\": \"UK\" }, { \"country\": \"uk\" }, { \"country\": \"Ümlaut\" }]";
+  let output = run_stdin(&["{ n[tolower($.country)]++ } END { print n[\"uk\"], toupper($[2].country), tolower(12) }"], input);
+  assert_eq!(output, "2 ÜMLAUT 12\n");
+}
+
+#[test]
+fn aggregate_state_patterns() {
+  let input = "[[\"USSR\", 8649, 275], [\"Canada\", 3852, 25], [\"China\", 3705, 1032], [\"USA\", 3615, 237]]";
+  let output = run_stdin(&["maxpop < $[2] { maxpop = $[2]; country = $[0] } END { print country, maxpop }"], input);
+  assert_eq!(output, "China 1032\n");
+
+  // later rules see what earlier ones assigned for the same record
+  let output = run_stdin(&["$[1] > big { big = $[1] } $[1] == big { print $[0] }"], input);
+  assert_eq!(output, "USSR\n");
+
+  // unset variables are 0 next to numbers and empty next to strings
+  let output = run_stdin(&["$[0] > last { last = $[0] } END { print last, unset == 0, unset == \"\", unset < 1 }"], input);
+  assert_eq!(output, "USSR 1 1 1\n");
+}
+
 // one true awk inspired tests
 macro_rules! jqawk_test {
   ($name:ident, $program:expr, $input:expr, $expected:expr) => {
@@ -128,9 +1685,9 @@ macro_rules! jqawk_test {
 
 jqawk_test!(p1, "{ print }", "[1, 2, 3]", "1\n2\n3\n");
 jqawk_test!(p2, "{ print $[0], $[2] }", "[[1, 2, 3], [10, 20, 30]]", "1 3\n10 30\n");
-// p3 omitted until printf
+jqawk_test!(p3, "{ printf \"%4d %-3s|\\n\", $[0], $[1] }", "[[1, \"a\"], [20, \"bc\"]]", "   1 a  |\n  20 bc |\n");
 jqawk_test!(p4, "{ print NR, $ }", "[2, 4, 6, 8]", "1 2\n2 4\n3 6\n4 8\n");
-// p5 ommitted until printf
+jqawk_test!(p5, "{ printf \"%d: %05.1f\\n\", NR, $ / 3 }", "[1, 10]", "1: 000.3\n2: 003.3\n");
 jqawk_test!(p6, "END { print NR }", "[1, 2, 3, 4]", "4\n");
 jqawk_test!(p7, "$[1] > 100", "[[10, 20], [100, 200], [1000, 2000]]", "[100,200]\n[1000,2000]\n");
 jqawk_test!(p8,
@@ -147,6 +1704,8 @@ jqawk_test!(p20,
     { \"id\": 2, \"name\": \"alligator\", \"age\": 35 },
     { \"id\": 3, \"name\": \"clive\", \"age\": 35 }]",
   "2\n");
+jqawk_test!(p23, "/Canada/, /Brazil/", "[\"USSR\", \"Canada\", \"China\", \"Brazil\", \"India\", \"Canada\"]", "Canada\nChina\nBrazil\nCanada\n");
+jqawk_test!(p24, "NR == 2, NR == 3 { print NR, $ }", "[\"a\", \"b\", \"c\", \"d\"]", "2 b\n3 c\n");
 
 /*
 p.21
@@ -158,12 +1717,6 @@ p.21a
 p.22
 $4 ~ /^(Asia|Europe)$/
 
-p.23
-/Canada/, /Brazil/
-
-p.24
-FNR == 1, FNR == 5 { print FILENAME, $0 }
-
 p.25
 { printf "%10s %6.1f\n", $1, 1000 * $3 / $2 }
 